@@ -0,0 +1,280 @@
+use ggez::{Context, GameError, GameResult};
+use sprite::Atlas;
+use std::collections::HashMap;
+use std::io::Read;
+use toml;
+
+pub const TILE_ANIMATIONS_PATH: &str = "/tile_animations.toml";
+pub const PLAYER_ANIMATIONS_PATH: &str = "/player_animations.toml";
+pub const TICKS_PER_SECOND: f32 = 60.0;
+
+/// A frame of an animated tile, shown for `duration` ticks
+#[derive(Deserialize)]
+pub struct TileFrame {
+    pub id: usize,
+    pub duration: u32,
+}
+
+#[derive(Deserialize)]
+pub struct TileAnimation {
+    pub id: usize,
+    pub frames: Vec<TileFrame>,
+}
+
+#[derive(Deserialize)]
+struct TileAnimationFile {
+    #[serde(default)]
+    animation: Vec<TileAnimation>,
+}
+
+/// Animated tile definitions, keyed by the tile id placed in the map.
+/// Every animation is driven by the same global clock so all instances
+/// of a tile show the same frame.
+pub struct TileAnimations {
+    animations: HashMap<usize, TileAnimation>,
+}
+
+impl TileAnimations {
+    pub fn load(ctx: &mut Context, path: &str) -> GameResult<TileAnimations> {
+        let mut file = ctx.filesystem.open(path)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let file: TileAnimationFile = toml::from_str(&contents)?;
+
+        let mut animations = HashMap::new();
+        for animation in file.animation {
+            if animation.frames.iter().map(|f| f.duration).sum::<u32>() == 0 {
+                return Err(GameError::ResourceLoadError(format!(
+                    "Animation for tile {} in {} has no duration",
+                    animation.id, path
+                )));
+            }
+            animations.insert(animation.id, animation);
+        }
+        Ok(TileAnimations { animations })
+    }
+
+    /// Gets the tile to draw in place of `id` at the given clock tick
+    pub fn frame(&self, id: usize, clock: u64) -> usize {
+        let animation = match self.animations.get(&id) {
+            Some(animation) => animation,
+            None => return id,
+        };
+
+        let length: u32 = animation.frames.iter().map(|f| f.duration).sum();
+        let mut time = (clock % u64::from(length)) as u32;
+        for frame in &animation.frames {
+            if time < frame.duration {
+                return frame.id;
+            }
+            time -= frame.duration;
+        }
+        id
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum LoopMode {
+    Loop,
+    Once,
+    PingPong,
+}
+
+fn default_loop_mode() -> LoopMode {
+    LoopMode::Loop
+}
+
+/// A named sequence of atlas frames played back at `fps`
+#[derive(Deserialize)]
+pub struct Clip {
+    pub frames: Vec<String>,
+    pub fps: f32,
+    #[serde(default = "default_loop_mode")]
+    pub mode: LoopMode,
+    // Plays faster or slower depending on how fast the entity moves
+    #[serde(default)]
+    pub scale_with_speed: bool,
+}
+
+impl Clip {
+    /// Frames of playback after which the clip looks the same as at the start,
+    /// or in the case of clips played once, after which it stays on its last frame
+    fn period(&self) -> f32 {
+        let count = self.frames.len();
+        match self.mode {
+            LoopMode::PingPong if count > 1 => (2 * count - 2) as f32,
+            _ => count as f32,
+        }
+    }
+
+    /// Gets the index of the frame shown after `time` frames of playback
+    fn frame_index(&self, time: f32) -> usize {
+        let count = self.frames.len();
+        let i = time as usize;
+        match self.mode {
+            LoopMode::Loop => i % count,
+            LoopMode::Once => i.min(count - 1),
+            LoopMode::PingPong => {
+                if count == 1 {
+                    return 0;
+                }
+                let period = 2 * count - 2;
+                let i = i % period;
+                if i < count {
+                    i
+                } else {
+                    period - i
+                }
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum AnimationState {
+    Idle,
+    Walk,
+    Run,
+    Skid,
+    Jump,
+    Fall,
+    Crouch,
+    Die,
+}
+
+/// Clip played in each animation state
+#[derive(Deserialize)]
+struct StateClips {
+    idle: String,
+    walk: String,
+    run: String,
+    skid: String,
+    jump: String,
+    fall: String,
+    crouch: String,
+    die: String,
+}
+
+impl StateClips {
+    fn get(&self, state: AnimationState) -> &str {
+        match state {
+            AnimationState::Idle => &self.idle,
+            AnimationState::Walk => &self.walk,
+            AnimationState::Run => &self.run,
+            AnimationState::Skid => &self.skid,
+            AnimationState::Jump => &self.jump,
+            AnimationState::Fall => &self.fall,
+            AnimationState::Crouch => &self.crouch,
+            AnimationState::Die => &self.die,
+        }
+    }
+}
+
+const STATES: [AnimationState; 8] = [
+    AnimationState::Idle,
+    AnimationState::Walk,
+    AnimationState::Run,
+    AnimationState::Skid,
+    AnimationState::Jump,
+    AnimationState::Fall,
+    AnimationState::Crouch,
+    AnimationState::Die,
+];
+
+/// Clips of an entity and which of them to play in each state
+#[derive(Deserialize)]
+pub struct AnimationSet {
+    // Speed at which clips scaling with speed play at their own fps
+    pub speed_reference: f32,
+    clips: HashMap<String, Clip>,
+    states: StateClips,
+}
+
+impl AnimationSet {
+    /// Loads an animation set, checking that every frame exists in the atlas
+    pub fn load(ctx: &mut Context, path: &str, atlas: &Atlas) -> GameResult<AnimationSet> {
+        let mut file = ctx.filesystem.open(path)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let set: AnimationSet = toml::from_str(&contents)?;
+
+        if set.speed_reference <= 0.0 {
+            return Err(GameError::ResourceLoadError(format!(
+                "speed_reference in {} needs to be positive",
+                path
+            )));
+        }
+        for state in STATES.iter() {
+            let name = set.states.get(*state);
+            if !set.clips.contains_key(name) {
+                return Err(GameError::ResourceLoadError(format!(
+                    "Unknown clip {} for state {:?} in {}",
+                    name, state, path
+                )));
+            }
+        }
+        for (name, clip) in &set.clips {
+            if clip.frames.is_empty() || clip.fps <= 0.0 {
+                return Err(GameError::ResourceLoadError(format!(
+                    "Clip {} in {} needs at least one frame and a positive fps",
+                    name, path
+                )));
+            }
+            if let Some(frame) = clip.frames.iter().find(|f| atlas.get(f).is_none()) {
+                return Err(GameError::ResourceLoadError(format!(
+                    "Clip {} in {} uses unknown frame {}",
+                    name, path, frame
+                )));
+            }
+        }
+        Ok(set)
+    }
+
+    pub fn clip(&self, state: AnimationState) -> &Clip {
+        &self.clips[self.states.get(state)]
+    }
+}
+
+/// Plays the clip of the current state, restarting it when the state changes
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Animator {
+    pub state: AnimationState,
+    pub time: f32,
+}
+
+impl Animator {
+    pub fn new() -> Animator {
+        Animator {
+            state: AnimationState::Idle,
+            time: 0.0,
+        }
+    }
+
+    /// Advances the animation by one tick. `speed` is used by clips scaling with speed.
+    pub fn update(&mut self, set: &AnimationSet, state: AnimationState, speed: f32) {
+        if state != self.state {
+            self.state = state;
+            self.time = 0.0;
+        }
+
+        let clip = set.clip(self.state);
+        let mut rate = clip.fps / TICKS_PER_SECOND;
+        if clip.scale_with_speed {
+            rate *= speed / set.speed_reference;
+        }
+        // Kept within the clip so it doesn't grow, and lose precision, in long idles
+        self.time += rate;
+        if clip.mode == LoopMode::Once {
+            self.time = self.time.min(clip.period());
+        } else {
+            self.time %= clip.period();
+        }
+    }
+
+    /// Gets the name of the frame to draw
+    pub fn frame<'a>(&self, set: &'a AnimationSet) -> &'a str {
+        let clip = set.clip(self.state);
+        &clip.frames[clip.frame_index(self.time)]
+    }
+}
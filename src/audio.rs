@@ -0,0 +1,251 @@
+use events::GameEvent;
+use ggez::audio::{SoundData, Source};
+use ggez::{Context, GameResult};
+use std::io::Read;
+use toml;
+
+pub const AUDIO_PATH: &str = "/audio.toml";
+const VOLUME_STEP: f32 = 0.1;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Sfx {
+    Jump,
+    Stomp,
+    Coin,
+    Bump,
+    PowerUp,
+    Death,
+}
+
+impl Sfx {
+    /// Effect by its name in the audio settings, e.g. `power_up`
+    pub fn from_name(name: &str) -> Option<Sfx> {
+        match name {
+            "jump" => Some(Sfx::Jump),
+            "stomp" => Some(Sfx::Stomp),
+            "coin" => Some(Sfx::Coin),
+            "bump" => Some(Sfx::Bump),
+            "power_up" => Some(Sfx::PowerUp),
+            "death" => Some(Sfx::Death),
+            _ => None,
+        }
+    }
+}
+
+const SFX: [Sfx; 6] = [
+    Sfx::Jump,
+    Sfx::Stomp,
+    Sfx::Coin,
+    Sfx::Bump,
+    Sfx::PowerUp,
+    Sfx::Death,
+];
+
+/// Volumes that can be changed in game
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Volume {
+    Master,
+    Music,
+    Sfx,
+}
+
+pub const VOLUMES: [Volume; 3] = [Volume::Master, Volume::Music, Volume::Sfx];
+
+/// Sound file played for each effect
+#[derive(Deserialize)]
+struct SfxFiles {
+    jump: String,
+    stomp: String,
+    coin: String,
+    bump: String,
+    power_up: String,
+    death: String,
+}
+
+impl SfxFiles {
+    fn get(&self, sfx: Sfx) -> &str {
+        match sfx {
+            Sfx::Jump => &self.jump,
+            Sfx::Stomp => &self.stomp,
+            Sfx::Coin => &self.coin,
+            Sfx::Bump => &self.bump,
+            Sfx::PowerUp => &self.power_up,
+            Sfx::Death => &self.death,
+        }
+    }
+}
+
+/// Volumes from 0 to 1. Music and effects are scaled by the master volume.
+#[derive(Deserialize, Clone, Debug)]
+pub struct AudioSettings {
+    pub master_volume: f32,
+    pub music_volume: f32,
+    pub sfx_volume: f32,
+    pub muted: bool,
+}
+
+#[derive(Deserialize)]
+struct AudioFile {
+    settings: AudioSettings,
+    sfx: SfxFiles,
+}
+
+struct Music {
+    path: String,
+    // None when the track failed to load, so it isn't retried every tick
+    source: Option<Source>,
+}
+
+/// Plays sound effects and the music of the level.
+/// Sounds that fail to load are reported and then left silent.
+pub struct Audio {
+    pub settings: AudioSettings,
+    sounds: Vec<Option<SoundData>>,
+    playing: Vec<Source>,
+    music: Option<Music>,
+}
+
+impl Audio {
+    pub fn load(ctx: &mut Context) -> GameResult<Audio> {
+        let mut file = ctx.filesystem.open(AUDIO_PATH)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let file: AudioFile = toml::from_str(&contents)?;
+
+        let mut sounds = Vec::new();
+        for sfx in SFX.iter() {
+            let path = file.sfx.get(*sfx);
+            match SoundData::new(ctx, path) {
+                Ok(data) => sounds.push(Some(data)),
+                Err(e) => {
+                    println!("Failed to load sound {}: {}", path, e);
+                    sounds.push(None);
+                }
+            }
+        }
+
+        Ok(Audio {
+            settings: file.settings,
+            sounds,
+            playing: Vec::new(),
+            music: None,
+        })
+    }
+
+    fn sfx_volume(&self) -> f32 {
+        if self.settings.muted {
+            0.0
+        } else {
+            self.settings.master_volume * self.settings.sfx_volume
+        }
+    }
+
+    fn music_volume(&self) -> f32 {
+        if self.settings.muted {
+            0.0
+        } else {
+            self.settings.master_volume * self.settings.music_volume
+        }
+    }
+
+    fn play(&mut self, ctx: &mut Context, sfx: Sfx) {
+        if self.settings.muted {
+            return;
+        }
+        let index = SFX.iter().position(|s| *s == sfx).unwrap();
+        let data = match self.sounds[index] {
+            Some(ref data) => data.clone(),
+            None => return,
+        };
+
+        // Every play gets its own source so the same effect can overlap itself
+        let volume = self.sfx_volume();
+        let result = Source::from_data(ctx, data).and_then(|mut source| {
+            source.set_volume(volume);
+            source.play()?;
+            Ok(source)
+        });
+        match result {
+            Ok(source) => self.playing.push(source),
+            Err(e) => println!("Failed to play sound {:?}: {}", sfx, e),
+        }
+    }
+
+    /// Plays the sound effect for an event, if it has one
+    pub fn handle_event(&mut self, ctx: &mut Context, event: GameEvent) {
+        let sfx = match event {
+            GameEvent::Jumped => Sfx::Jump,
+            GameEvent::HeadBumped { .. } => Sfx::Bump,
+            GameEvent::EnemyStomped { .. } => Sfx::Stomp,
+            GameEvent::CoinCollected { .. } | GameEvent::ItemCollected { .. } => Sfx::Coin,
+            GameEvent::StarCoinCollected { .. } => Sfx::PowerUp,
+            GameEvent::PlayerDied { .. } => Sfx::Death,
+            GameEvent::SoundPlayed { sfx } => sfx,
+            _ => return,
+        };
+        self.play(ctx, sfx);
+    }
+
+    /// Starts looping a music track, unless it is already playing. `None` stops the music.
+    pub fn play_music(&mut self, ctx: &mut Context, path: Option<&str>) {
+        if self.music.as_ref().map(|m| m.path.as_str()) == path {
+            return;
+        }
+        self.music = None;
+        let path = match path {
+            Some(path) => path,
+            None => return,
+        };
+
+        let volume = self.music_volume();
+        let result = Source::new(ctx, path).and_then(|mut source| {
+            source.set_repeat(true);
+            source.set_volume(volume);
+            source.play()?;
+            Ok(source)
+        });
+        if let Err(ref e) = result {
+            println!("Failed to play music {}: {}", path, e);
+        }
+        self.music = Some(Music {
+            path: path.to_string(),
+            source: result.ok(),
+        });
+    }
+
+    pub fn toggle_mute(&mut self) {
+        self.settings.muted = !self.settings.muted;
+        self.apply_volume();
+    }
+
+    /// Raises or lowers one of the volumes by a step
+    pub fn change_volume(&mut self, volume: Volume, steps: i32) {
+        let setting = match volume {
+            Volume::Master => &mut self.settings.master_volume,
+            Volume::Music => &mut self.settings.music_volume,
+            Volume::Sfx => &mut self.settings.sfx_volume,
+        };
+        *setting = (*setting + steps as f32 * VOLUME_STEP).clamp(0.0, 1.0);
+        self.apply_volume();
+    }
+
+    fn apply_volume(&mut self) {
+        let sfx_volume = self.sfx_volume();
+        for source in &mut self.playing {
+            source.set_volume(sfx_volume);
+        }
+        let music_volume = self.music_volume();
+        if let Some(Music {
+            source: Some(ref mut source),
+            ..
+        }) = self.music
+        {
+            source.set_volume(music_volume);
+        }
+    }
+
+    /// Drops effects that have finished playing
+    pub fn update(&mut self) {
+        self.playing.retain(|source| !source.stopped());
+    }
+}
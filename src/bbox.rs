@@ -1,22 +1,23 @@
-use graphics::Point2;
+use math::{Scalar, Vec2};
 
 /// Bounding box used by entities for collision
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
 pub struct BBox {
-    pub pos: Point2,
-    pub size: Point2,
+    pub pos: Vec2,
+    pub size: Vec2,
 }
 
 impl BBox {
-    pub fn new(x: f32, y: f32, width: f32, height: f32) -> BBox {
+    pub fn new(x: Scalar, y: Scalar, width: Scalar, height: Scalar) -> BBox {
         BBox {
-            pos: Point2::new(x, y),
-            size: Point2::new(width, height),
+            pos: Vec2::new(x, y),
+            size: Vec2::new(width, height),
         }
     }
 
-    /// Checks if bounding box intersects with another bounding box
+    /// Checks if bounding box intersects with another bounding box. Boxes that only touch do not intersect.
     pub fn intersects(&self, other: &BBox) -> bool {
-        (self.pos.x + self.size.x > other.pos.x && self.pos.x <= other.pos.x + other.size.x)
-            && (self.pos.y + self.size.y > other.pos.y && self.pos.y <= other.pos.y + other.size.y)
+        (self.pos.x + self.size.x > other.pos.x && self.pos.x < other.pos.x + other.size.x)
+            && (self.pos.y + self.size.y > other.pos.y && self.pos.y < other.pos.y + other.size.y)
     }
 }
@@ -0,0 +1,34 @@
+use graphics::Point2;
+use map::{Map, TILE_SIZE};
+use math::{self, scalar, Vec2, ZERO};
+use {SCREEN_HEIGHT, SCREEN_WIDTH};
+
+/// Camera following a target while staying inside the map. A locked camera holds the
+/// players in, so it moves in scalars like they do.
+pub struct Camera {
+    pub pos: Vec2,
+}
+
+impl Camera {
+    pub fn new() -> Camera {
+        Camera {
+            pos: Vec2::default(),
+        }
+    }
+
+    /// Centers the camera on the target, clamped to the edges of the map
+    pub fn follow(&mut self, target: Vec2, map: &Map) {
+        let max_x = scalar(map.dimensions.0 as f32 * TILE_SIZE - SCREEN_WIDTH as f32);
+        let max_y = scalar(map.dimensions.1 as f32 * TILE_SIZE - SCREEN_HEIGHT as f32);
+        let x = target.x - scalar(SCREEN_WIDTH as f32 / 2.0);
+        let y = target.y - scalar(SCREEN_HEIGHT as f32 / 2.0);
+        self.pos.x = math::max(math::min(x, max_x), ZERO);
+        self.pos.y = math::max(math::min(y, max_y), ZERO);
+    }
+
+    /// Converts a position in the world to a position on the screen
+    pub fn to_screen(&self, pos: Point2) -> Point2 {
+        let camera = self.pos.to_point();
+        Point2::new(pos.x - camera.x, pos.y - camera.y)
+    }
+}
@@ -0,0 +1,57 @@
+use math::{to_bits, Scalar, Vec2};
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// FNV-1a hash of the game state, used to check that two runs with the
+/// same inputs ended up in the same state
+pub struct Checksum {
+    hash: u64,
+}
+
+impl Checksum {
+    pub fn new() -> Checksum {
+        Checksum { hash: FNV_OFFSET }
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.hash ^= u64::from(value);
+        self.hash = self.hash.wrapping_mul(FNV_PRIME);
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        for byte in 0..4 {
+            self.write_u8((value >> (byte * 8)) as u8);
+        }
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.write_u32(value as u32);
+        self.write_u32((value >> 32) as u32);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    // Length first, so "ab" then "c" differs from "a" then "bc"
+    pub fn write_str(&mut self, value: &str) {
+        self.write_u32(value.len() as u32);
+        for &byte in value.as_bytes() {
+            self.write_u8(byte);
+        }
+    }
+
+    pub fn write_scalar(&mut self, value: Scalar) {
+        self.write_u32(to_bits(value));
+    }
+
+    pub fn write_vec2(&mut self, value: Vec2) {
+        self.write_scalar(value.x);
+        self.write_scalar(value.y);
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.hash
+    }
+}
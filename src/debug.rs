@@ -0,0 +1,248 @@
+use bbox::BBox;
+use ecs::{Collider, Transform, Velocity};
+use ggez::graphics::{self, Color, DrawMode, Point2, Rect};
+use ggez::{Context, GameResult};
+use map::TILE_SIZE;
+use math::to_f32;
+use object::Solid;
+use state::MainState;
+use {SCREEN_HEIGHT, SCREEN_WIDTH};
+
+const VELOCITY_SCALE: f32 = 8.0; // Length of a velocity arrow per pixel per tick
+const SLOWDOWNS: [u32; 4] = [1, 2, 4, 8]; // Fixed steps per simulated tick
+
+/// Debug control over how fast the world runs: paused, one tick at a time or slowed down
+#[derive(Clone, Copy)]
+pub struct TimeControl {
+    pub paused: bool,
+    pub slowdown: u32,
+    step: bool,  // Run one tick while paused
+    waited: u32, // Fixed steps since the last tick while slowed down
+}
+
+impl TimeControl {
+    pub fn new() -> TimeControl {
+        TimeControl {
+            paused: false,
+            slowdown: 1,
+            step: false,
+            waited: 0,
+        }
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+        self.step = false;
+    }
+
+    /// Runs exactly one more tick, pausing first if running
+    pub fn step(&mut self) {
+        self.paused = true;
+        self.step = true;
+    }
+
+    /// Goes from full speed to half, a quarter, an eighth and back
+    pub fn cycle_speed(&mut self) {
+        let i = SLOWDOWNS.iter().position(|&s| s == self.slowdown).unwrap_or(0);
+        self.slowdown = SLOWDOWNS[(i + 1) % SLOWDOWNS.len()];
+        self.waited = 0;
+    }
+
+    pub fn is_normal(&self) -> bool {
+        !self.paused && self.slowdown == 1
+    }
+
+    /// Whether the world moves on during this fixed step
+    pub fn tick(&mut self) -> bool {
+        if self.paused {
+            let step = self.step;
+            self.step = false;
+            return step;
+        }
+        self.waited += 1;
+        if self.waited < self.slowdown {
+            return false;
+        }
+        self.waited = 0;
+        true
+    }
+}
+
+fn color(r: f32, g: f32, b: f32) -> Color {
+    Color::new(r, g, b, 1.0)
+}
+
+impl MainState {
+    /// Draws what collision works with over the world: the tile grid, bounding boxes,
+    /// ground probes, the tiles last touched and velocities, with a panel of player state
+    pub fn draw_debug(&mut self, ctx: &mut Context) -> GameResult<()> {
+        self.draw_tile_grid(ctx)?;
+
+        graphics::set_color(ctx, color(0.0, 1.0, 1.0))?;
+        for platform in &self.game.platforms {
+            self.draw_bbox(ctx, &platform.bbox)?;
+        }
+        for entity in &self.game.world.entities {
+            let (transform, collider) = match (&entity.transform, &entity.collider) {
+                (Some(transform), Some(collider)) => (transform, collider),
+                _ => continue,
+            };
+            let bbox_color = if entity.hostile.is_some() {
+                color(1.0, 0.2, 0.2)
+            } else if entity.item.is_some() {
+                color(1.0, 1.0, 0.2)
+            } else {
+                color(0.4, 0.6, 1.0)
+            };
+            graphics::set_color(ctx, bbox_color)?;
+            self.draw_bbox(ctx, &collider.bbox(transform))?;
+            self.draw_contacts(ctx, transform, entity.velocity.as_ref(), collider)?;
+        }
+        for player in &self.game.players {
+            graphics::set_color(ctx, color(0.2, 1.0, 0.2))?;
+            self.draw_bbox(ctx, &player.obj.get_bbox())?;
+            graphics::set_color(ctx, color(1.0, 0.2, 1.0))?;
+            self.draw_bbox(ctx, &player.obj.ground_probe())?;
+            let obj = &player.obj;
+            self.draw_contacts(ctx, &obj.transform, Some(&obj.velocity), &obj.collider)?;
+        }
+        graphics::set_color(ctx, color(1.0, 1.0, 1.0))?;
+
+        // State of each player, below the HUD
+        for (i, player) in self.game.players.iter().enumerate() {
+            let obj = &player.obj;
+            let lines = [
+                format!(
+                    "P{} pos ({:.1}, {:.1}) vel ({:.2}, {:.2})",
+                    player.index + 1,
+                    to_f32(obj.transform.pos.x),
+                    to_f32(obj.transform.pos.y),
+                    to_f32(obj.velocity.linear.x),
+                    to_f32(obj.velocity.linear.y)
+                ),
+                format!(
+                    "jumping {} falling {} running {} grounded {} dead {}",
+                    obj.velocity.is_jumping,
+                    obj.velocity.is_falling,
+                    obj.is_running,
+                    obj.collider.grounded,
+                    player.dead
+                ),
+                format!(
+                    "turn_cycle {} coyote {} jump_buffer {} standing_on {:?} ceiling {:?}",
+                    player.turn_cycle,
+                    player.coyote_ticks,
+                    player.jump_buffer_ticks,
+                    obj.collider.standing_on,
+                    obj.collider.ceiling_tile
+                ),
+                format!(
+                    "animation {:?} time {:.2} frame {}",
+                    player.animator.state,
+                    player.animator.time,
+                    player.animator.frame(&self.game.player_animations)
+                ),
+            ];
+            for (j, line) in lines.iter().enumerate() {
+                let text = graphics::Text::new(ctx, line, &self.font)?;
+                let y = 60.0 + (i * lines.len() + j) as f32 * 18.0;
+                graphics::draw(ctx, &text, Point2::new(8.0, y), 0.0)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Shows the tick the world is at with how fast it runs, when that is not full speed
+    pub fn draw_frame_counter(&mut self, ctx: &mut Context) -> GameResult<()> {
+        if self.time.is_normal() && !self.debug {
+            return Ok(());
+        }
+        let speed = if self.time.paused {
+            "PAUSED".to_string()
+        } else if self.time.slowdown > 1 {
+            format!("1/{} SPEED", self.time.slowdown)
+        } else {
+            String::new()
+        };
+        let mut label = format!("TICK {} {}", self.game.clock, speed);
+        if let Some(ref session) = self.netplay {
+            label = format!("{} ROLLBACKS {}", label.trim_end(), session.rollbacks);
+        }
+        let text = graphics::Text::new(ctx, label.trim_end(), &self.font)?;
+        let x = SCREEN_WIDTH as f32 - text.width() as f32 - 8.0;
+        graphics::draw(ctx, &text, Point2::new(x, 28.0), 0.0)
+    }
+
+    // Lines between the tiles on screen
+    fn draw_tile_grid(&mut self, ctx: &mut Context) -> GameResult<()> {
+        graphics::set_color(ctx, Color::new(1.0, 1.0, 1.0, 0.15))?;
+        let (width, height) = (SCREEN_WIDTH as f32, SCREEN_HEIGHT as f32);
+        let camera = self.game.camera.pos.to_point();
+        let mut x = -(camera.x % TILE_SIZE);
+        while x < width {
+            graphics::line(ctx, &[Point2::new(x, 0.0), Point2::new(x, height)], 1.0)?;
+            x += TILE_SIZE;
+        }
+        let mut y = -(camera.y % TILE_SIZE);
+        while y < height {
+            graphics::line(ctx, &[Point2::new(0.0, y), Point2::new(width, y)], 1.0)?;
+            y += TILE_SIZE;
+        }
+        Ok(())
+    }
+
+    fn draw_bbox(&self, ctx: &mut Context, bbox: &BBox) -> GameResult<()> {
+        let pos = self.game.camera.to_screen(bbox.pos.to_point());
+        let size = bbox.size.to_point();
+        graphics::rectangle(
+            ctx,
+            DrawMode::Line(1.0),
+            Rect::new(pos.x, pos.y, size.x, size.y),
+        )
+    }
+
+    // Fills the tiles an object stands on or bumped from below and draws its velocity.
+    // The color set for its bounding box is restored afterwards.
+    fn draw_contacts(
+        &self,
+        ctx: &mut Context,
+        transform: &Transform,
+        velocity: Option<&Velocity>,
+        collider: &Collider,
+    ) -> GameResult<()> {
+        let previous = graphics::get_color(ctx);
+        let map = self.game.map.as_ref().unwrap();
+        let standing_tile = match collider.standing_on {
+            Some(Solid::Tile(index)) => Some(index),
+            _ => None,
+        };
+        graphics::set_color(ctx, Color::new(1.0, 0.5, 0.0, 0.4))?;
+        for index in standing_tile.into_iter().chain(collider.ceiling_tile) {
+            let center = map.tile_center(index);
+            let pos = self.game.camera.to_screen(Point2::new(
+                center.x - TILE_SIZE / 2.0,
+                center.y - TILE_SIZE / 2.0,
+            ));
+            let rect = Rect::new(pos.x, pos.y, TILE_SIZE, TILE_SIZE);
+            graphics::rectangle(ctx, DrawMode::Fill, rect)?;
+        }
+
+        // Up is positive in velocities but down on screen
+        let bbox = collider.bbox(transform);
+        let (pos, size) = (bbox.pos.to_point(), bbox.size.to_point());
+        let start = self
+            .game
+            .camera
+            .to_screen(Point2::new(pos.x + size.x / 2.0, pos.y + size.y / 2.0));
+        let velocity = velocity.map_or(Point2::new(0.0, 0.0), |v| v.linear.to_point());
+        let end = Point2::new(
+            start.x + velocity.x * VELOCITY_SCALE,
+            start.y - velocity.y * VELOCITY_SCALE,
+        );
+        graphics::set_color(ctx, color(1.0, 1.0, 1.0))?;
+        if start != end {
+            graphics::line(ctx, &[start, end], 2.0)?;
+        }
+        graphics::set_color(ctx, previous)
+    }
+}
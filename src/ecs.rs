@@ -0,0 +1,419 @@
+/// Entities of the world other than the players, each made of optional components.
+/// Systems act on every entity that has the components they need, so a new kind
+/// of entity is a new mix of components rather than new code. Players are made of
+/// the same Transform, Velocity and Collider, see `Object`.
+use bbox::BBox;
+use checksum::Checksum;
+use events::{EventQueue, GameEvent};
+use game::Game;
+use ggez::{graphics, Context, GameResult};
+use map::{Map, COIN, TILE_SIZE};
+use math::{scalar, Vec2, ZERO};
+use object::{self, Direction, Inset, Solid};
+use physics::PhysicsProfile;
+use player::Player;
+use script::Scripted;
+use sprite;
+use state::MainState;
+
+const WALK_SPEED: f32 = 1.0;
+const WALK_FRAME_TICKS: u32 = 10;
+
+pub type EntityId = usize; // Index into the entities of the world, until they are next removed
+
+/// Where an entity is and which way it faces
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Transform {
+    pub pos: Vec2,
+    pub direction: Direction,
+}
+
+impl Transform {
+    pub fn new(pos: Vec2, direction: Direction) -> Transform {
+        Transform { pos, direction }
+    }
+}
+
+/// How fast an entity moves, and whether it is going up or coming down
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Velocity {
+    pub linear: Vec2,
+    pub is_falling: bool,
+    pub is_jumping: bool,
+}
+
+/// Box an entity collides with, below and to the right of its position, and what it
+/// touched in the last update
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Collider {
+    pub size: Vec2,
+    pub inset: Inset,
+    pub grounded: bool,
+    pub standing_on: Option<Solid>,
+    pub hit_ceiling: bool,
+    pub ceiling_tile: Option<usize>, // Index of the tile hit from below
+    pub hit_wall_left: bool,
+    pub hit_wall_right: bool,
+}
+
+impl Collider {
+    pub fn new(size: f32) -> Collider {
+        Collider {
+            size: Vec2::from_f32(size, size),
+            inset: Inset::default(),
+            grounded: false,
+            standing_on: None,
+            hit_ceiling: false,
+            ceiling_tile: None,
+            hit_wall_left: false,
+            hit_wall_right: false,
+        }
+    }
+}
+
+/// Falls and collides with the map and platforms
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Physics {
+    pub gravity: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Sheet {
+    Enemies,
+    Tiles,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Sprite {
+    pub sheet: Sheet,
+    pub frame: String,
+}
+
+/// Cycles the sprite through frames
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Animation {
+    pub frames: Vec<String>,
+    pub ticks_per_frame: u32,
+}
+
+/// Stomps it takes to get rid of the entity
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Health {
+    pub hp: u32,
+}
+
+/// How an entity decides where to go
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Ai {
+    // Walks in the direction it faces, turning around at walls
+    Walk { speed: f32 },
+}
+
+/// Hurts players who touch it, unless it can be stomped and they land on it
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Hostile {
+    pub stompable: bool,
+}
+
+/// Picked up by players who touch it
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Item {
+    Coin,
+}
+
+/// An entity, as the components it has
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Components {
+    pub transform: Option<Transform>,
+    pub velocity: Option<Velocity>,
+    pub collider: Option<Collider>,
+    pub physics: Option<Physics>,
+    pub sprite: Option<Sprite>,
+    pub animation: Option<Animation>,
+    pub health: Option<Health>,
+    pub ai: Option<Ai>,
+    pub hostile: Option<Hostile>,
+    pub item: Option<Item>,
+    pub script: Option<Scripted>,
+    #[serde(default)]
+    despawned: bool, // Removed at the end of the tick
+}
+
+impl Components {
+    /// Bounding box of entities with a transform and a collider
+    pub fn bbox(&self) -> Option<BBox> {
+        match (self.transform.as_ref(), self.collider.as_ref()) {
+            (Some(transform), Some(collider)) => Some(collider.bbox(transform)),
+            _ => None,
+        }
+    }
+}
+
+fn transform(pos: Vec2, direction: Direction) -> Option<Transform> {
+    Some(Transform::new(pos, direction))
+}
+
+pub fn goomba(pos: Vec2) -> Components {
+    Components {
+        transform: transform(pos, Direction::Left),
+        velocity: Some(Velocity::default()),
+        collider: Some(Collider::new(TILE_SIZE)),
+        physics: Some(Physics { gravity: true }),
+        sprite: Some(Sprite {
+            sheet: Sheet::Enemies,
+            frame: "goomba_walk_1".to_string(),
+        }),
+        animation: Some(Animation {
+            frames: vec!["goomba_walk_1".to_string(), "goomba_walk_2".to_string()],
+            ticks_per_frame: WALK_FRAME_TICKS,
+        }),
+        health: Some(Health { hp: 1 }),
+        ai: Some(Ai::Walk { speed: WALK_SPEED }),
+        hostile: Some(Hostile { stompable: true }),
+        ..Components::default()
+    }
+}
+
+/// A coin floating where it is placed
+pub fn coin(pos: Vec2) -> Components {
+    Components {
+        transform: transform(pos, Direction::Right),
+        collider: Some(Collider::new(TILE_SIZE)),
+        sprite: Some(Sprite {
+            sheet: Sheet::Tiles,
+            frame: format!("tile_{}", COIN),
+        }),
+        item: Some(Item::Coin),
+        ..Components::default()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct World {
+    pub entities: Vec<Components>,
+}
+
+impl World {
+    pub fn new() -> World {
+        World::default()
+    }
+
+    pub fn spawn(&mut self, components: Components) -> EntityId {
+        self.entities.push(components);
+        self.entities.len() - 1
+    }
+
+    /// Marks an entity to be removed by `maintain`, ids stay valid until then
+    pub fn despawn(&mut self, id: EntityId) {
+        self.entities[id].despawned = true;
+    }
+
+    /// Removes the entities despawned since the last call
+    pub fn maintain(&mut self) {
+        self.entities.retain(|entity| !entity.despawned);
+    }
+
+    /// Bounding boxes of the hostile entities, for what checks where enemies are
+    pub fn hostile_bboxes(&self) -> Vec<BBox> {
+        self.entities
+            .iter()
+            .filter(|e| e.hostile.is_some() && !e.despawned)
+            .filter_map(Components::bbox)
+            .collect()
+    }
+
+    /// Sets the velocity of entities with an AI
+    pub fn update_ai(&mut self) {
+        for entity in &mut self.entities {
+            let components = (
+                entity.ai.as_ref(),
+                entity.transform.as_mut(),
+                entity.velocity.as_mut(),
+                entity.collider.as_ref(),
+            );
+            if let (Some(Ai::Walk { speed }), Some(transform), Some(velocity), Some(collider)) =
+                components
+            {
+                if collider.hit_wall_left {
+                    transform.direction = Direction::Right;
+                } else if collider.hit_wall_right {
+                    transform.direction = Direction::Left;
+                }
+                velocity.linear.x = match transform.direction {
+                    Direction::Left => -scalar(*speed),
+                    Direction::Right => scalar(*speed),
+                };
+            }
+        }
+    }
+
+    /// Moves entities with physics by their velocity. Those falling out of the map are removed.
+    pub fn update_physics(&mut self, map: &Map, solids: &[BBox], profile: &PhysicsProfile) {
+        let bottom = scalar(map.dimensions.1 as f32 * TILE_SIZE);
+        for entity in &mut self.entities {
+            let components = (
+                entity.physics.as_ref(),
+                entity.transform.as_mut(),
+                entity.velocity.as_mut(),
+                entity.collider.as_mut(),
+            );
+            let (physics, transform, velocity, collider) = match components {
+                (Some(physics), Some(transform), Some(velocity), Some(collider)) => {
+                    (physics, transform, velocity, collider)
+                }
+                _ => continue,
+            };
+            if physics.gravity {
+                velocity.fall(profile);
+            }
+            object::update(transform, velocity, collider, map, solids);
+            if !collider.grounded && !velocity.is_jumping {
+                velocity.is_falling = true;
+            }
+            if transform.pos.y > bottom {
+                entity.despawned = true;
+            }
+        }
+    }
+
+    /// Moves animated sprites on to the frame for the tick
+    pub fn update_animation(&mut self, clock: u64) {
+        for entity in &mut self.entities {
+            if let (Some(animation), Some(sprite)) =
+                (entity.animation.as_ref(), entity.sprite.as_mut())
+            {
+                if animation.frames.is_empty() {
+                    continue;
+                }
+                let frame = clock / u64::from(animation.ticks_per_frame.max(1));
+                sprite.frame = animation.frames[frame as usize % animation.frames.len()].clone();
+            }
+        }
+    }
+
+    /// Players landing on stompable entities hurt them, any other touch of a hostile
+    /// entity hurts the player. Touching an item picks it up.
+    pub fn update_contacts(
+        &mut self,
+        players: &mut [Player],
+        profile: &PhysicsProfile,
+        events: &mut EventQueue,
+    ) {
+        for player in players.iter_mut() {
+            let player_bbox = player.obj.get_bbox();
+            for entity in &mut self.entities {
+                if player.dead {
+                    break;
+                }
+                let touching = match entity.bbox() {
+                    Some(bbox) => !entity.despawned && player_bbox.intersects(&bbox),
+                    None => false,
+                };
+                if !touching {
+                    continue;
+                }
+
+                if let Some(item) = entity.item {
+                    entity.despawned = true;
+                    events.push(GameEvent::ItemCollected {
+                        item,
+                        player: player.index,
+                    });
+                }
+                let stompable = match entity.hostile {
+                    Some(ref hostile) => hostile.stompable,
+                    None => continue,
+                };
+                if stompable && player.obj.velocity.linear.y < ZERO {
+                    let hp = entity.health.as_mut().map_or(0, |health| {
+                        health.hp = health.hp.saturating_sub(1);
+                        health.hp
+                    });
+                    if hp == 0 {
+                        entity.despawned = true;
+                    }
+                    player.obj.velocity.linear.y = scalar(profile.jump_impulse / 2.0);
+                    player.obj.velocity.is_jumping = true;
+                    player.obj.velocity.is_falling = false;
+                    events.push(GameEvent::EnemyStomped {
+                        player: player.index,
+                    });
+                } else {
+                    events.push(GameEvent::PlayerDamaged {
+                        player: player.index,
+                    });
+                    player.kill(events);
+                }
+            }
+        }
+    }
+
+    pub fn checksum(&self, sum: &mut Checksum) {
+        sum.write_u32(self.entities.len() as u32);
+        for entity in &self.entities {
+            if let Some(ref transform) = entity.transform {
+                transform.checksum(sum);
+            }
+            if let Some(ref velocity) = entity.velocity {
+                velocity.checksum(sum);
+            }
+            if let Some(ref collider) = entity.collider {
+                collider.checksum(sum);
+            }
+            if let Some(ref health) = entity.health {
+                sum.write_u32(health.hp);
+            }
+            if let Some(ref script) = entity.script {
+                script.state.checksum(sum);
+            }
+            sum.write_bool(entity.despawned);
+        }
+    }
+}
+
+impl Game {
+    /// Empties the world of everything spawned and puts back the entities the map places
+    pub fn reset_world(&mut self) {
+        self.world = World::new();
+        let map = self.map.as_ref().unwrap();
+        for def in &map.entities {
+            self.world.spawn(Components {
+                transform: transform(Vec2::from_f32(def.pos.0, def.pos.1), Direction::Right),
+                collider: Some(Collider::new(TILE_SIZE)),
+                sprite: Some(Sprite {
+                    sheet: Sheet::Enemies,
+                    frame: def.sprite.clone(),
+                }),
+                script: Some(Scripted::new(&def.script)),
+                ..Components::default()
+            });
+        }
+    }
+}
+
+impl MainState {
+    /// Draws every entity with a body and a sprite
+    pub fn draw_entities(&mut self, ctx: &mut Context) -> GameResult<()> {
+        for entity in &self.game.world.entities {
+            let (transform, sprite) = match (entity.transform.as_ref(), entity.sprite.as_ref()) {
+                (Some(transform), Some(sprite)) => (transform, sprite),
+                _ => continue,
+            };
+            let atlas = match sprite.sheet {
+                Sheet::Enemies => &self.enemy_sprites,
+                Sheet::Tiles => &self.tiles,
+            };
+            let frame = match atlas.get(&sprite.frame) {
+                Some(frame) => frame,
+                None => continue,
+            };
+            let dest = self.game.camera.to_screen(transform.pos.to_point());
+            let param = sprite::create_sprite_param(frame, dest, &atlas.image);
+            graphics::draw_ex(ctx, &atlas.image, param)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,47 @@
+use audio::Sfx;
+use ecs::Item;
+use math::Vec2;
+
+/// Things that happened in the simulation during a tick, for audio, the HUD
+/// and other systems to react to without gameplay code knowing about them
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum GameEvent {
+    Jumped,
+    // Positions are where the player's feet are
+    Landed { pos: Vec2 },
+    Skidded { pos: Vec2 },
+    // Tiles are indices into the main layer, players are indices into the players
+    HeadBumped { tile: usize, player: usize },
+    BrickBroken { tile: usize },
+    EnemyStomped { player: usize },
+    CoinCollected { tile: usize, player: usize },
+    StarCoinCollected { tile: usize, player: usize },
+    ItemCollected { item: Item, player: usize },
+    PlayerDamaged { player: usize },
+    PlayerDied { player: usize },
+    // Asked for by a script
+    SoundPlayed { sfx: Sfx },
+}
+
+/// Events pushed during the current tick. Cleared at the start of every tick.
+pub struct EventQueue {
+    events: Vec<GameEvent>,
+}
+
+impl EventQueue {
+    pub fn new() -> EventQueue {
+        EventQueue { events: Vec::new() }
+    }
+
+    pub fn push(&mut self, event: GameEvent) {
+        self.events.push(event);
+    }
+
+    pub fn clear(&mut self) {
+        self.events.clear();
+    }
+
+    pub fn events(&self) -> &[GameEvent] {
+        &self.events
+    }
+}
@@ -0,0 +1,892 @@
+use animation::{AnimationSet, TICKS_PER_SECOND};
+use bbox::BBox;
+use camera::Camera;
+use checksum::Checksum;
+use ecs::World;
+use events::{EventQueue, GameEvent};
+use ggez::{Context, GameError, GameResult};
+use map::{LayerKind, Map, BRICK_BLOCK, QUESTION_BLOCK, STAR_COIN, TILE_SIZE, USED_BLOCK};
+use math::{self, scalar, Vec2, ZERO};
+use object::{self, Direction, Solid};
+use physics::{PhysicsConfig, PhysicsProfile};
+use platform::Platform;
+use player::{Buttons, Player};
+use profile::StarCoin;
+use script::{Script, Zone};
+use sprite::EMPTY_SPRITE;
+use stats::Stats;
+use std::collections::HashMap;
+use std::rc::Rc;
+use trigger::TriggerState;
+use warp::{self, Destination, Transition, WarpDirection, WARP_TICKS};
+use SCREEN_WIDTH;
+
+const CAMERA_MARGIN: f32 = 64.0; // Closest the leading player gets to the edge of the screen
+pub const RESPAWN_TICKS: u32 = 120; // Ticks a dead player is shown for before coming back
+
+/// Where the game gets the maps it goes to and the scripts they use
+pub trait Loader {
+    fn load_map(&mut self, path: &str) -> GameResult<Map>;
+    /// Compiled script, or an error starting with the path
+    fn load_script(&mut self, path: &str) -> Result<Script, String>;
+}
+
+impl Loader for Context {
+    fn load_map(&mut self, path: &str) -> GameResult<Map> {
+        let map = Map::load(self, path)?;
+        warp::validate(self, &map, path)?;
+        Ok(map)
+    }
+
+    fn load_script(&mut self, path: &str) -> Result<Script, String> {
+        Script::load(self, path)
+    }
+}
+
+/// A level the players just finished, for the profile being played to record
+pub struct Completion {
+    pub level: String,
+    pub next: String,
+    pub ticks: u32,
+    pub star_coins: Vec<StarCoin>,
+    pub stats: Vec<Stats>,
+}
+
+/// Everything the simulation runs on, without what shows it. Two games fed the same
+/// buttons from the same start stay the same, tick for tick.
+pub struct Game {
+    pub map: Option<Rc<Map>>, // Shared with the snapshots taken since it last changed
+    pub map_path: String, // File the current map was loaded from and is saved to
+    pub level: String,    // Map the current level started in, which may be left for other areas
+    pub camera: Camera,
+    pub physics: PhysicsConfig,
+    pub player_animations: AnimationSet,
+    pub tile_count: usize, // Tiles in the tile sheet, which scripts can place
+    pub events: EventQueue,
+    pub stats: Vec<Stats>, // One per player
+    pub clock: u64,
+    pub time_left: u32,   // Ticks left to finish the level
+    pub level_ticks: u32, // Ticks spent in the level so far
+    pub players: Vec<Player>,
+    pub world: World, // Everything other than the players
+    pub platforms: Vec<Platform>,
+    pub warp: Option<Transition>,
+    pub star_coins: Vec<StarCoin>, // Picked up in the current level
+    pub completed: Option<Completion>,
+    pub scripts: HashMap<String, Script>, // Compiled scripts of the current map, by path
+    pub zones: Vec<Zone>,
+    pub message: Option<(String, u32)>, // Shown by a script, with the ticks left to show it
+    pub script_errors: Vec<String>,
+    pub triggers: Vec<TriggerState>, // One per trigger of the current map
+    pub camera_locked: bool,
+    pub music: Option<String>, // Track a trigger switched to, played instead of the map's
+}
+
+impl Game {
+    /// One player on a map, with the level not started yet
+    pub fn new(
+        map: Map,
+        path: &str,
+        physics: PhysicsConfig,
+        player_animations: AnimationSet,
+        tile_count: usize,
+    ) -> Game {
+        Game {
+            time_left: map.time_limit * TICKS_PER_SECOND as u32,
+            platforms: map.platforms.iter().map(Platform::new).collect(),
+            map: Some(Rc::new(map)),
+            map_path: path.to_string(),
+            level: path.to_string(),
+            camera: Camera::new(),
+            physics,
+            player_animations,
+            tile_count,
+            events: EventQueue::new(),
+            stats: vec![Stats::new(0)],
+            clock: 0,
+            level_ticks: 0,
+            players: vec![Player::new(0)],
+            world: World::new(),
+            warp: None,
+            star_coins: Vec::new(),
+            completed: None,
+            scripts: HashMap::new(),
+            zones: Vec::new(),
+            message: None,
+            script_errors: Vec::new(),
+            triggers: Vec::new(),
+            camera_locked: false,
+            music: None,
+        }
+    }
+
+    /// Physics profile with the overrides of the current map applied
+    pub fn physics_profile(&self) -> PhysicsProfile {
+        self.physics
+            .profile
+            .with_overrides(&self.map.as_ref().unwrap().physics)
+    }
+
+    /// Current map to change, copied first if a snapshot still shares it
+    pub fn map_mut(&mut self) -> &mut Map {
+        Rc::make_mut(self.map.as_mut().unwrap())
+    }
+
+    /// Loads a map with its scripts, making it the current map
+    pub fn load_map<L: Loader>(&mut self, loader: &mut L, path: &str) -> GameResult<()> {
+        let map = loader.load_map(path)?;
+        self.map = Some(Rc::new(map));
+        self.map_path = path.to_string();
+        self.load_scripts(loader);
+        Ok(())
+    }
+
+    /// Starts a level from the beginning of the map it is in
+    pub fn start_level<L: Loader>(&mut self, loader: &mut L, path: &str) -> GameResult<()> {
+        self.load_map(loader, path)?;
+        self.level = path.to_string();
+        self.star_coins.clear();
+        self.restart_level();
+        Ok(())
+    }
+
+    /// Puts the players back at the start with the full time limit. Players out of
+    /// lives stay out, unless everyone is, in which case the game starts over.
+    /// Star coins already picked up stay picked up, as they are gone from the map.
+    pub fn restart_level(&mut self) {
+        self.warp = None;
+        if self.stats.iter().all(|stats| stats.lives == 0) {
+            for stats in &mut self.stats {
+                *stats = Stats::new(stats.player);
+            }
+        }
+        for (player, stats) in self.players.iter_mut().zip(&self.stats) {
+            player.respawn();
+            if stats.lives == 0 {
+                player.dead = true;
+                player.dead_ticks = RESPAWN_TICKS;
+            }
+        }
+        self.time_left = self.map.as_ref().unwrap().time_limit * TICKS_PER_SECOND as u32;
+        self.level_ticks = 0;
+        self.reset_platforms();
+        self.reset_world();
+        self.reset_scripts();
+        self.reset_triggers();
+    }
+
+    /// Puts the platforms back where the map places them
+    pub fn reset_platforms(&mut self) {
+        self.platforms = self.map
+            .as_ref()
+            .unwrap()
+            .platforms
+            .iter()
+            .map(Platform::new)
+            .collect();
+    }
+
+    /// Adds the second player next to the first, or removes them
+    pub fn toggle_second_player(&mut self) {
+        if self.players.len() > 1 {
+            self.players.truncate(1);
+            self.stats.truncate(1);
+            return;
+        }
+        let mut player = Player::new(1);
+        player.obj.transform.pos = self.players[0].obj.transform.pos;
+        self.players.push(player);
+        self.stats.push(Stats::new(1));
+    }
+
+    // Takes out the platforms that fell below the map, the way the world drops despawned
+    // entities. Whatever stood on one stands on nothing, and contacts with the solids
+    // after it move down to their new index.
+    fn remove_fallen_platforms(&mut self) {
+        let bottom = scalar(self.map.as_ref().unwrap().dimensions.1 as f32 * TILE_SIZE);
+        let mut kept = 0;
+        let new_index: Vec<Option<usize>> = self
+            .platforms
+            .iter()
+            .map(|platform| {
+                if platform.bbox.pos.y > bottom {
+                    return None;
+                }
+                kept += 1;
+                Some(kept - 1)
+            })
+            .collect();
+        let removed = self.platforms.len() - kept;
+        if removed == 0 {
+            return;
+        }
+        let remap = |standing_on: &mut Option<Solid>| {
+            if let Some(Solid::Dynamic(i)) = *standing_on {
+                *standing_on = match new_index.get(i) {
+                    Some(&index) => index.map(Solid::Dynamic),
+                    None => Some(Solid::Dynamic(i - removed)), // Another player
+                };
+            }
+        };
+        for player in &mut self.players {
+            remap(&mut player.obj.collider.standing_on);
+        }
+        let entities = &mut self.world.entities;
+        for collider in entities.iter_mut().filter_map(|e| e.collider.as_mut()) {
+            remap(&mut collider.standing_on);
+        }
+        self.platforms.retain(|p| p.bbox.pos.y <= bottom);
+    }
+
+    // Platforms move first, carrying along whatever stood on them last tick
+    fn update_platforms(&mut self, profile: &PhysicsProfile) {
+        self.remove_fallen_platforms();
+        let map = self.map.as_ref().unwrap();
+        let standing_on = || {
+            let players = self
+                .players
+                .iter()
+                .map(|player| player.obj.collider.standing_on);
+            let entities = self.world.entities.iter();
+            players.chain(entities.filter_map(|e| e.collider.as_ref().map(|c| c.standing_on)))
+        };
+        let ridden: Vec<bool> = (0..self.platforms.len())
+            .map(|i| standing_on().any(|solid| solid == Some(Solid::Dynamic(i))))
+            .collect();
+        for (platform, ridden) in self.platforms.iter_mut().zip(ridden) {
+            platform.update(ridden, profile);
+        }
+
+        // Riders are swept along so a platform can't push them into a wall
+        let platforms: Vec<BBox> = self.platforms.iter().map(|p| p.bbox).collect();
+        let moved = &self.platforms;
+        let delta = |standing_on: Option<Solid>| match standing_on {
+            Some(Solid::Dynamic(i)) if i < moved.len() => Some(moved[i].delta),
+            _ => None,
+        };
+        for player in &mut self.players {
+            if let Some(delta) = delta(player.obj.collider.standing_on) {
+                player.obj.carry(delta, map, &platforms);
+            }
+        }
+        for entity in &mut self.world.entities {
+            if let (Some(transform), Some(collider)) =
+                (entity.transform.as_mut(), entity.collider.as_ref())
+            {
+                if let Some(delta) = delta(collider.standing_on) {
+                    object::carry(transform, collider, delta, map, &platforms);
+                }
+            }
+        }
+    }
+
+    /// Runs the game for one tick, with the buttons each player holds
+    pub fn simulate<L: Loader>(&mut self, loader: &mut L, buttons: &[Buttons]) -> GameResult<()> {
+        self.clock += 1;
+        self.events.clear();
+        let profile = self.physics_profile();
+        let restart = self.players
+            .iter()
+            .zip(buttons)
+            .any(|(player, held)| held.pressed(player.buttons, Buttons::RESTART));
+        for (player, &held) in self.players.iter_mut().zip(buttons) {
+            player.apply_buttons(held, &profile);
+        }
+        if restart {
+            self.restart_level();
+        }
+
+        if self.warp.is_some() {
+            self.update_warp(loader)?;
+        } else {
+            self.update_play()?;
+        }
+        for event in self.events.events() {
+            for stats in &mut self.stats {
+                stats.handle_event(*event);
+            }
+        }
+        self.update_deaths();
+        Ok(())
+    }
+
+    // In co-op, a dead player with lives left comes back where a living player is
+    // once their death has been shown. Once everyone is dead the level restarts,
+    // unless nobody has lives left, which is game over.
+    fn update_deaths(&mut self) {
+        let living = match self.players.iter().find(|player| !player.dead) {
+            Some(player) => player.obj.transform.pos,
+            None => {
+                let lives_left = self.stats.iter().any(|stats| stats.lives > 0);
+                if lives_left && self.all_deaths_shown() {
+                    self.restart_level();
+                }
+                return;
+            }
+        };
+        for (player, stats) in self.players.iter_mut().zip(&self.stats) {
+            if player.dead && player.dead_ticks >= RESPAWN_TICKS && stats.lives > 0 {
+                player.respawn();
+                player.obj.transform.pos = living;
+            }
+        }
+    }
+
+    /// Whether every player is dead and has been for long enough to be done dying
+    pub fn all_deaths_shown(&self) -> bool {
+        self.players
+            .iter()
+            .all(|player| player.dead && player.dead_ticks >= RESPAWN_TICKS)
+    }
+
+    fn update_play(&mut self) -> GameResult<()> {
+        let profile = self.physics_profile();
+        self.update_platforms(&profile);
+        {
+            let map = self.map.as_ref().unwrap();
+            let platforms: Vec<BBox> = self.platforms.iter().map(|p| p.bbox).collect();
+            // Players are solid to each other, so they can stand on each other's heads
+            for i in 0..self.players.len() {
+                let mut solids = platforms.clone();
+                solids.extend(
+                    self.players
+                        .iter()
+                        .filter(|other| other.index != i && !other.dead)
+                        .map(|other| other.obj.get_bbox()),
+                );
+                self.players[i].update(
+                    map,
+                    &solids,
+                    &profile,
+                    &self.player_animations,
+                    &mut self.events,
+                )?;
+            }
+            self.world.update_ai();
+            self.world.update_physics(map, &platforms, &profile);
+            self.world.update_animation(self.clock);
+
+            // Pushing into a warp the way it faces takes the players through it
+            for player in &mut self.players {
+                let entered = map.warps.iter().find(|warp| warp.entered_by(player));
+                if let (Some(warp), false) = (entered, player.dead) {
+                    let (x, y) = (warp.tile.0 as f32 * TILE_SIZE, warp.tile.1 as f32 * TILE_SIZE);
+                    match warp.direction {
+                        WarpDirection::Left | WarpDirection::Right => {
+                            player.obj.transform.pos.y = scalar(y)
+                        }
+                        _ => player.obj.transform.pos.x = scalar(x),
+                    }
+                    self.warp = Some(Transition {
+                        player: player.index,
+                        direction: warp.direction,
+                        ticks: 0,
+                        destination: Some(warp.destination.clone()),
+                    });
+                    return Ok(());
+                }
+            }
+        }
+        self.update_camera();
+
+        if self.players.iter().any(|player| !player.dead) {
+            self.level_ticks += 1;
+            if self.time_left > 0 {
+                self.time_left -= 1;
+            } else {
+                for player in &mut self.players {
+                    player.kill(&mut self.events);
+                }
+            }
+        }
+
+        self.world
+            .update_contacts(&mut self.players, &profile, &mut self.events);
+        self.world.maintain();
+
+        // Star coins are picked up by touching them. The map is only changed when one
+        // is, so it stays shared with the snapshots otherwise.
+        let mut touched = Vec::new();
+        for player in self.players.iter().filter(|player| !player.dead) {
+            let map = self.map.as_ref().unwrap();
+            for (index, _) in map.tiles_overlapping(&player.obj.get_bbox()) {
+                let tile = &map.layer(LayerKind::Main).tiles[index];
+                if tile.active && tile.id == STAR_COIN {
+                    touched.push((index, player.index));
+                }
+            }
+        }
+        for (index, player) in touched {
+            let tile = &mut self.map_mut().layer_mut(LayerKind::Main).tiles[index];
+            // Both players may touch it on the same tick
+            if tile.id != STAR_COIN {
+                continue;
+            }
+            tile.id = EMPTY_SPRITE;
+            self.star_coins.push(StarCoin {
+                map: self.map_path.clone(),
+                tile: index,
+            });
+            self.events.push(GameEvent::StarCoinCollected {
+                tile: index,
+                player,
+            });
+        }
+
+        self.update_scripts();
+        self.update_triggers();
+
+        // Question blocks give a coin the first time they are bumped, bricks break
+        let bumped: Vec<(usize, usize)> = self.events
+            .events()
+            .iter()
+            .filter_map(|event| match *event {
+                GameEvent::HeadBumped { tile, player } => Some((tile, player)),
+                _ => None,
+            })
+            .collect();
+        for (index, player) in bumped {
+            let tile = &mut self.map_mut().layer_mut(LayerKind::Main).tiles[index];
+            if tile.id == QUESTION_BLOCK {
+                tile.id = USED_BLOCK;
+                self.events.push(GameEvent::CoinCollected {
+                    tile: index,
+                    player,
+                });
+            } else if tile.id == BRICK_BLOCK {
+                tile.id = EMPTY_SPRITE;
+                self.events.push(GameEvent::BrickBroken { tile: index });
+            }
+        }
+        Ok(())
+    }
+
+    // Follows the middle of the players still alive. When they are too far apart the
+    // camera stays with the one furthest right, pulling the one lagging behind along.
+    // A locked camera stays put and keeps the players inside the screen.
+    fn update_camera(&mut self) {
+        if self.camera_locked {
+            let left_edge = self.camera.pos.x;
+            let right_edge = self.camera.pos.x + scalar(SCREEN_WIDTH as f32);
+            for player in self.players.iter_mut().filter(|player| !player.dead) {
+                let width = player.obj.get_bbox().size.x;
+                if player.obj.transform.pos.x < left_edge {
+                    player.obj.transform.pos.x = left_edge;
+                } else if player.obj.transform.pos.x + width > right_edge {
+                    player.obj.transform.pos.x = right_edge - width;
+                }
+            }
+            return;
+        }
+
+        let alive: Vec<Vec2> = self
+            .players
+            .iter()
+            .filter(|player| !player.dead)
+            .map(|player| player.obj.transform.pos)
+            .collect();
+        if alive.is_empty() {
+            return;
+        }
+        let min_x = alive.iter().map(|p| p.x).fold(alive[0].x, math::min);
+        let max_x = alive.iter().map(|p| p.x).fold(alive[0].x, math::max);
+        let y = alive.iter().fold(ZERO, |sum, p| sum + p.y) / scalar(alive.len() as f32);
+        let lead = scalar(SCREEN_WIDTH as f32 / 2.0 - CAMERA_MARGIN);
+        let middle = (min_x + max_x) / scalar(2.0);
+        let target = Vec2::new(math::max(middle, max_x - lead), y);
+        self.camera.follow(target, self.map.as_ref().unwrap());
+
+        let left_edge = self.camera.pos.x;
+        for player in self.players.iter_mut().filter(|player| !player.dead) {
+            if player.obj.transform.pos.x < left_edge {
+                player.obj.transform.pos.x = left_edge;
+            }
+        }
+    }
+
+    // Slides the player into a warp, then everyone out of the exit it leads to
+    fn update_warp<L: Loader>(&mut self, loader: &mut L) -> GameResult<()> {
+        let mut transition = self.warp.take().unwrap();
+        for player in &mut self.players {
+            let sliding = match transition.destination {
+                Some(_) => player.index == transition.player,
+                None => !player.dead,
+            };
+            if sliding {
+                player.slide(transition.direction.step(), &self.player_animations);
+            }
+        }
+        self.update_camera();
+        transition.ticks += 1;
+        if transition.ticks < WARP_TICKS {
+            self.warp = Some(transition);
+            return Ok(());
+        }
+
+        if let Some(destination) = transition.destination {
+            match self.arrive(loader, &destination, transition.player) {
+                Ok(exiting) => self.warp = exiting,
+                Err(e) => {
+                    println!("Failed to warp to {}: {}", destination, e);
+                    self.restart_level();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Goes to the map a warp leads to and puts the players in its exit, ready to come out
+    fn arrive<L: Loader>(
+        &mut self,
+        loader: &mut L,
+        destination: &Destination,
+        player: usize,
+    ) -> GameResult<Option<Transition>> {
+        let name = match *destination {
+            Destination::Exit { ref exit } => exit,
+            Destination::Area { ref map, ref exit } => {
+                // Same level, so the timer keeps running
+                self.load_map(loader, map)?;
+                self.reset_platforms();
+                self.reset_world();
+                self.reset_scripts();
+                self.reset_triggers();
+                exit
+            }
+            Destination::Level { ref map } => {
+                self.completed = Some(Completion {
+                    level: self.level.clone(),
+                    next: map.clone(),
+                    ticks: self.level_ticks,
+                    star_coins: self.star_coins.clone(),
+                    stats: self.stats.clone(),
+                });
+                self.start_level(loader, map)?;
+                return Ok(None);
+            }
+        };
+        let exit = match self.map.as_ref().unwrap().exit(name) {
+            Some(exit) => exit.clone(),
+            None => {
+                return Err(GameError::ResourceLoadError(format!(
+                    "No exit named {}",
+                    name
+                )))
+            }
+        };
+
+        let (dx, dy) = exit.direction.step();
+        let distance = WARP_TICKS as f32;
+        for player in self.players.iter_mut().filter(|player| !player.dead) {
+            player.obj.transform.pos = Vec2::from_f32(
+                exit.tile.0 as f32 * TILE_SIZE - dx * distance,
+                exit.tile.1 as f32 * TILE_SIZE - dy * distance,
+            );
+            match exit.direction {
+                WarpDirection::Left => player.obj.transform.direction = Direction::Left,
+                WarpDirection::Right => player.obj.transform.direction = Direction::Right,
+                _ => {}
+            }
+        }
+        Ok(Some(Transition {
+            player,
+            direction: exit.direction,
+            ticks: 0,
+            destination: None,
+        }))
+    }
+
+    /// Checksum of everything the simulation depends on. Two runs fed the same
+    /// inputs give the same checksum, on any platform when built with `fixed-point`.
+    pub fn checksum(&self) -> u64 {
+        let mut sum = Checksum::new();
+        sum.write_u64(self.clock);
+        sum.write_u32(self.time_left);
+        sum.write_u32(self.level_ticks);
+        if let Some(ref map) = self.map {
+            map.checksum(&mut sum);
+        }
+        for player in &self.players {
+            player.checksum(&mut sum);
+        }
+        for stats in &self.stats {
+            sum.write_u32(stats.score);
+            sum.write_u32(stats.coins);
+            sum.write_u32(stats.lives);
+        }
+        for star_coin in &self.star_coins {
+            sum.write_str(&star_coin.map);
+            sum.write_u32(star_coin.tile as u32);
+        }
+        self.world.checksum(&mut sum);
+        for platform in &self.platforms {
+            platform.checksum(&mut sum);
+        }
+        for zone in &self.zones {
+            zone.players.checksum(&mut sum);
+            zone.script.checksum(&mut sum);
+        }
+        for trigger in &self.triggers {
+            trigger.occupancy.checksum(&mut sum);
+            sum.write_bool(trigger.fired);
+        }
+        sum.write_bool(self.camera_locked);
+        sum.write_vec2(self.camera.pos);
+        sum.write_bool(self.warp.is_some());
+        if let Some(ref warp) = self.warp {
+            warp.checksum(&mut sum);
+        }
+        sum.finish()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use platform::{Motion, PlatformDef};
+    use script::{EntityDef, ScriptZone};
+    use toml;
+    use trigger::{Action, Trigger};
+
+    const FLOOR_Y: u32 = 11;
+    const TICKS: u32 = 1200;
+
+    const ZONE_SCRIPT: &str = "
+on start
+  set visits 0
+end
+on enter
+  set visits visits + 1
+  start_timer drop 30
+end
+on timer drop
+  spawn_enemy 24 8
+  message \"Look out\" 60
+end
+";
+
+    const MOVER_SCRIPT: &str = "
+on start
+  set step 1
+end
+on tick
+  move step 0
+  wait 20
+  set step 0 - step
+end
+on touch
+  remove
+end
+";
+
+    /// Gives the scripts of the course, with no other maps to go to
+    pub struct Scripts;
+
+    impl Loader for Scripts {
+        fn load_map(&mut self, path: &str) -> GameResult<Map> {
+            Err(GameError::ResourceLoadError(format!("No map {}", path)))
+        }
+
+        fn load_script(&mut self, path: &str) -> Result<Script, String> {
+            let source = match path {
+                "/zone.script" => ZONE_SCRIPT,
+                "/mover.script" => MOVER_SCRIPT,
+                _ => return Err(format!("{}: not found", path)),
+            };
+            Script::parse(source).map_err(|e| format!("{} {}", path, e))
+        }
+    }
+
+    // Floor with a gap to fall down, blocks to bump, a star coin, a moving platform,
+    // a trigger spawning things and scripts running in a zone and an entity
+    fn course_map() -> Map {
+        let mut map = Map::new(48, FLOOR_Y + 2);
+        let width = map.dimensions.0;
+        {
+            let main = map.layer_mut(LayerKind::Main);
+            for x in (0..30).chain(33..width) {
+                main.tiles[(FLOOR_Y * width + x) as usize].id = BRICK_BLOCK;
+            }
+            main.tiles[((FLOOR_Y - 3) * width + 4) as usize].id = QUESTION_BLOCK;
+            main.tiles[((FLOOR_Y - 3) * width + 5) as usize].id = BRICK_BLOCK;
+            main.tiles[((FLOOR_Y - 1) * width + 7) as usize].id = STAR_COIN;
+        }
+        let motion = Motion::Oscillate {
+            offset: (3.0 * TILE_SIZE, 0.0),
+            period: 120,
+        };
+        map.platforms.push(PlatformDef::new(
+            (10.0 * TILE_SIZE, 8.0 * TILE_SIZE),
+            motion,
+        ));
+        let mut trigger = Trigger::new((16, FLOOR_Y - 3));
+        trigger.size = (1, 3);
+        trigger.actions = vec![
+            Action::SpawnCoin { tile: (18, 9) },
+            Action::ToggleTiles {
+                tile: (4, FLOOR_Y - 3),
+                size: (2, 1),
+            },
+        ];
+        map.triggers.push(trigger);
+        map.scripts.push(ScriptZone {
+            tile: (20, FLOOR_Y - 3),
+            size: (2, 3),
+            script: "/zone.script".to_string(),
+        });
+        map.entities.push(EntityDef {
+            pos: (26.0 * TILE_SIZE, (FLOOR_Y - 1) as f32 * TILE_SIZE),
+            sprite: "goomba_1".to_string(),
+            script: "/mover.script".to_string(),
+        });
+        map
+    }
+
+    /// Two players at the start of a course with a bit of everything in it
+    pub fn course() -> Game {
+        let physics = toml::from_str(include_str!("../resources/physics.toml")).unwrap();
+        let animations =
+            toml::from_str(include_str!("../resources/player_animations.toml")).unwrap();
+        let mut game = Game::new(
+            course_map(),
+            "/course.toml",
+            PhysicsConfig::new(physics),
+            animations,
+            1000,
+        );
+        game.load_scripts(&mut Scripts);
+        game.toggle_second_player();
+        game.restart_level();
+        game
+    }
+
+    /// Buttons a player holds on a tick. Made up, but the same every time, mostly
+    /// running right and jumping.
+    pub fn buttons(player: usize, tick: u32) -> Buttons {
+        // Xorshift of the player and the stretch of ticks the buttons are held for
+        let mut x = (tick / 8 + 1).wrapping_mul(2_654_435_761) ^ ((player as u32 + 1) * 40_503);
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        let mut buttons = Buttons::default();
+        buttons.set(Buttons::RIGHT, x % 8 < 6);
+        buttons.set(Buttons::LEFT, x % 8 == 7);
+        buttons.set(Buttons::JUMP, x & 0x100 != 0);
+        buttons.set(Buttons::RUN, x & 0x600 != 0);
+        buttons
+    }
+
+    #[test]
+    fn same_buttons_give_the_same_game() {
+        let (mut a, mut b) = (course(), course());
+        assert_eq!(a.checksum(), b.checksum());
+        for tick in 0..TICKS {
+            let held = [buttons(0, tick), buttons(1, tick)];
+            a.simulate(&mut Scripts, &held).unwrap();
+            b.simulate(&mut Scripts, &held).unwrap();
+            assert_eq!(
+                a.checksum(),
+                b.checksum(),
+                "the games differ after tick {}",
+                tick
+            );
+        }
+        assert!(a.script_errors.is_empty(), "{:?}", a.script_errors);
+    }
+
+    #[test]
+    fn other_buttons_give_another_game() {
+        let (mut a, mut b) = (course(), course());
+        for tick in 0..TICKS {
+            a.simulate(&mut Scripts, &[buttons(0, tick), buttons(1, tick)])
+                .unwrap();
+            b.simulate(&mut Scripts, &[buttons(1, tick), buttons(0, tick)])
+                .unwrap();
+        }
+        assert_ne!(a.checksum(), b.checksum());
+    }
+
+    #[test]
+    fn checksum_covers_the_counters() {
+        let checksum = course().checksum();
+        let changes: [fn(&mut Game); 6] = [
+            |game| game.stats[0].score += 100,
+            |game| game.stats[1].coins += 1,
+            |game| game.stats[1].lives -= 1,
+            |game| game.time_left -= 1,
+            |game| game.level_ticks += 1,
+            |game| {
+                game.star_coins.push(StarCoin {
+                    map: "/course.toml".to_string(),
+                    tile: 7,
+                })
+            },
+        ];
+        for (i, change) in changes.iter().enumerate() {
+            let mut game = course();
+            change(&mut game);
+            assert_ne!(
+                game.checksum(),
+                checksum,
+                "change {} isn't in the checksum",
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn falling_platforms_are_gone_below_the_map() {
+        let mut game = course();
+        let def = PlatformDef::new(
+            (31.0 * TILE_SIZE, 4.0 * TILE_SIZE),
+            Motion::Falling { delay: 0 },
+        );
+        game.platforms.push(Platform::new(&def));
+        game.players[1].obj.collider.standing_on = Some(Solid::Dynamic(1));
+        let idle = [Buttons::default(); 2];
+        for _ in 0..TICKS {
+            game.simulate(&mut Scripts, &idle).unwrap();
+        }
+        assert_eq!(game.platforms.len(), 1);
+    }
+
+    // Where the last tick left things that the next one starts from
+    #[test]
+    fn checksum_covers_contacts_the_camera_and_warps() {
+        let warping = || {
+            let mut game = course();
+            game.warp = Some(Transition {
+                player: 0,
+                direction: WarpDirection::Down,
+                ticks: 0,
+                destination: Some(Destination::Exit {
+                    exit: "start".to_string(),
+                }),
+            });
+            game
+        };
+        let checksum = warping().checksum();
+        let changes: [fn(&mut Game); 6] = [
+            |game| game.players[0].obj.collider.standing_on = Some(Solid::Dynamic(0)),
+            |game| game.players[1].obj.collider.hit_ceiling = true,
+            |game| game.players[0].obj.collider.hit_wall_right = true,
+            |game| game.camera.pos.x += scalar(1.0),
+            |game| game.warp.as_mut().unwrap().direction = WarpDirection::Up,
+            |game| {
+                game.warp.as_mut().unwrap().destination = Some(Destination::Exit {
+                    exit: "pipe".to_string(),
+                })
+            },
+        ];
+        for (i, change) in changes.iter().enumerate() {
+            let mut game = warping();
+            change(&mut game);
+            assert_ne!(
+                game.checksum(),
+                checksum,
+                "change {} isn't in the checksum",
+                i
+            );
+        }
+    }
+}
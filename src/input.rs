@@ -1,20 +1,71 @@
-use ggez::event::{Keycode, Mod};
-use ggez::Context;
-use object::Direction;
+use ggez::event::{Keycode, Mod, LCTRLMOD, RCTRLMOD};
+use ggez::{Context, GameError};
+use map::{LayerKind, TILE_SIZE};
+use player::Buttons;
+use platform::{Motion, PlatformDef, FALL_DELAY, OSCILLATE_PERIOD, PATH_SPEED};
 use sprite::EMPTY_SPRITE;
-use state::MainState;
+use state::{MainState, Prompt};
+use trigger::{Action, Trigger};
+use warp::{self, Destination, Exit, Warp, WarpDirection};
+
+// Player one plays with WASD, player two with the arrow keys
+const BINDINGS: [[(Keycode, u8); 6]; 2] = [
+    [
+        (Keycode::A, Buttons::LEFT),
+        (Keycode::D, Buttons::RIGHT),
+        (Keycode::W, Buttons::UP),
+        (Keycode::S, Buttons::DOWN),
+        (Keycode::Space, Buttons::JUMP),
+        (Keycode::LShift, Buttons::RUN),
+    ],
+    [
+        (Keycode::Left, Buttons::LEFT),
+        (Keycode::Right, Buttons::RIGHT),
+        (Keycode::Up, Buttons::UP),
+        (Keycode::Down, Buttons::DOWN),
+        (Keycode::RCtrl, Buttons::JUMP),
+        (Keycode::RShift, Buttons::RUN),
+    ],
+];
+
+// Index of the key binding set a key belongs to and the button it is
+fn button(keycode: Keycode) -> Option<(usize, u8)> {
+    BINDINGS.iter().enumerate().find_map(|(set, keys)| {
+        keys.iter()
+            .find(|&&(key, _)| key == keycode)
+            .map(|&(_, button)| (set, button))
+    })
+}
 
 impl MainState {
     pub fn key_down_editor(
         &mut self,
-        _ctx: &mut Context,
+        ctx: &mut Context,
         keycode: Keycode,
-        _keymod: Mod,
+        keymod: Mod,
         _repeat: bool,
     ) {
-        let map = &mut self.map.as_mut().unwrap();
+        if keymod.intersects(LCTRLMOD | RCTRLMOD) {
+            self.key_down_editor_ctrl(ctx, keycode);
+            return;
+        }
+        if keycode == Keycode::P || keycode == Keycode::K {
+            self.key_down_editor_platform(keycode);
+            return;
+        }
+        if keycode == Keycode::E || keycode == Keycode::G || keycode == Keycode::Return {
+            self.key_down_editor_warp(keycode);
+            return;
+        }
+        if [Keycode::T, Keycode::Y, Keycode::B, Keycode::O, Keycode::I].contains(&keycode) {
+            self.key_down_editor_trigger(keycode);
+            return;
+        }
+
+        let tile_count = self.tiles.frame_count();
+        let map = self.game.map_mut();
         let max_index = (map.dimensions.0 * map.dimensions.1) as usize;
-        let tile = &mut map.tiles[self.editor.index].id;
+        let tile = &mut map.layer_mut(self.editor.layer).tiles[self.editor.index].id;
         match keycode {
             Keycode::D => {
                 self.editor.index += 1;
@@ -38,7 +89,7 @@ impl MainState {
             }
             Keycode::Up => {
                 *tile += 1;
-                if *tile >= EMPTY_SPRITE {
+                if *tile >= tile_count {
                     *tile = 0;
                 }
             }
@@ -53,51 +104,284 @@ impl MainState {
             Keycode::X => {
                 *tile = EMPTY_SPRITE;
             }
+            Keycode::H => {
+                let tile = &mut map.layer_mut(self.editor.layer).tiles[self.editor.index];
+                tile.active = !tile.active;
+            }
+            Keycode::Num1 => {
+                self.editor.layer = LayerKind::Background;
+            }
+            Keycode::Num2 => {
+                self.editor.layer = LayerKind::Main;
+            }
+            Keycode::Num3 => {
+                self.editor.layer = LayerKind::Foreground;
+            }
+            Keycode::V => {
+                let visible = &mut self.editor.visible[self.editor.layer.index()];
+                *visible = !*visible;
+            }
             _ => {}
         };
     }
 
-    pub fn key_down_play(
-        &mut self,
-        _ctx: &mut Context,
-        keycode: Keycode,
-        _keymod: Mod,
-        repeat: bool,
-    ) {
-        if repeat {
+    // P places a platform at the cursor, or changes how the one there moves, and then
+    // removes it. K adds the cursor as a waypoint of the platform being edited, or as
+    // the far end of its swing.
+    fn key_down_editor_platform(&mut self, keycode: Keycode) {
+        let map = self.game.map_mut();
+        let cursor = (
+            (self.editor.index as u32 % map.dimensions.0) as f32 * TILE_SIZE,
+            (self.editor.index as u32 / map.dimensions.0) as f32 * TILE_SIZE,
+        );
+        let at_cursor = map.platforms.iter().position(|def| def.pos == cursor);
+
+        match keycode {
+            Keycode::P => match at_cursor {
+                None => {
+                    let motion = Motion::Path {
+                        speed: PATH_SPEED,
+                        waypoints: Vec::new(),
+                    };
+                    map.platforms.push(PlatformDef::new(cursor, motion));
+                    self.editor.platform = Some(map.platforms.len() - 1);
+                }
+                Some(i) => {
+                    let motion = match map.platforms[i].motion {
+                        Motion::Path { .. } => Some(Motion::Oscillate {
+                            offset: (4.0 * TILE_SIZE, 0.0),
+                            period: OSCILLATE_PERIOD,
+                        }),
+                        Motion::Oscillate { .. } => Some(Motion::Falling { delay: FALL_DELAY }),
+                        Motion::Falling { .. } => None,
+                    };
+                    match motion {
+                        Some(motion) => {
+                            map.platforms[i].motion = motion;
+                            self.editor.platform = Some(i);
+                        }
+                        None => {
+                            map.platforms.remove(i);
+                            self.editor.platform = None;
+                        }
+                    }
+                }
+            },
+            Keycode::K => {
+                let def = match self.editor.platform.and_then(|i| map.platforms.get_mut(i)) {
+                    Some(def) => def,
+                    None => return,
+                };
+                match def.motion {
+                    Motion::Path {
+                        ref mut waypoints, ..
+                    } => waypoints.push(cursor),
+                    Motion::Oscillate { ref mut offset, .. } => {
+                        *offset = (cursor.0 - def.pos.0, cursor.1 - def.pos.1);
+                    }
+                    Motion::Falling { .. } => {}
+                }
+            }
+            _ => {}
+        }
+        self.game.reset_platforms();
+    }
+
+    // E places an exit at the cursor, or turns the one there and then removes it.
+    // G does the same for warps, Return types in where the warp leads.
+    fn key_down_editor_warp(&mut self, keycode: Keycode) {
+        let map = self.game.map_mut();
+        let cursor = (
+            self.editor.index as u32 % map.dimensions.0,
+            self.editor.index as u32 / map.dimensions.0,
+        );
+        let exit = map.exits.iter().position(|exit| exit.tile == cursor);
+        let warp = map.warps.iter().position(|warp| warp.tile == cursor);
+
+        match keycode {
+            Keycode::E => match exit {
+                None => {
+                    let name = (1..)
+                        .map(|i| format!("exit_{}", i))
+                        .find(|name| map.exit(name).is_none())
+                        .unwrap();
+                    map.exits.push(Exit {
+                        name,
+                        tile: cursor,
+                        direction: WarpDirection::Up,
+                    });
+                }
+                Some(i) => {
+                    let direction = map.exits[i].direction.next();
+                    if direction == WarpDirection::Up {
+                        map.exits.remove(i);
+                    } else {
+                        map.exits[i].direction = direction;
+                    }
+                }
+            },
+            Keycode::G => {
+                if let Some(i) = warp {
+                    let direction = map.warps[i].direction.next();
+                    if direction == WarpDirection::Down {
+                        map.warps.remove(i);
+                    } else {
+                        map.warps[i].direction = direction;
+                    }
+                }
+            }
+            Keycode::Return => {
+                let text = match warp {
+                    Some(i) => map.warps[i].destination.to_string(),
+                    None => String::new(),
+                };
+                self.editor.prompt = Some(Prompt::Warp(text));
+            }
+            _ => {}
+        }
+    }
+
+    // T places a trigger at the cursor, or changes when the one there fires and then
+    // removes it. Y makes the cursor the bottom right corner of the trigger being
+    // edited, B changes what sets it off, O whether it fires only once and I types in
+    // its actions.
+    fn key_down_editor_trigger(&mut self, keycode: Keycode) {
+        let map = self.game.map_mut();
+        let cursor = (
+            self.editor.index as u32 % map.dimensions.0,
+            self.editor.index as u32 / map.dimensions.0,
+        );
+        let at_cursor = map.triggers.iter().position(|trigger| trigger.tile == cursor);
+
+        if keycode == Keycode::T {
+            match at_cursor {
+                None => {
+                    map.triggers.push(Trigger::new(cursor));
+                    self.editor.trigger = Some(map.triggers.len() - 1);
+                }
+                Some(i) => {
+                    let on = map.triggers[i].on.next();
+                    if on == Trigger::new(cursor).on {
+                        map.triggers.remove(i);
+                        self.editor.trigger = None;
+                    } else {
+                        map.triggers[i].on = on;
+                        self.editor.trigger = Some(i);
+                    }
+                }
+            }
+            self.game.reset_triggers();
             return;
         }
 
+        let trigger = match self.editor.trigger.and_then(|i| map.triggers.get_mut(i)) {
+            Some(trigger) => trigger,
+            None => return,
+        };
         match keycode {
-            Keycode::D => {
-                self.player.moving_right = true;
-                self.player.obj.direction = Direction::Right;
-                if self.player.obj.velocity.x <= -3.0 {
-                    self.player.turn_cycle = 20;
+            Keycode::Y if cursor.0 >= trigger.tile.0 && cursor.1 >= trigger.tile.1 => {
+                trigger.size = (cursor.0 - trigger.tile.0 + 1, cursor.1 - trigger.tile.1 + 1);
+            }
+            Keycode::B => trigger.by = trigger.by.next(),
+            Keycode::O => trigger.once = !trigger.once,
+            Keycode::I => {
+                let i = self.editor.trigger.unwrap();
+                self.editor.prompt = Some(Prompt::Actions(i, trigger.actions_text()));
+            }
+            _ => {}
+        }
+    }
+
+    /// Keys while typing in a prompt. Return sets the destination of the warp at the
+    /// cursor, adding a warp there if there is none, or the actions of a trigger.
+    /// Escape cancels.
+    pub fn key_down_prompt(&mut self, ctx: &mut Context, keycode: Keycode) {
+        match keycode {
+            Keycode::Backspace => {
+                if let Some(ref mut prompt) = self.editor.prompt {
+                    prompt.text_mut().pop();
                 }
             }
-            Keycode::A => {
-                self.player.moving_left = true;
-                self.player.obj.direction = Direction::Left;
-                if self.player.obj.velocity.x >= 3.0 {
-                    self.player.turn_cycle = 20;
+            Keycode::Escape => {
+                self.editor.prompt = None;
+            }
+            Keycode::Return => {
+                let text = match self.editor.prompt {
+                    Some(Prompt::Actions(i, ref text)) => {
+                        match Action::parse_list(text) {
+                            Ok(actions) => {
+                                if let Some(trigger) = self.game.map_mut().triggers.get_mut(i) {
+                                    trigger.actions = actions;
+                                }
+                            }
+                            Err(e) => {
+                                println!("Invalid trigger actions: {}", e);
+                                return;
+                            }
+                        }
+                        self.editor.prompt = None;
+                        return;
+                    }
+                    Some(Prompt::Warp(ref text)) => text.clone(),
+                    None => return,
+                };
+                let map = self.game.map_mut();
+                let checked = Destination::parse(&text)
+                    .map_err(GameError::ResourceLoadError)
+                    .and_then(|destination| {
+                        warp::check_destination(ctx, map, &destination).map(|_| destination)
+                    });
+                let destination = match checked {
+                    Ok(destination) => destination,
+                    Err(e) => {
+                        println!("Invalid warp destination: {}", e);
+                        return;
+                    }
+                };
+                let cursor = (
+                    self.editor.index as u32 % map.dimensions.0,
+                    self.editor.index as u32 / map.dimensions.0,
+                );
+                match map.warps.iter().position(|warp| warp.tile == cursor) {
+                    Some(i) => map.warps[i].destination = destination,
+                    None => map.warps.push(Warp {
+                        tile: cursor,
+                        direction: WarpDirection::Down,
+                        destination,
+                    }),
                 }
+                self.editor.prompt = None;
             }
-            Keycode::Space => {
-                if !self.player.obj.is_jumping && !self.player.obj.is_falling {
-                    self.player.obj.is_jumping = true;
-                    let run_speed = self.player.obj.velocity.x.abs() / 4.0;
-                    self.player.obj.velocity.y = 6.0 + 1.5 * run_speed;
+            _ => {}
+        }
+    }
+
+    // Ctrl + S saves the map, Ctrl + L reloads it from disk
+    fn key_down_editor_ctrl(&mut self, ctx: &mut Context, keycode: Keycode) {
+        match keycode {
+            Keycode::S => {
+                let game = &self.game;
+                if let Err(e) = game.map.as_ref().unwrap().save(ctx, &game.map_path) {
+                    println!("Failed to save map: {}", e);
                 }
             }
-            Keycode::LShift => {
-                self.player.obj.is_running = true;
+            Keycode::L => {
+                let path = self.game.map_path.clone();
+                match self.game.load_map(ctx, &path) {
+                    Ok(()) => {
+                        self.editor.deselect();
+                        self.game.restart_level();
+                    }
+                    Err(e) => println!("Failed to load map: {}", e),
+                }
             }
             _ => {}
         }
     }
 
-    pub fn key_up_play(
+    // Play mode keys only say which buttons are held, the players react to them on
+    // the next tick
+    pub fn key_down_play(
         &mut self,
         _ctx: &mut Context,
         keycode: Keycode,
@@ -107,25 +391,35 @@ impl MainState {
         if repeat {
             return;
         }
+        if keycode == Keycode::R {
+            self.held[0].set(Buttons::RESTART, true);
+        }
+        if keycode == Keycode::Backspace {
+            self.rewind.held = true;
+        }
+        if let Some((set, button)) = button(keycode) {
+            self.held[set].set(button, true);
+        }
+    }
 
-        match keycode {
-            Keycode::D => {
-                self.player.moving_right = false;
-            }
-            Keycode::A => {
-                self.player.moving_left = false;
-            }
-            Keycode::Space => {
-                if self.player.obj.is_jumping {
-                    self.player.obj.is_jumping = false;
-                    self.player.obj.is_falling = true;
-                    self.player.obj.velocity.y = 0.0;
-                }
-            }
-            Keycode::LShift => {
-                self.player.obj.is_running = false;
-            }
-            _ => {}
+    pub fn key_up_play(
+        &mut self,
+        _ctx: &mut Context,
+        keycode: Keycode,
+        _keymod: Mod,
+        repeat: bool,
+    ) {
+        if repeat {
+            return;
+        }
+        if keycode == Keycode::R {
+            self.held[0].set(Buttons::RESTART, false);
+        }
+        if keycode == Keycode::Backspace {
+            self.rewind.held = false;
+        }
+        if let Some((set, button)) = button(keycode) {
+            self.held[set].set(button, false);
         }
     }
 }
@@ -1,17 +1,46 @@
 extern crate ggez;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate toml;
 
+mod animation;
+mod audio;
 mod player;
 mod object;
 mod bbox;
+mod camera;
 mod sprite;
 mod state;
+mod game;
 mod input;
-mod enemy;
+mod ecs;
+mod map;
+mod physics;
+mod math;
+#[cfg(feature = "fixed-point")]
+mod fixed;
+mod checksum;
+mod events;
+mod stats;
+mod particle;
+mod platform;
+mod warp;
+mod snapshot;
+mod net;
+mod profile;
+mod screen;
+mod script;
+mod trigger;
+mod occupancy;
+mod debug;
+mod rewind;
 
 use bbox::BBox;
 use ggez::conf;
 use ggez::event;
 use ggez::graphics;
+use net::NetConfig;
 use object::Object;
 use state::MainState;
 use std::env;
@@ -21,6 +50,15 @@ const SCREEN_WIDTH: u32 = 512;
 const SCREEN_HEIGHT: u32 = 512;
 
 fn main() {
+    let args: Vec<String> = env::args().collect();
+    let net_config = match NetConfig::from_args(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
     let c = conf::Conf::new();
     println!("Starting with default config: {:#?}", c);
     let ctx = &mut ggez::ContextBuilder::new("mario", "skuzzi")
@@ -37,7 +75,19 @@ fn main() {
         ctx.filesystem.mount(&path, true);
     }
 
-    let state = &mut MainState::new(ctx).unwrap();
+    let state = &mut match MainState::new(ctx) {
+        Ok(state) => state,
+        Err(e) => {
+            println!("Failed to start the game: {}", e);
+            return;
+        }
+    };
+    if let Some(config) = net_config {
+        if let Err(e) = state.start_netplay(&config) {
+            println!("Failed to start the networked game: {}", e);
+            return;
+        }
+    }
     if let Err(e) = event::run(ctx, state) {
         println!("Error encountered: {}", e);
     } else {
@@ -0,0 +1,227 @@
+use bbox::BBox;
+use checksum::Checksum;
+use ggez::graphics::Point2;
+use ggez::{Context, GameError, GameResult};
+use math::{scalar, to_f32, Scalar};
+use physics::PhysicsOverrides;
+use platform::PlatformDef;
+use script::{EntityDef, ScriptZone};
+use sprite::EMPTY_SPRITE;
+use std::io::{Read, Write};
+use toml;
+use trigger::Trigger;
+use warp::{Exit, Warp};
+
+pub const MAP_PATH: &str = "/map.toml";
+pub const TILE_SIZE: f32 = 32.0; // Size of a tile in the world
+pub const BRICK_BLOCK: usize = 1; // Breaks when bumped from below
+pub const QUESTION_BLOCK: usize = 24; // Gives a coin when bumped from below
+pub const USED_BLOCK: usize = 27; // What a question block turns into once bumped
+pub const STAR_COIN: usize = 57; // Picked up by touching it, remembered in the profile
+pub const COIN: usize = 123; // Loose coin, in another color than star coins
+
+/// The layers making up a map, in the order they are drawn
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LayerKind {
+    Background,
+    Main,
+    Foreground,
+}
+
+pub const LAYERS: [LayerKind; 3] = [LayerKind::Background, LayerKind::Main, LayerKind::Foreground];
+
+impl LayerKind {
+    pub fn index(self) -> usize {
+        match self {
+            LayerKind::Background => 0,
+            LayerKind::Main => 1,
+            LayerKind::Foreground => 2,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Tile {
+    pub active: bool, // Tiles turned off by a trigger neither collide nor draw
+    pub id: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Layer {
+    pub tiles: Vec<Tile>,
+}
+
+/// Image scrolled behind the tile map at a fraction of the camera speed
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ParallaxLayer {
+    pub image: String,
+    pub scroll_factor: f32,
+    pub offset_y: f32,
+    pub repeat: bool,
+}
+
+/// Tile map. Only the main layer takes part in collision,
+/// the background and foreground layers are decoration.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Map {
+    pub dimensions: (u32, u32),
+    #[serde(default = "default_background_color")]
+    pub background_color: (f32, f32, f32),
+    // Looping music tracks, the hurry up track plays when time runs low.
+    // Empty for no music.
+    #[serde(default = "default_music")]
+    pub music: String,
+    #[serde(default = "default_hurry_music")]
+    pub hurry_music: String,
+    // Seconds to finish the level in
+    #[serde(default = "default_time_limit")]
+    pub time_limit: u32,
+    // Drawn back to front
+    #[serde(default)]
+    pub parallax: Vec<ParallaxLayer>,
+    // Changes to the physics profile in this level, e.g. lower gravity
+    #[serde(default)]
+    pub physics: PhysicsOverrides,
+    #[serde(default)]
+    pub platforms: Vec<PlatformDef>,
+    #[serde(default)]
+    pub exits: Vec<Exit>,
+    #[serde(default)]
+    pub warps: Vec<Warp>,
+    #[serde(default)]
+    pub scripts: Vec<ScriptZone>,
+    #[serde(default)]
+    pub entities: Vec<EntityDef>,
+    #[serde(default)]
+    pub triggers: Vec<Trigger>,
+    pub layers: Vec<Layer>,
+}
+
+fn default_background_color() -> (f32, f32, f32) {
+    (0.43, 0.56, 0.97)
+}
+
+fn default_music() -> String {
+    "/music/overworld.wav".to_string()
+}
+
+fn default_hurry_music() -> String {
+    "/music/overworld_hurry.wav".to_string()
+}
+
+fn default_time_limit() -> u32 {
+    400
+}
+
+impl Map {
+    pub fn new(width: u32, height: u32) -> Map {
+        let empty = Tile {
+            active: true,
+            id: EMPTY_SPRITE,
+        };
+        let layers = LAYERS
+            .iter()
+            .map(|_| Layer {
+                tiles: vec![empty.clone(); (width * height) as usize],
+            })
+            .collect();
+        Map {
+            dimensions: (width, height),
+            background_color: default_background_color(),
+            music: default_music(),
+            hurry_music: default_hurry_music(),
+            time_limit: default_time_limit(),
+            parallax: Vec::new(),
+            physics: PhysicsOverrides::default(),
+            platforms: Vec::new(),
+            exits: Vec::new(),
+            warps: Vec::new(),
+            scripts: Vec::new(),
+            entities: Vec::new(),
+            triggers: Vec::new(),
+            layers,
+        }
+    }
+
+    pub fn layer(&self, kind: LayerKind) -> &Layer {
+        &self.layers[kind.index()]
+    }
+
+    pub fn layer_mut(&mut self, kind: LayerKind) -> &mut Layer {
+        &mut self.layers[kind.index()]
+    }
+
+    /// Exit with a name, if the map has one
+    pub fn exit(&self, name: &str) -> Option<&Exit> {
+        self.exits.iter().find(|exit| exit.name == name)
+    }
+
+    /// Indices and bounding boxes of the tiles on the main layer overlapping a bounding box
+    pub fn tiles_overlapping(&self, bbox: &BBox) -> Vec<(usize, BBox)> {
+        let (width, height) = (self.dimensions.0 as i64, self.dimensions.1 as i64);
+        let cell = |v: Scalar| (to_f32(v) / TILE_SIZE).floor() as i64;
+        let min_x = cell(bbox.pos.x).max(0);
+        let max_x = cell(bbox.pos.x + bbox.size.x).min(width - 1);
+        let min_y = cell(bbox.pos.y).max(0);
+        let max_y = cell(bbox.pos.y + bbox.size.y).min(height - 1);
+
+        let mut tiles = Vec::new();
+        for y in min_y..max_y + 1 {
+            for x in min_x..max_x + 1 {
+                let tile = BBox::new(
+                    scalar(x as f32 * TILE_SIZE),
+                    scalar(y as f32 * TILE_SIZE),
+                    scalar(TILE_SIZE),
+                    scalar(TILE_SIZE),
+                );
+                if bbox.intersects(&tile) {
+                    tiles.push(((y * width + x) as usize, tile));
+                }
+            }
+        }
+        tiles
+    }
+
+    /// Center of the tile at an index in the layers, in world coordinates
+    pub fn tile_center(&self, index: usize) -> Point2 {
+        let x = (index as u32 % self.dimensions.0) as f32 * TILE_SIZE;
+        let y = (index as u32 / self.dimensions.0) as f32 * TILE_SIZE;
+        Point2::new(x + TILE_SIZE / 2.0, y + TILE_SIZE / 2.0)
+    }
+
+    pub fn checksum(&self, sum: &mut Checksum) {
+        sum.write_u32(self.dimensions.0);
+        sum.write_u32(self.dimensions.1);
+        for layer in &self.layers {
+            for tile in &layer.tiles {
+                sum.write_u32(tile.id as u32);
+                sum.write_bool(tile.active);
+            }
+        }
+    }
+
+    /// Loads a map from a TOML file in the resource or user directories
+    pub fn load(ctx: &mut Context, path: &str) -> GameResult<Map> {
+        let mut file = ctx.filesystem.open(path)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let map: Map = toml::from_str(&contents)?;
+
+        let size = (map.dimensions.0 * map.dimensions.1) as usize;
+        if map.layers.len() != LAYERS.len() || map.layers.iter().any(|l| l.tiles.len() != size) {
+            return Err(GameError::ResourceLoadError(format!(
+                "Map {} does not match its dimensions",
+                path
+            )));
+        }
+        Ok(map)
+    }
+
+    /// Saves the map as TOML to the user directory
+    pub fn save(&self, ctx: &mut Context, path: &str) -> GameResult<()> {
+        let contents = toml::to_string(self)?;
+        let mut file = ctx.filesystem.create(path)?;
+        file.write_all(contents.as_bytes())?;
+        Ok(())
+    }
+}
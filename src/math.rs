@@ -0,0 +1,89 @@
+/// Number type and vectors used by physics and collision.
+/// Building with the `fixed-point` feature swaps `f32` for `Fixed` so the
+/// same inputs always give bit-identical positions and velocities.
+#[cfg(feature = "fixed-point")]
+use fixed::Fixed;
+use graphics::Point2;
+
+#[cfg(not(feature = "fixed-point"))]
+pub type Scalar = f32;
+#[cfg(feature = "fixed-point")]
+pub type Scalar = Fixed;
+
+/// Name of the kind of scalar, recorded in files that store scalars
+#[cfg(not(feature = "fixed-point"))]
+pub const SCALAR_KIND: &str = "float";
+#[cfg(feature = "fixed-point")]
+pub const SCALAR_KIND: &str = "fixed";
+
+#[cfg(not(feature = "fixed-point"))]
+pub const ZERO: Scalar = 0.0;
+#[cfg(feature = "fixed-point")]
+pub const ZERO: Scalar = Fixed::ZERO;
+
+#[cfg(not(feature = "fixed-point"))]
+pub fn scalar(value: f32) -> Scalar {
+    value
+}
+#[cfg(feature = "fixed-point")]
+pub fn scalar(value: f32) -> Scalar {
+    Fixed::from_f32(value)
+}
+
+#[cfg(not(feature = "fixed-point"))]
+pub fn to_f32(value: Scalar) -> f32 {
+    value
+}
+#[cfg(feature = "fixed-point")]
+pub fn to_f32(value: Scalar) -> f32 {
+    value.to_f32()
+}
+
+/// Bit pattern of a scalar, used for checksums
+#[cfg(not(feature = "fixed-point"))]
+pub fn to_bits(value: Scalar) -> u32 {
+    value.to_bits()
+}
+#[cfg(feature = "fixed-point")]
+pub fn to_bits(value: Scalar) -> u32 {
+    value.bits() as u32
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+pub struct Vec2 {
+    pub x: Scalar,
+    pub y: Scalar,
+}
+
+impl Vec2 {
+    pub fn new(x: Scalar, y: Scalar) -> Vec2 {
+        Vec2 { x, y }
+    }
+
+    pub fn from_f32(x: f32, y: f32) -> Vec2 {
+        Vec2::new(scalar(x), scalar(y))
+    }
+
+    /// Converts to a point for drawing
+    pub fn to_point(self) -> Point2 {
+        Point2::new(to_f32(self.x), to_f32(self.y))
+    }
+}
+
+// Neither kind of scalar has both min and max on its own
+
+pub fn min(a: Scalar, b: Scalar) -> Scalar {
+    if b < a {
+        b
+    } else {
+        a
+    }
+}
+
+pub fn max(a: Scalar, b: Scalar) -> Scalar {
+    if b > a {
+        b
+    } else {
+        a
+    }
+}
@@ -0,0 +1,453 @@
+use game::{Game, Loader};
+use ggez::GameResult;
+use player::Buttons;
+use snapshot::Snapshot;
+use std::collections::VecDeque;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+pub const INPUT_DELAY: u32 = 2; // Ticks between pressing a button and it taking effect
+pub const MAX_PREDICTION: u32 = 8; // Ticks run ahead of the peer's inputs before waiting for them
+const SNAPSHOTS: usize = MAX_PREDICTION as usize + 2;
+const CHECKSUMS: usize = 256; // Kept for longer than snapshots, as the peer's arrive late
+const MAX_PACKET: usize = 512;
+const MAX_INPUTS: usize = 255; // Inputs sent in one packet
+
+// Packet kinds
+const HELLO: u8 = 0;
+const INPUTS: u8 = 1;
+
+/// Settings for a networked game, from the command line:
+/// `--net <local address> <peer address> <player 1 or 2> [--latency <ms>] [--loss <fraction>]`.
+/// Latency and loss are added to outgoing packets to try out bad connections on localhost.
+pub struct NetConfig {
+    pub local: SocketAddr,
+    pub peer: SocketAddr,
+    pub player: usize,
+    pub latency: Duration,
+    pub loss: f32,
+}
+
+impl NetConfig {
+    pub fn from_args(args: &[String]) -> Result<Option<NetConfig>, String> {
+        let start = match args.iter().position(|arg| arg == "--net") {
+            Some(start) => start,
+            None => return Ok(None),
+        };
+        let usage = "Usage: --net <local address> <peer address> <player 1 or 2>";
+        let value = |i: usize| args.get(i).ok_or_else(|| usage.to_string());
+        let address = |text: &String| {
+            text.parse::<SocketAddr>()
+                .map_err(|e| format!("Invalid address {}: {}", text, e))
+        };
+        let mut config = NetConfig {
+            local: address(value(start + 1)?)?,
+            peer: address(value(start + 2)?)?,
+            player: match value(start + 3)?.as_str() {
+                "1" => 0,
+                "2" => 1,
+                other => return Err(format!("Player must be 1 or 2, got {}", other)),
+            },
+            latency: Duration::from_millis(0),
+            loss: 0.0,
+        };
+
+        let mut options = args[start + 4..].iter();
+        while let Some(option) = options.next() {
+            let mut value = || {
+                options
+                    .next()
+                    .ok_or_else(|| format!("Missing value after {}", option))
+            };
+            match option.as_str() {
+                "--latency" => {
+                    let ms = value()?;
+                    let ms = ms.parse::<u64>()
+                        .map_err(|e| format!("Invalid latency {}: {}", ms, e))?;
+                    config.latency = Duration::from_millis(ms);
+                }
+                "--loss" => {
+                    let loss = value()?;
+                    config.loss = loss.parse::<f32>()
+                        .map_err(|e| format!("Invalid loss {}: {}", loss, e))?;
+                }
+                _ => {}
+            }
+        }
+        Ok(Some(config))
+    }
+}
+
+// Holds back outgoing packets and drops some of them, to simulate a bad connection
+struct Shim {
+    latency: Duration,
+    loss: f32,
+    queue: VecDeque<(Instant, Vec<u8>)>,
+    seed: u32,
+}
+
+impl Shim {
+    // Xorshift, only decides which packets get lost
+    fn random(&mut self) -> f32 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        self.seed as f32 / u32::MAX as f32
+    }
+
+    fn send(&mut self, socket: &UdpSocket, peer: SocketAddr, packet: Vec<u8>) {
+        if self.loss > 0.0 && self.random() < self.loss {
+            return;
+        }
+        self.queue.push_back((Instant::now() + self.latency, packet));
+        self.flush(socket, peer);
+    }
+
+    // Sends the packets whose delay has passed
+    fn flush(&mut self, socket: &UdpSocket, peer: SocketAddr) {
+        let now = Instant::now();
+        while self.queue.front().is_some_and(|&(time, _)| time <= now) {
+            let (_, packet) = self.queue.pop_front().unwrap();
+            // Lost packets are expected, the inputs in them get sent again
+            let _ = socket.send_to(&packet, peer);
+        }
+    }
+}
+
+/// Peer-to-peer game with rollback. Both sides run the whole simulation and send each
+/// other the buttons of their own player for every tick. Until the peer's buttons for a
+/// tick arrive they are predicted to be the same as the last ones received. When a
+/// prediction turns out wrong, the world goes back to the snapshot taken before that
+/// tick and the ticks since are simulated again.
+pub struct Session {
+    socket: UdpSocket,
+    peer: SocketAddr,
+    shim: Shim,
+    pub local: usize, // Index of the player controlled on this side
+    pub started: bool, // Set once the peer has been heard from
+    pub frame: u32, // Next tick to simulate
+    pub rollbacks: u32, // Times a wrong prediction sent the world back
+    local_inputs: Vec<Buttons>, // For every tick so far, INPUT_DELAY ticks ahead of `frame`
+    remote_inputs: Vec<Buttons>, // Received from the peer, in order
+    used: Vec<Buttons>, // Peer buttons each tick was simulated with, maybe predicted
+    remote_ack: u32, // Number of our inputs the peer has
+    rollback: Option<u32>, // Earliest tick that was simulated with a wrong prediction
+    snapshots: Vec<Option<(u32, Snapshot)>>, // Tick and the world before it
+    checksums: Vec<Option<(u32, u64)>>,      // Tick and the checksum of the world before it
+    desynced: bool,
+}
+
+impl Session {
+    pub fn new(config: &NetConfig) -> io::Result<Session> {
+        let socket = UdpSocket::bind(config.local)?;
+        socket.set_nonblocking(true)?;
+        Ok(Session {
+            socket,
+            peer: config.peer,
+            shim: Shim {
+                latency: config.latency,
+                loss: config.loss,
+                queue: VecDeque::new(),
+                seed: 0x2545_f491,
+            },
+            local: config.player,
+            started: false,
+            frame: 0,
+            rollbacks: 0,
+            local_inputs: vec![Buttons::default(); INPUT_DELAY as usize],
+            remote_inputs: Vec::new(),
+            used: Vec::new(),
+            remote_ack: 0,
+            rollback: None,
+            snapshots: vec![None; SNAPSHOTS],
+            checksums: vec![None; CHECKSUMS],
+            desynced: false,
+        })
+    }
+
+    /// Reads every packet that has arrived from the peer
+    pub fn receive(&mut self) {
+        // Checksums can only be compared for ticks both sides have all the inputs of
+        let confirmed = self.confirmed();
+        let mut buffer = [0; MAX_PACKET];
+        loop {
+            let (size, from) = match self.socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    // E.g. the peer's port not being open yet, which is fine before starting
+                    if self.started {
+                        println!("Failed to receive from {}: {}", self.peer, e);
+                    }
+                    break;
+                }
+            };
+            if from != self.peer || size == 0 {
+                continue;
+            }
+            self.started = true;
+            if buffer[0] == INPUTS {
+                self.read_inputs(&buffer[..size], confirmed);
+            }
+        }
+        self.shim.flush(&self.socket, self.peer);
+    }
+
+    // Inputs packet: ack (u32), first tick (u32), count (u8), buttons, then the tick
+    // and checksum (u64) of a world both sides agree on
+    fn read_inputs(&mut self, packet: &[u8], confirmed: Option<u32>) {
+        if packet.len() < 10 {
+            return;
+        }
+        let ack = read_u32(&packet[1..]);
+        let first = read_u32(&packet[5..]);
+        let count = packet[9] as usize;
+        if packet.len() < 10 + count + 12 {
+            return;
+        }
+        self.remote_ack = self.remote_ack.max(ack);
+
+        for (i, &bits) in packet[10..10 + count].iter().enumerate() {
+            let frame = first + i as u32;
+            if frame as usize != self.remote_inputs.len() {
+                continue;
+            }
+            let buttons = Buttons(bits);
+            self.remote_inputs.push(buttons);
+            if self.used.get(frame as usize).is_some_and(|&used| used != buttons) {
+                self.rollback = Some(self.rollback.map_or(frame, |from| from.min(frame)));
+            }
+        }
+
+        let sync = &packet[10 + count..];
+        let (frame, checksum) = (read_u32(sync), read_u64(&sync[4..]));
+        if confirmed.is_some_and(|confirmed| frame <= confirmed) {
+            if let Some(ours) = self.checksum(frame) {
+                if ours != checksum && !self.desynced {
+                    println!(
+                        "Desync at tick {}: checksum {:016x} here, {:016x} on the peer",
+                        frame, ours, checksum
+                    );
+                    self.desynced = true;
+                }
+            }
+        }
+    }
+
+    // Latest tick whose snapshot was taken after simulating only confirmed inputs
+    fn confirmed(&self) -> Option<u32> {
+        if self.frame == 0 {
+            return None;
+        }
+        Some((self.remote_inputs.len() as u32).min(self.frame - 1))
+    }
+
+    fn checksum(&self, frame: u32) -> Option<u64> {
+        match self.checksums[frame as usize % CHECKSUMS] {
+            Some((stored, checksum)) if stored == frame => Some(checksum),
+            _ => None,
+        }
+    }
+
+    /// Tick to go back to, if a prediction was wrong
+    pub fn take_rollback(&mut self) -> Option<u32> {
+        let rollback = self.rollback.take();
+        if rollback.is_some() {
+            self.rollbacks += 1;
+        }
+        rollback
+    }
+
+    /// Whether the next tick can be simulated without getting too far ahead of the peer
+    pub fn can_advance(&self) -> bool {
+        self.started && self.frame < self.remote_inputs.len() as u32 + MAX_PREDICTION
+    }
+
+    /// Records the buttons held here, to take effect INPUT_DELAY ticks from now
+    pub fn push_local(&mut self, buttons: Buttons) {
+        self.local_inputs.push(buttons);
+    }
+
+    /// Keeps the world as it was before a tick, to roll back to
+    pub fn save(&mut self, frame: u32, snapshot: Snapshot, checksum: u64) {
+        self.snapshots[frame as usize % SNAPSHOTS] = Some((frame, snapshot));
+        self.checksums[frame as usize % CHECKSUMS] = Some((frame, checksum));
+    }
+
+    pub fn snapshot(&self, frame: u32) -> Option<&Snapshot> {
+        match self.snapshots[frame as usize % SNAPSHOTS] {
+            Some((stored, ref snapshot)) if stored == frame => Some(snapshot),
+            _ => None,
+        }
+    }
+
+    /// Buttons of each player for a tick, predicting the peer's if they haven't arrived yet
+    pub fn inputs(&mut self, frame: u32) -> Vec<Buttons> {
+        let remote = match self.remote_inputs.get(frame as usize) {
+            Some(&buttons) => buttons,
+            None => self.remote_inputs.last().cloned().unwrap_or_default(),
+        };
+        if (frame as usize) < self.used.len() {
+            self.used[frame as usize] = remote;
+        } else {
+            self.used.push(remote);
+        }
+
+        let mut inputs = vec![remote; 2];
+        inputs[self.local] = self.local_inputs[frame as usize];
+        inputs
+    }
+
+    /// Sends our inputs the peer doesn't have yet, or says hello until the peer answers
+    pub fn send(&mut self) {
+        let mut packet = Vec::with_capacity(MAX_PACKET);
+        if !self.started {
+            packet.push(HELLO);
+        } else {
+            let first = (self.remote_ack as usize).min(self.local_inputs.len());
+            let inputs = &self.local_inputs[first..];
+            let count = inputs.len().min(MAX_INPUTS);
+            packet.push(INPUTS);
+            write_u32(&mut packet, self.remote_inputs.len() as u32);
+            write_u32(&mut packet, first as u32);
+            packet.push(count as u8);
+            packet.extend(inputs[..count].iter().map(|buttons| buttons.0));
+
+            let sync = self.confirmed()
+                .and_then(|frame| self.checksum(frame).map(|checksum| (frame, checksum)))
+                .unwrap_or((u32::MAX, 0));
+            write_u32(&mut packet, sync.0);
+            write_u64(&mut packet, sync.1);
+        }
+        self.shim.send(&self.socket, self.peer, packet);
+    }
+}
+
+impl Game {
+    /// Advances a networked game by a tick if the peer isn't too far behind, first going
+    /// back to fix any ticks simulated with wrongly predicted buttons of the peer
+    pub fn step_netplay<L: Loader>(
+        &mut self,
+        loader: &mut L,
+        session: &mut Session,
+        local: Buttons,
+    ) -> GameResult<()> {
+        session.receive();
+        if let Some(from) = session.take_rollback() {
+            self.restore(loader, session.snapshot(from).unwrap());
+            for frame in from..session.frame {
+                self.advance_netplay(loader, session, frame)?;
+            }
+        }
+        if session.can_advance() {
+            session.push_local(local);
+            let frame = session.frame;
+            self.advance_netplay(loader, session, frame)?;
+            session.frame += 1;
+        }
+        session.send();
+        Ok(())
+    }
+
+    // Simulates a tick of a networked game, keeping the world from before it to roll back to
+    fn advance_netplay<L: Loader>(
+        &mut self,
+        loader: &mut L,
+        session: &mut Session,
+        frame: u32,
+    ) -> GameResult<()> {
+        session.save(frame, self.snapshot(), self.checksum());
+        let buttons = session.inputs(frame);
+        self.simulate(loader, &buttons)
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut array = [0; 4];
+    array.copy_from_slice(&bytes[..4]);
+    u32::from_le_bytes(array)
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut array = [0; 8];
+    array.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(array)
+}
+
+fn write_u32(packet: &mut Vec<u8>, value: u32) {
+    packet.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_u64(packet: &mut Vec<u8>, value: u64) {
+    packet.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use game::tests::{buttons, course, Scripts};
+    use std::thread;
+
+    const TICKS: u32 = 200; // Fewer than CHECKSUMS, so every tick can be compared
+
+    // Two sides of a game on localhost, each losing packets and sending them late
+    fn connect() -> (Session, Session) {
+        let config = |player| NetConfig {
+            local: "127.0.0.1:0".parse().unwrap(),
+            peer: "127.0.0.1:0".parse().unwrap(),
+            player,
+            latency: Duration::from_millis(30),
+            loss: 0.2,
+        };
+        let mut one = Session::new(&config(0)).unwrap();
+        let mut two = Session::new(&config(1)).unwrap();
+        one.peer = two.socket.local_addr().unwrap();
+        two.peer = one.socket.local_addr().unwrap();
+        (one, two)
+    }
+
+    // Buttons a player's tick is simulated with, after the input delay
+    fn delayed(player: usize, frame: u32) -> Buttons {
+        match frame.checked_sub(INPUT_DELAY) {
+            Some(pressed) => buttons(player, pressed),
+            None => Buttons::default(),
+        }
+    }
+
+    #[test]
+    fn rollback_keeps_both_sides_in_sync() {
+        let (mut one, mut two) = connect();
+        let (mut game_one, mut game_two) = (course(), course());
+        let started = Instant::now();
+        let done = |session: &Session| session.confirmed().is_some_and(|frame| frame >= TICKS);
+        while !done(&one) || !done(&two) {
+            assert!(
+                started.elapsed() < Duration::from_secs(30),
+                "stuck at ticks {} and {}",
+                one.frame,
+                two.frame
+            );
+            let (frame_one, frame_two) = (one.frame, two.frame);
+            game_one
+                .step_netplay(&mut Scripts, &mut one, buttons(0, frame_one))
+                .unwrap();
+            game_two
+                .step_netplay(&mut Scripts, &mut two, buttons(1, frame_two))
+                .unwrap();
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(one.rollbacks > 0 && two.rollbacks > 0);
+        assert!(!one.desynced && !two.desynced);
+
+        // Both sides end up where a game that knew every button all along is
+        let mut game = course();
+        for frame in 0..TICKS {
+            let checksum = Some(game.checksum());
+            let checksums = (one.checksum(frame), two.checksum(frame));
+            assert_eq!(checksums, (checksum, checksum), "tick {}", frame);
+            let held = [delayed(0, frame), delayed(1, frame)];
+            game.simulate(&mut Scripts, &held).unwrap();
+        }
+    }
+}
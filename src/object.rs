@@ -1,129 +1,329 @@
-/// Object underlying other entities (position, bounding box, physics)
-use ggez::graphics::Point2;
+/// Physics on the Transform, Velocity and Collider components, for the entities of the
+/// world and the players alike
+use checksum::Checksum;
+use ecs::{Collider, Transform, Velocity};
+use math::{scalar, Scalar, Vec2, ZERO};
 use sprite::EMPTY_SPRITE;
-use state::Map;
+use map::{LayerKind, Map, STAR_COIN};
+use physics::PhysicsProfile;
 use BBox;
 
-#[derive(PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
 pub enum Direction {
     Left,
     Right,
 }
 
-pub struct Transform {
-    x: f32,
-    y: f32,
-    width: f32,
-    height: f32,
+/// How much smaller than its sprite a collider is, from the top left and in size
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Inset {
+    x: Scalar,
+    y: Scalar,
+    width: Scalar,
+    height: Scalar,
 }
 
-impl Transform {
-    pub fn new(x: f32, y: f32, width: f32, height: f32) -> Transform {
-        Transform {
-            x,
-            y,
-            width,
-            height,
+impl Inset {
+    pub fn new(x: f32, y: f32, width: f32, height: f32) -> Inset {
+        Inset {
+            x: scalar(x),
+            y: scalar(y),
+            width: scalar(width),
+            height: scalar(height),
         }
     }
 }
 
+/// Something a collider can run into
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(tag = "kind", content = "index", rename_all = "snake_case")]
+pub enum Solid {
+    Tile(usize),    // Index of a tile on the main layer
+    Dynamic(usize), // Index into the dynamic solids passed to update
+}
+
+/// The body of a player: the same components entities are made of, moved by the same
+/// physics. Players keep theirs outside the world, as input, warps, the camera and
+/// scripts reach them by player index.
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Object {
-    pub pos: Point2,
-    pub bounds: Point2,
     pub transform: Transform,
-    pub velocity: Point2,
-    pub is_falling: bool,
-    pub is_jumping: bool,
+    pub velocity: Velocity,
+    pub collider: Collider,
     pub is_running: bool,
-    pub direction: Direction,
 }
 
-const DEFAULT_DIRECTION: Direction = Direction::Right;
-
 impl Object {
     pub fn new() -> Object {
         Object {
-            pos: Point2::new(0.0, 0.0),
-            bounds: Point2::new(0.0, 0.0),
-            transform: Transform::new(0.0, 0.0, 0.0, 0.0),
-            velocity: Point2::new(0.0, 0.0),
-            is_falling: false,
-            is_jumping: false,
+            transform: Transform::new(Vec2::default(), Direction::Right),
+            velocity: Velocity::default(),
+            collider: Collider::new(0.0),
             is_running: false,
-            direction: DEFAULT_DIRECTION,
         }
     }
 
-    // Tests if the player collided with any active, non-empty tiles
-    // If collision occured returns the bounding box of the tile collided with
-    pub fn collided(bbox: &BBox, map: &Map) -> Option<BBox> {
-        for (i, tile) in map.tiles.iter().enumerate() {
-            if tile.id == EMPTY_SPRITE {
-                continue;
-            }
+    pub fn get_bbox(&self) -> BBox {
+        self.collider.bbox(&self.transform)
+    }
 
-            let i = i as u32;
-            let x = (i % map.dimensions.0) * 32;
-            let y = (i / map.dimensions.0) * 32;
-            let other_bbox = BBox::new(x as f32, y as f32, 32.0, 32.0);
-            if bbox.intersects(&other_bbox) {
-                return Some(other_bbox);
-            }
+    pub fn ground_probe(&self) -> BBox {
+        self.collider.ground_probe(&self.transform)
+    }
+
+    pub fn fall(&mut self, profile: &PhysicsProfile) {
+        self.velocity.fall(profile);
+    }
+
+    pub fn update(&mut self, map: &Map, solids: &[BBox]) {
+        update(
+            &mut self.transform,
+            &mut self.velocity,
+            &mut self.collider,
+            map,
+            solids,
+        );
+    }
+
+    pub fn carry(&mut self, delta: Vec2, map: &Map, solids: &[BBox]) {
+        carry(&mut self.transform, &self.collider, delta, map, solids);
+    }
+
+    pub fn checksum(&self, sum: &mut Checksum) {
+        self.transform.checksum(sum);
+        self.velocity.checksum(sum);
+        self.collider.checksum(sum);
+        sum.write_bool(self.is_running);
+    }
+}
+
+// Indices and bounding boxes of the active, non-empty tiles on the main layer overlapping
+// a bounding box. Star coins are picked up rather than collided with.
+fn solid_tiles(bbox: &BBox, map: &Map) -> Vec<(usize, BBox)> {
+    let tiles = &map.layer(LayerKind::Main).tiles;
+    map.tiles_overlapping(bbox)
+        .into_iter()
+        .filter(|&(index, _)| {
+            let tile = &tiles[index];
+            tile.active && tile.id != EMPTY_SPRITE && tile.id != STAR_COIN
+        })
+        .collect()
+}
+
+// Tiles and dynamic solids overlapping a bounding box
+fn obstacles(bbox: &BBox, map: &Map, solids: &[BBox]) -> Vec<(Solid, BBox)> {
+    let mut obstacles: Vec<(Solid, BBox)> = solid_tiles(bbox, map)
+        .into_iter()
+        .map(|(index, tile)| (Solid::Tile(index), tile))
+        .collect();
+    for (i, solid) in solids.iter().enumerate() {
+        if bbox.intersects(solid) {
+            obstacles.push((Solid::Dynamic(i), *solid));
         }
-        None
     }
+    obstacles
+}
 
-    // Get bounding box for object
-    pub fn get_bbox(&self) -> BBox {
-        let mut bbox = BBox {
-            pos: self.pos,
-            size: self.bounds,
-        };
-        self.apply_transform(bbox)
-    }
-
-    // Apply transformation to a BBox (used to make BBox smaller / larger than sprite)
-    fn apply_transform(&self, mut bbox: BBox) -> BBox {
-        bbox.pos.x += self.transform.x;
-        bbox.size.x -= self.transform.width;
-        bbox.pos.y += self.transform.y;
-        bbox.size.y -= self.transform.height;
-        bbox
-    }
-
-    pub fn update(&mut self, map: &Map) {
-        // Apply velocity and test for collision and correct accordingly
-        self.pos.y -= self.velocity.y;
-        let bbox = self.get_bbox();
-        self.handle_collision_y(&bbox, map);
-        self.pos.x += self.velocity.x;
-        let bbox = self.get_bbox();
-        self.handle_collision_x(&bbox, map);
-    }
-
-    fn handle_collision_x(&mut self, bbox: &BBox, map: &Map) {
-        if let Some(collision) = Object::collided(bbox, map) {
-            if self.velocity.x < 0.0 {
-                self.pos.x = collision.pos.x + collision.size.x - 3.0;
-            } else {
-                self.pos.x = collision.pos.x - self.bounds.x + 3.0;
-            }
-            self.velocity.x = 0.0;
+impl Collider {
+    /// Box collided with, with the collider at the position of the transform
+    pub fn bbox(&self, transform: &Transform) -> BBox {
+        let inset = &self.inset;
+        BBox {
+            pos: Vec2::new(transform.pos.x + inset.x, transform.pos.y + inset.y),
+            size: Vec2::new(self.size.x - inset.width, self.size.y - inset.height),
         }
     }
 
-    fn handle_collision_y(&mut self, bbox: &BBox, map: &Map) {
-        if let Some(collision) = Object::collided(bbox, map) {
-            if self.is_falling {
-                self.pos.y = collision.pos.y - self.bounds.y - 1.0;
-                self.is_falling = false;
-            } else if self.is_jumping {
-                self.pos.y = collision.pos.y + collision.size.y + 1.0;
+    /// Box a pixel below the bounding box, checked for something to stand on
+    pub fn ground_probe(&self, transform: &Transform) -> BBox {
+        let mut probe = self.bbox(transform);
+        probe.pos.y += scalar(1.0);
+        probe
+    }
+}
+
+impl Velocity {
+    /// Pulls down. Rising slows until it turns into a fall, and falls are capped at the
+    /// maximum speed.
+    pub fn fall(&mut self, profile: &PhysicsProfile) {
+        let gravity = scalar(profile.gravity);
+        let max_fall_speed = scalar(profile.max_fall_speed);
+        if self.linear.y > ZERO {
+            self.linear.y -= gravity;
+            if self.linear.y < ZERO {
                 self.is_jumping = false;
                 self.is_falling = true;
             }
-            self.velocity.y = 0.0;
+        }
+
+        if self.is_falling {
+            self.linear.y -= gravity;
+            if self.linear.y <= -max_fall_speed {
+                self.linear.y = -max_fall_speed;
+            }
+        }
+    }
+}
+
+/// Moves a body by its velocity, one axis at a time. Each move is swept against the
+/// tiles and the dynamic solids (e.g. platforms) so the body stops exactly at the first
+/// one in its path, however fast it moves, and the sides touched are recorded as
+/// contacts of the collider.
+pub fn update(
+    transform: &mut Transform,
+    velocity: &mut Velocity,
+    collider: &mut Collider,
+    map: &Map,
+    solids: &[BBox],
+) {
+    collider.grounded = false;
+    collider.standing_on = None;
+    collider.hit_ceiling = false;
+    collider.ceiling_tile = None;
+    collider.hit_wall_left = false;
+    collider.hit_wall_right = false;
+
+    let dy = -velocity.linear.y;
+    if let Some((travel, solid)) = sweep(transform, collider, dy, false, map, solids) {
+        transform.pos.y += travel;
+        if dy > ZERO {
+            collider.grounded = true;
+            collider.standing_on = Some(solid);
+            velocity.is_falling = false;
+        } else {
+            collider.hit_ceiling = true;
+            if let Solid::Tile(index) = solid {
+                collider.ceiling_tile = Some(index);
+            }
+            velocity.is_jumping = false;
+            velocity.is_falling = true;
+        }
+        velocity.linear.y = ZERO;
+    } else {
+        transform.pos.y += dy;
+    }
+
+    let dx = velocity.linear.x;
+    if let Some((travel, _)) = sweep(transform, collider, dx, true, map, solids) {
+        transform.pos.x += travel;
+        if dx > ZERO {
+            collider.hit_wall_right = true;
+        } else {
+            collider.hit_wall_left = true;
+        }
+        velocity.linear.x = ZERO;
+    } else {
+        transform.pos.x += dx;
+    }
+
+    // Standing still on something is not found by the sweep, so look just below the feet
+    if !collider.grounded && velocity.linear.y <= ZERO {
+        collider.standing_on = obstacles(&collider.ground_probe(transform), map, solids)
+            .first()
+            .map(|&(solid, _)| solid);
+        collider.grounded = collider.standing_on.is_some();
+    }
+}
+
+/// Moves a body along with what it stands on, stopping at any solid in the way
+pub fn carry(
+    transform: &mut Transform,
+    collider: &Collider,
+    delta: Vec2,
+    map: &Map,
+    solids: &[BBox],
+) {
+    let travel = |transform: &Transform, d: Scalar, horizontal: bool| {
+        sweep(transform, collider, d, horizontal, map, solids).map_or(d, |(travel, _)| travel)
+    };
+    transform.pos.y += travel(transform, delta.y, false);
+    transform.pos.x += travel(transform, delta.x, true);
+}
+
+// Distance a body can move along an axis before touching a solid, if one is in the way
+fn sweep(
+    transform: &Transform,
+    collider: &Collider,
+    delta: Scalar,
+    horizontal: bool,
+    map: &Map,
+    solids: &[BBox],
+) -> Option<(Scalar, Solid)> {
+    if delta == ZERO {
+        return None;
+    }
+    let bbox = collider.bbox(transform);
+    let mut swept = bbox;
+    // Coordinate along the axis of the box's position and size
+    let axis = |v: Vec2| if horizontal { v.x } else { v.y };
+    if horizontal {
+        swept.size.x += delta.abs();
+        if delta < ZERO {
+            swept.pos.x += delta;
+        }
+    } else {
+        swept.size.y += delta.abs();
+        if delta < ZERO {
+            swept.pos.y += delta;
+        }
+    }
+
+    let mut hit = None;
+    for (solid, other) in obstacles(&swept, map, solids) {
+        // Solids the body already overlaps are ignored so it can move out of them
+        let gap = if delta > ZERO {
+            axis(other.pos) - (axis(bbox.pos) + axis(bbox.size))
+        } else {
+            axis(bbox.pos) - (axis(other.pos) + axis(other.size))
+        };
+        let nearer = match hit {
+            Some((nearest, _)) => gap < nearest,
+            None => true,
+        };
+        if gap >= ZERO && nearer {
+            hit = Some((gap, solid));
+        }
+    }
+    hit.map(|(gap, solid)| if delta > ZERO { (gap, solid) } else { (-gap, solid) })
+}
+
+impl Transform {
+    pub fn checksum(&self, sum: &mut Checksum) {
+        sum.write_vec2(self.pos);
+        sum.write_bool(self.direction == Direction::Left);
+    }
+}
+
+impl Velocity {
+    pub fn checksum(&self, sum: &mut Checksum) {
+        sum.write_vec2(self.linear);
+        sum.write_bool(self.is_falling);
+        sum.write_bool(self.is_jumping);
+    }
+}
+
+impl Collider {
+    pub fn checksum(&self, sum: &mut Checksum) {
+        sum.write_bool(self.grounded);
+        write_contact(sum, self.standing_on);
+        sum.write_bool(self.hit_ceiling);
+        sum.write_u32(self.ceiling_tile.map_or(u32::MAX, |index| index as u32));
+        sum.write_bool(self.hit_wall_left);
+        sum.write_bool(self.hit_wall_right);
+    }
+}
+
+// What a body stands on carries it along, so it goes into the checksum
+fn write_contact(sum: &mut Checksum, contact: Option<Solid>) {
+    match contact {
+        None => sum.write_u8(0),
+        Some(Solid::Tile(index)) => {
+            sum.write_u8(1);
+            sum.write_u32(index as u32);
+        }
+        Some(Solid::Dynamic(index)) => {
+            sum.write_u8(2);
+            sum.write_u32(index as u32);
         }
     }
 }
@@ -0,0 +1,84 @@
+use bbox::BBox;
+use checksum::Checksum;
+
+/// What is inside an area of the map, to tell when things come in and go out.
+/// Shared by script zones and triggers.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct Occupancy {
+    inside: Vec<usize>, // Keys of what was in the area after the last update
+}
+
+/// What came into an area and what left it during an update
+#[derive(Default, PartialEq, Debug)]
+pub struct Changes {
+    pub entered: Vec<usize>,
+    pub left: Vec<usize>,
+}
+
+impl Occupancy {
+    /// Finds which of the candidates, each a key with a bounding box, are in the area
+    /// now. Candidates missing since the last update count as having left.
+    pub fn update(&mut self, area: &BBox, candidates: &[(usize, BBox)]) -> Changes {
+        let inside: Vec<usize> = candidates
+            .iter()
+            .filter(|(_, bbox)| area.intersects(bbox))
+            .map(|&(key, _)| key)
+            .collect();
+        let changes = Changes {
+            entered: inside
+                .iter()
+                .cloned()
+                .filter(|key| !self.inside.contains(key))
+                .collect(),
+            left: self
+                .inside
+                .iter()
+                .cloned()
+                .filter(|key| !inside.contains(key))
+                .collect(),
+        };
+        self.inside = inside;
+        changes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inside.is_empty()
+    }
+
+    pub fn checksum(&self, sum: &mut Checksum) {
+        sum.write_u32(self.inside.len() as u32);
+        for &key in &self.inside {
+            sum.write_u32(key as u32);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::scalar;
+
+    fn bbox(x: f32) -> BBox {
+        BBox::new(scalar(x), scalar(0.0), scalar(16.0), scalar(16.0))
+    }
+
+    #[test]
+    fn tells_who_came_in_and_who_left() {
+        let area = bbox(0.0);
+        let mut occupancy = Occupancy::default();
+
+        let changes = occupancy.update(&area, &[(0, bbox(8.0)), (1, bbox(64.0))]);
+        assert_eq!(changes.entered, vec![0]);
+        assert!(changes.left.is_empty());
+
+        let changes = occupancy.update(&area, &[(0, bbox(4.0)), (1, bbox(8.0))]);
+        assert_eq!(changes.entered, vec![1]);
+        assert!(changes.left.is_empty());
+
+        // Gone from the candidates, e.g. dead, is the same as out of the area
+        let changes = occupancy.update(&area, &[(1, bbox(64.0))]);
+        assert!(changes.entered.is_empty());
+        assert_eq!(changes.left, vec![0, 1]);
+        assert!(occupancy.is_empty());
+    }
+}
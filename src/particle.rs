@@ -0,0 +1,221 @@
+use camera::Camera;
+use events::GameEvent;
+use ggez::graphics::spritebatch::SpriteBatch;
+use ggez::graphics::{self, Color, FilterMode, Point2};
+use ggez::{Context, GameError, GameResult};
+use map::{Map, TILE_SIZE};
+use sprite::{self, Atlas};
+use std::io::Read;
+use toml;
+
+pub const PARTICLES_PATH: &str = "/particles.toml";
+pub const PARTICLE_ATLAS_PATH: &str = "/particles.atlas.toml";
+const MAX_PARTICLES: usize = 512;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Effect {
+    BrickDebris,
+    SkidDust,
+    LandingPuff,
+    CoinSparkle,
+}
+
+/// How the particles of an effect are spawned and move
+#[derive(Deserialize)]
+struct Emitter {
+    frames: Vec<String>,
+    count: usize,
+    lifetime: u32,
+    velocity_min: (f32, f32),
+    velocity_max: (f32, f32),
+    gravity: f32,
+    fade: bool,
+    // Atlas indices of the frames, filled in on load
+    #[serde(skip)]
+    frame_indices: Vec<usize>,
+}
+
+#[derive(Deserialize)]
+struct Emitters {
+    brick_debris: Emitter,
+    skid_dust: Emitter,
+    landing_puff: Emitter,
+    coin_sparkle: Emitter,
+}
+
+impl Emitters {
+    fn get(&self, effect: Effect) -> &Emitter {
+        match effect {
+            Effect::BrickDebris => &self.brick_debris,
+            Effect::SkidDust => &self.skid_dust,
+            Effect::LandingPuff => &self.landing_puff,
+            Effect::CoinSparkle => &self.coin_sparkle,
+        }
+    }
+
+    fn get_mut(&mut self, effect: Effect) -> &mut Emitter {
+        match effect {
+            Effect::BrickDebris => &mut self.brick_debris,
+            Effect::SkidDust => &mut self.skid_dust,
+            Effect::LandingPuff => &mut self.landing_puff,
+            Effect::CoinSparkle => &mut self.coin_sparkle,
+        }
+    }
+}
+
+const EFFECTS: [Effect; 4] = [
+    Effect::BrickDebris,
+    Effect::SkidDust,
+    Effect::LandingPuff,
+    Effect::CoinSparkle,
+];
+
+#[derive(Clone, Copy)]
+struct Particle {
+    effect: Effect,
+    pos: Point2,
+    velocity: Point2,
+    age: u32,
+}
+
+/// Short lived sprites that are only for show and don't affect the game.
+/// Particles live in a ring buffer allocated up front. New particles go in the
+/// slot after the last one written, which holds the oldest particle, if it is
+/// still alive, once the buffer has wrapped around.
+pub struct Particles {
+    atlas: Atlas,
+    emitters: Emitters,
+    particles: Vec<Particle>, // Dead particles stay until their slot is written over
+    head: usize,              // Slot the next particle goes in
+    batch: SpriteBatch,
+    seed: u32,
+}
+
+impl Particles {
+    pub fn load(ctx: &mut Context) -> GameResult<Particles> {
+        let atlas = Atlas::load(ctx, PARTICLE_ATLAS_PATH)?;
+        let mut file = ctx.filesystem.open(PARTICLES_PATH)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let mut emitters: Emitters = toml::from_str(&contents)?;
+
+        for effect in EFFECTS.iter() {
+            let emitter = emitters.get_mut(*effect);
+            if emitter.frames.is_empty() || emitter.lifetime == 0 {
+                return Err(GameError::ResourceLoadError(format!(
+                    "Emitter for {:?} in {} needs at least one frame and a lifetime",
+                    effect, PARTICLES_PATH
+                )));
+            }
+            for name in &emitter.frames {
+                match atlas.index(name) {
+                    Some(index) => emitter.frame_indices.push(index),
+                    None => {
+                        return Err(GameError::ResourceLoadError(format!(
+                            "Emitter for {:?} in {} uses unknown frame {}",
+                            effect, PARTICLES_PATH, name
+                        )))
+                    }
+                }
+            }
+        }
+
+        let mut batch = SpriteBatch::new(atlas.image.clone());
+        batch.set_filter(FilterMode::Nearest);
+        Ok(Particles {
+            atlas,
+            emitters,
+            particles: Vec::with_capacity(MAX_PARTICLES),
+            head: 0,
+            batch,
+            seed: 0x2545_f491,
+        })
+    }
+
+    // Xorshift, good enough for scattering particles
+    fn random(&mut self, min: f32, max: f32) -> f32 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        min + (max - min) * (self.seed as f32 / u32::MAX as f32)
+    }
+
+    /// Spawns the particles of an effect at a position in the world
+    fn emit(&mut self, effect: Effect, pos: Point2) {
+        let (count, min, max) = {
+            let emitter = self.emitters.get(effect);
+            (emitter.count, emitter.velocity_min, emitter.velocity_max)
+        };
+        for _ in 0..count {
+            let velocity = Point2::new(self.random(min.0, max.0), self.random(min.1, max.1));
+            let particle = Particle {
+                effect,
+                pos,
+                velocity,
+                age: 0,
+            };
+            if self.particles.len() < MAX_PARTICLES {
+                self.particles.push(particle);
+            } else {
+                self.particles[self.head] = particle;
+            }
+            self.head = (self.head + 1) % MAX_PARTICLES;
+        }
+    }
+
+    /// Spawns the effect for an event, if it has one
+    pub fn handle_event(&mut self, event: GameEvent, map: &Map) {
+        match event {
+            GameEvent::BrickBroken { tile } => {
+                self.emit(Effect::BrickDebris, map.tile_center(tile));
+            }
+            GameEvent::CoinCollected { tile, .. } => {
+                // The coin pops out of the top of the block
+                let mut pos = map.tile_center(tile);
+                pos.y -= TILE_SIZE;
+                self.emit(Effect::CoinSparkle, pos);
+            }
+            GameEvent::StarCoinCollected { tile, .. } => {
+                self.emit(Effect::CoinSparkle, map.tile_center(tile));
+            }
+            GameEvent::Landed { pos } => self.emit(Effect::LandingPuff, pos.to_point()),
+            GameEvent::Skidded { pos } => self.emit(Effect::SkidDust, pos.to_point()),
+            _ => {}
+        }
+    }
+
+    pub fn update(&mut self) {
+        let emitters = &self.emitters;
+        for particle in &mut self.particles {
+            let emitter = emitters.get(particle.effect);
+            if particle.age >= emitter.lifetime {
+                continue;
+            }
+            particle.velocity.y += emitter.gravity;
+            particle.pos.x += particle.velocity.x;
+            particle.pos.y += particle.velocity.y;
+            particle.age += 1;
+        }
+    }
+
+    pub fn draw(&mut self, ctx: &mut Context, camera: &Camera) -> GameResult<()> {
+        self.batch.clear();
+        for particle in &self.particles {
+            let emitter = self.emitters.get(particle.effect);
+            if particle.age >= emitter.lifetime {
+                continue;
+            }
+            let progress = particle.age as f32 / emitter.lifetime as f32;
+            let frame = emitter.frame_indices
+                [(progress * emitter.frame_indices.len() as f32) as usize];
+            let frame = self.atlas.frame(frame).unwrap();
+            let dest = camera.to_screen(particle.pos);
+            let mut param = sprite::create_sprite_param(frame, dest, &self.atlas.image);
+            if emitter.fade {
+                param.color = Some(Color::new(1.0, 1.0, 1.0, 1.0 - progress));
+            }
+            self.batch.add(param);
+        }
+        graphics::draw_ex(ctx, &self.batch, graphics::DrawParam::default())
+    }
+}
@@ -0,0 +1,166 @@
+use ggez::{Context, GameResult};
+use math::{scalar, Scalar};
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use toml;
+
+pub const PHYSICS_PATH: &str = "/physics.toml";
+const RELOAD_INTERVAL: u32 = 30; // Ticks between checks for changes to the profile
+
+/// Values controlling how the player moves. Velocities are in pixels per tick.
+#[derive(Deserialize, Clone, Debug)]
+pub struct PhysicsProfile {
+    pub acceleration: f32,
+    pub friction: f32,
+    pub gravity: f32,
+    pub max_fall_speed: f32,
+    pub walk_speed: f32,
+    pub run_modifier: f32, // Speed modifier when running
+    pub skid_speed: f32,   // Turning around faster than this skids
+    pub jump_impulse: f32,
+    pub jump_speed_bonus: f32, // Extra impulse when jumping at walk speed
+    pub coyote_time: u32,      // Ticks after leaving the ground in which a jump is still allowed
+    pub jump_buffer: u32,      // Ticks before landing in which a jump press is remembered
+}
+
+/// Parts of the physics profile overridden by a level
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct PhysicsOverrides {
+    pub acceleration: Option<f32>,
+    pub friction: Option<f32>,
+    pub gravity: Option<f32>,
+    pub max_fall_speed: Option<f32>,
+    pub walk_speed: Option<f32>,
+    pub run_modifier: Option<f32>,
+    pub skid_speed: Option<f32>,
+    pub jump_impulse: Option<f32>,
+    pub jump_speed_bonus: Option<f32>,
+    pub coyote_time: Option<u32>,
+    pub jump_buffer: Option<u32>,
+}
+
+impl PhysicsProfile {
+    pub fn with_overrides(&self, overrides: &PhysicsOverrides) -> PhysicsProfile {
+        PhysicsProfile {
+            acceleration: overrides.acceleration.unwrap_or(self.acceleration),
+            friction: overrides.friction.unwrap_or(self.friction),
+            gravity: overrides.gravity.unwrap_or(self.gravity),
+            max_fall_speed: overrides.max_fall_speed.unwrap_or(self.max_fall_speed),
+            walk_speed: overrides.walk_speed.unwrap_or(self.walk_speed),
+            run_modifier: overrides.run_modifier.unwrap_or(self.run_modifier),
+            skid_speed: overrides.skid_speed.unwrap_or(self.skid_speed),
+            jump_impulse: overrides.jump_impulse.unwrap_or(self.jump_impulse),
+            jump_speed_bonus: overrides.jump_speed_bonus.unwrap_or(self.jump_speed_bonus),
+            coyote_time: overrides.coyote_time.unwrap_or(self.coyote_time),
+            jump_buffer: overrides.jump_buffer.unwrap_or(self.jump_buffer),
+        }
+    }
+
+    pub fn max_speed(&self, running: bool) -> Scalar {
+        if running {
+            scalar(self.walk_speed * self.run_modifier)
+        } else {
+            scalar(self.walk_speed)
+        }
+    }
+
+    /// Vertical velocity given by a jump, higher when running
+    pub fn jump_velocity(&self, speed_x: Scalar) -> Scalar {
+        scalar(self.jump_impulse)
+            + scalar(self.jump_speed_bonus) * speed_x.abs() / scalar(self.walk_speed)
+    }
+}
+
+/// Physics profile loaded from disk, reloaded while the game runs when the file changes
+pub struct PhysicsConfig {
+    pub profile: PhysicsProfile,
+    file: Option<PathBuf>,
+    modified: Option<SystemTime>,
+    ticks: u32,
+}
+
+impl PhysicsConfig {
+    /// Profile that is never reloaded, as there is no file behind it
+    #[cfg(test)]
+    pub fn new(profile: PhysicsProfile) -> PhysicsConfig {
+        PhysicsConfig {
+            profile,
+            file: None,
+            modified: None,
+            ticks: 0,
+        }
+    }
+
+    pub fn load(ctx: &mut Context) -> GameResult<PhysicsConfig> {
+        let mut file = ctx.filesystem.open(PHYSICS_PATH)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let profile = toml::from_str(&contents)?;
+
+        let file = find_file(ctx, PHYSICS_PATH);
+        let modified = file.as_ref().and_then(|f| modified_time(f));
+        Ok(PhysicsConfig {
+            profile,
+            file,
+            modified,
+            ticks: 0,
+        })
+    }
+
+    /// Checks the profile for changes every so often and reloads it.
+    /// A profile that fails to parse is reported and the old one kept.
+    pub fn update(&mut self) {
+        self.ticks += 1;
+        if self.ticks < RELOAD_INTERVAL {
+            return;
+        }
+        self.ticks = 0;
+
+        let file = match self.file {
+            Some(ref file) => file,
+            None => return,
+        };
+        let modified = modified_time(file);
+        if modified == self.modified {
+            return;
+        }
+        self.modified = modified;
+
+        let profile = fs::read_to_string(file)
+            .map_err(|e| e.to_string())
+            .and_then(|contents| toml::from_str(&contents).map_err(|e| e.to_string()));
+        match profile {
+            Ok(profile) => {
+                println!("Reloaded physics profile {:?}", file);
+                self.profile = profile;
+            }
+            Err(e) => println!("Failed to reload physics profile {:?}: {}", file, e),
+        }
+    }
+}
+
+fn modified_time(file: &Path) -> Option<SystemTime> {
+    fs::metadata(file).and_then(|m| m.modified()).ok()
+}
+
+/// Finds the file on disk that the virtual filesystem opens for a path,
+/// searching the directories in the same order it does
+fn find_file(ctx: &Context, path: &str) -> Option<PathBuf> {
+    let relative = path.trim_start_matches('/');
+    let mut dirs = vec![
+        ctx.filesystem.get_resources_dir().to_path_buf(),
+        ctx.filesystem.get_user_data_dir().to_path_buf(),
+        ctx.filesystem.get_user_config_dir().to_path_buf(),
+    ];
+    if let Ok(manifest_dir) = env::var("CARGO_MANIFEST_DIR") {
+        let mut dir = PathBuf::from(manifest_dir);
+        dir.push("resources");
+        dirs.push(dir);
+    }
+    dirs.into_iter()
+        .map(|dir| dir.join(relative))
+        .find(|file| file.is_file())
+}
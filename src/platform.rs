@@ -0,0 +1,170 @@
+use bbox::BBox;
+use checksum::Checksum;
+use map::TILE_SIZE;
+use math::{scalar, to_f32, Scalar, Vec2, ZERO};
+use physics::PhysicsProfile;
+
+pub const PLATFORM_TILE: usize = 27;
+pub const FALL_DELAY: u32 = 30; // Ticks a falling platform holds before dropping
+pub const PATH_SPEED: f32 = 1.0;
+pub const OSCILLATE_PERIOD: u32 = 240;
+
+/// How a platform moves. Positions are in world pixels.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Motion {
+    // Travels from its start through every waypoint and back, at `speed` pixels per tick
+    Path {
+        speed: f32,
+        waypoints: Vec<(f32, f32)>,
+    },
+    // Eases back and forth between its start and start + offset
+    Oscillate { offset: (f32, f32), period: u32 },
+    // Drops `delay` ticks after first being stood on
+    Falling { delay: u32 },
+}
+
+/// A platform as placed in the editor
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PlatformDef {
+    pub pos: (f32, f32),
+    pub width: u32, // In tiles
+    #[serde(default = "default_tile")]
+    pub tile: usize,
+    pub motion: Motion,
+}
+
+fn default_tile() -> usize {
+    PLATFORM_TILE
+}
+
+impl PlatformDef {
+    pub fn new(pos: (f32, f32), motion: Motion) -> PlatformDef {
+        PlatformDef {
+            pos,
+            width: 3,
+            tile: PLATFORM_TILE,
+            motion,
+        }
+    }
+}
+
+/// A solid that moves, carrying whatever stands on it
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Platform {
+    pub bbox: BBox,
+    pub delta: Vec2, // Distance moved during the last update
+    pub width: u32,
+    pub tile: usize,
+    origin: Vec2,
+    motion: Motion,
+    waypoint: usize, // Next point on the path, 0 is the origin
+    ticks: u32,
+    triggered: bool, // Falling platforms start counting down once stood on
+    fall_speed: Scalar,
+}
+
+impl Platform {
+    pub fn new(def: &PlatformDef) -> Platform {
+        let origin = Vec2::from_f32(def.pos.0, def.pos.1);
+        let size = Vec2::from_f32(def.width as f32 * TILE_SIZE, TILE_SIZE);
+        Platform {
+            bbox: BBox { pos: origin, size },
+            delta: Vec2::default(),
+            width: def.width,
+            tile: def.tile,
+            origin,
+            motion: def.motion.clone(),
+            waypoint: 1,
+            ticks: 0,
+            triggered: false,
+            fall_speed: ZERO,
+        }
+    }
+
+    /// Moves the platform by one tick. `ridden` is whether anything stood on it last tick.
+    pub fn update(&mut self, ridden: bool, profile: &PhysicsProfile) {
+        let previous = self.bbox.pos;
+        self.ticks += 1;
+        let pos = match self.motion {
+            Motion::Path {
+                speed,
+                ref waypoints,
+            } => follow_path(
+                self.bbox.pos,
+                self.origin,
+                &mut self.waypoint,
+                scalar(speed),
+                waypoints,
+            ),
+            Motion::Oscillate { offset, period } => {
+                // Smoothstep over a triangle wave, so it eases in and out at both ends
+                let period = period.max(2);
+                let phase = self.ticks % period;
+                let half = period / 2;
+                let t = scalar((if phase < half { phase } else { period - phase }) as f32)
+                    / scalar(half as f32);
+                let eased = t * t * (scalar(3.0) - scalar(2.0) * t);
+                Vec2::new(
+                    self.origin.x + scalar(offset.0) * eased,
+                    self.origin.y + scalar(offset.1) * eased,
+                )
+            }
+            Motion::Falling { delay } => {
+                if ridden && !self.triggered {
+                    self.triggered = true;
+                    self.ticks = 0;
+                }
+                let mut pos = self.bbox.pos;
+                if self.triggered && self.ticks > delay {
+                    self.fall_speed += scalar(profile.gravity);
+                    let max_fall_speed = scalar(profile.max_fall_speed);
+                    if self.fall_speed > max_fall_speed {
+                        self.fall_speed = max_fall_speed;
+                    }
+                    pos.y += self.fall_speed;
+                }
+                pos
+            }
+        };
+        self.bbox.pos = pos;
+        self.delta = Vec2::new(pos.x - previous.x, pos.y - previous.y);
+    }
+
+    pub fn checksum(&self, sum: &mut Checksum) {
+        sum.write_vec2(self.bbox.pos);
+        sum.write_vec2(self.delta);
+        sum.write_u32(self.waypoint as u32);
+        sum.write_u32(self.ticks);
+        sum.write_bool(self.triggered);
+        sum.write_scalar(self.fall_speed);
+    }
+}
+
+// Moves from `pos` towards the current waypoint, going on to the next one once reached
+fn follow_path(
+    pos: Vec2,
+    origin: Vec2,
+    waypoint: &mut usize,
+    speed: Scalar,
+    waypoints: &[(f32, f32)],
+) -> Vec2 {
+    if waypoints.is_empty() {
+        return pos;
+    }
+    let target = if *waypoint == 0 {
+        origin
+    } else {
+        let (x, y) = waypoints[*waypoint - 1];
+        Vec2::from_f32(x, y)
+    };
+
+    let (dx, dy) = (target.x - pos.x, target.y - pos.y);
+    let (fx, fy) = (to_f32(dx), to_f32(dy));
+    let distance = scalar((fx * fx + fy * fy).sqrt());
+    if distance <= speed {
+        *waypoint = (*waypoint + 1) % (waypoints.len() + 1);
+        return target;
+    }
+    Vec2::new(pos.x + dx * speed / distance, pos.y + dy * speed / distance)
+}
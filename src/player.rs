@@ -1,131 +1,529 @@
-use BBox;
 use Object;
 use object;
+use object::Direction;
+use animation::{AnimationSet, AnimationState, Animator};
+use bbox::BBox;
 use ggez::GameResult;
-use graphics::Point2;
-use sprite::EMPTY_SPRITE;
-use state::Map;
+use checksum::Checksum;
+use events::{EventQueue, GameEvent};
+use map::{Map, TILE_SIZE};
+use math::{scalar, to_f32, Vec2, ZERO};
+use physics::PhysicsProfile;
 
+/// Buttons a player holds during a tick. This is all the simulation needs from
+/// the keyboard, and what gets sent to the other side in a networked game.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+pub struct Buttons(pub u8);
+
+impl Buttons {
+    pub const LEFT: u8 = 1;
+    pub const RIGHT: u8 = 1 << 1;
+    pub const UP: u8 = 1 << 2;
+    pub const DOWN: u8 = 1 << 3;
+    pub const JUMP: u8 = 1 << 4;
+    pub const RUN: u8 = 1 << 5;
+    pub const RESTART: u8 = 1 << 6;
+
+    pub fn held(self, button: u8) -> bool {
+        self.0 & button != 0
+    }
+
+    /// Whether a button went down since the previous tick
+    pub fn pressed(self, previous: Buttons, button: u8) -> bool {
+        self.held(button) && !previous.held(button)
+    }
+
+    /// Whether a button went up since the previous tick
+    pub fn released(self, previous: Buttons, button: u8) -> bool {
+        !self.held(button) && previous.held(button)
+    }
+
+    pub fn set(&mut self, button: u8, held: bool) {
+        if held {
+            self.0 |= button;
+        } else {
+            self.0 &= !button;
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Player {
+    pub index: usize, // Player one is 0
     pub obj: Object,
-    pub sprite_id: usize,
+    pub animator: Animator,
     pub moving_left: bool,
     pub moving_right: bool,
-    pub run_cycle: usize,
+    pub crouching: bool,
+    pub looking_up: bool, // Holding up, to go through doors and pipes overhead
+    pub dead: bool,
+    #[serde(default)]
+    pub dead_ticks: u32, // Ticks since dying
     pub turn_cycle: usize,
+    pub coyote_ticks: u32,      // Ticks left to jump after walking off a ledge
+    pub jump_buffer_ticks: u32, // Ticks left for a jump pressed in the air to happen on landing
+    pub buttons: Buttons,       // Held during the last tick
 }
 
-pub const PLAYER_SPRITE_STANDING: usize = 42;
-pub const RUN_MODIFIER: f32 = 1.5; // Speed modifier when running
+const SPAWN_POINT: (f32, f32) = (0.0, 320.0); // Later players start a tile further right each
+const SKID_DUST_INTERVAL: usize = 5; // Ticks between puffs of dust while skidding
 
 impl Player {
-    pub fn new() -> Player {
+    pub fn new(index: usize) -> Player {
         let mut obj = Object::new();
-        obj.bounds = Point2::new(32.0, 32.0);
-        obj.transform = object::Transform::new(4.0, 4.0, 8.0, 8.0);
-        obj.pos = Point2::new(0.0, 320.0);
-        let sprite_id = PLAYER_SPRITE_STANDING;
+        obj.collider.size = Vec2::from_f32(32.0, 32.0);
+        // Narrower than the sprite, with the feet at the bottom of it
+        obj.collider.inset = object::Inset::new(4.0, 4.0, 8.0, 4.0);
+        obj.transform.pos = Player::spawn_point(index);
+        let animator = Animator::new();
         let moving_left = false;
         let moving_right = false;
-        let run_cycle = 0;
+        let crouching = false;
+        let looking_up = false;
+        let dead = false;
+        let dead_ticks = 0;
         let turn_cycle = 0;
+        let coyote_ticks = 0;
+        let jump_buffer_ticks = 0;
+        let buttons = Buttons::default();
         Player {
+            index,
             obj,
-            sprite_id,
+            animator,
             moving_left,
             moving_right,
-            run_cycle,
+            crouching,
+            looking_up,
+            dead,
+            dead_ticks,
             turn_cycle,
+            coyote_ticks,
+            jump_buffer_ticks,
+            buttons,
         }
     }
 
-    pub fn update(&mut self, map: &Map) -> GameResult<()> {
-        if self.obj.velocity.y > 0.0 {
-            self.obj.velocity.y -= 0.2;
-            if self.obj.velocity.y < 0.0 {
-                self.obj.is_jumping = false;
-                self.obj.is_falling = true;
+    fn spawn_point(index: usize) -> Vec2 {
+        Vec2::from_f32(SPAWN_POINT.0 + index as f32 * TILE_SIZE, SPAWN_POINT.1)
+    }
+
+    /// Puts the player back at the start of the map
+    pub fn respawn(&mut self) {
+        self.obj.transform.pos = Player::spawn_point(self.index);
+        self.obj.velocity.linear = Vec2::default();
+        self.obj.velocity.is_jumping = false;
+        self.obj.velocity.is_falling = false;
+        self.dead = false;
+        self.dead_ticks = 0;
+        self.coyote_ticks = 0;
+        self.jump_buffer_ticks = 0;
+    }
+
+    /// Takes the buttons held this tick, reacting to the ones pressed and released since the last
+    pub fn apply_buttons(&mut self, buttons: Buttons, profile: &PhysicsProfile) {
+        let previous = self.buttons;
+        let skid_speed = scalar(profile.skid_speed);
+        if buttons.pressed(previous, Buttons::RIGHT) {
+            self.obj.transform.direction = Direction::Right;
+            if self.obj.velocity.linear.x <= -skid_speed {
+                self.turn_cycle = 20;
             }
         }
-
-        if self.obj.is_falling {
-            self.obj.velocity.y -= 0.2;
-            if self.obj.velocity.y <= -5.0 {
-                self.obj.velocity.y = -5.0;
+        if buttons.pressed(previous, Buttons::LEFT) {
+            self.obj.transform.direction = Direction::Left;
+            if self.obj.velocity.linear.x >= skid_speed {
+                self.turn_cycle = 20;
             }
         }
+        if buttons.pressed(previous, Buttons::JUMP) {
+            self.jump_pressed(profile);
+        }
+        if buttons.released(previous, Buttons::JUMP) {
+            self.jump_released();
+        }
+        self.moving_right = buttons.held(Buttons::RIGHT);
+        self.moving_left = buttons.held(Buttons::LEFT);
+        self.looking_up = buttons.held(Buttons::UP);
+        self.crouching = buttons.held(Buttons::DOWN);
+        self.obj.is_running = buttons.held(Buttons::RUN);
+        self.buttons = buttons;
+    }
+
+    /// Buffers a jump, which happens on the next update if the player can jump
+    fn jump_pressed(&mut self, profile: &PhysicsProfile) {
+        self.jump_buffer_ticks = profile.jump_buffer + 1;
+    }
 
+    /// Releasing jump while rising cuts the jump short
+    fn jump_released(&mut self) {
+        if self.obj.velocity.is_jumping {
+            self.obj.velocity.is_jumping = false;
+            self.obj.velocity.is_falling = true;
+            self.obj.velocity.linear.y = ZERO;
+        }
+    }
+
+    fn is_grounded(&self) -> bool {
+        !self.obj.velocity.is_jumping && !self.obj.velocity.is_falling
+    }
+
+    // Jumps are allowed on the ground and for `coyote_time` ticks in the air after walking
+    // off a ledge, but not after a jump has started. A press is remembered for `jump_buffer`
+    // ticks in the air.
+    fn update_jump(&mut self, profile: &PhysicsProfile, events: &mut EventQueue) {
+        if self.is_grounded() {
+            self.coyote_ticks = profile.coyote_time;
+        }
+
+        if self.jump_buffer_ticks > 0
+            && (self.is_grounded() || (self.coyote_ticks > 0 && !self.obj.velocity.is_jumping))
+        {
+            self.obj.velocity.is_jumping = true;
+            self.obj.velocity.is_falling = false;
+            self.obj.velocity.linear.y = profile.jump_velocity(self.obj.velocity.linear.x);
+            self.jump_buffer_ticks = 0;
+            self.coyote_ticks = 0;
+            events.push(GameEvent::Jumped);
+            return;
+        }
+        if !self.is_grounded() {
+            self.coyote_ticks = self.coyote_ticks.saturating_sub(1);
+        }
+        self.jump_buffer_ticks = self.jump_buffer_ticks.saturating_sub(1);
+    }
+
+    /// Kills the player, unless already dead
+    pub fn kill(&mut self, events: &mut EventQueue) {
+        if !self.dead {
+            self.dead = true;
+            self.dead_ticks = 0;
+            events.push(GameEvent::PlayerDied { player: self.index });
+        }
+    }
+
+    pub fn update(
+        &mut self,
+        map: &Map,
+        solids: &[BBox],
+        profile: &PhysicsProfile,
+        animations: &AnimationSet,
+        events: &mut EventQueue,
+    ) -> GameResult<()> {
+        if self.dead {
+            self.dead_ticks += 1;
+            self.animator.update(animations, AnimationState::Die, 0.0);
+            return Ok(());
+        }
+
+        self.update_jump(profile, events);
+
+        self.obj.fall(profile);
+
+        let acceleration = scalar(profile.acceleration);
+        let friction = scalar(profile.friction);
+        let max_speed = profile.max_speed(self.obj.is_running);
         if self.moving_right {
-            self.obj.velocity.x += 0.1;
-            if self.obj.is_running {
-                if self.obj.velocity.x > 4.0 * RUN_MODIFIER {
-                    self.obj.velocity.x = 4.0 * RUN_MODIFIER;
-                }
-            } else {
-                if self.obj.velocity.x > 4.0 {
-                    self.obj.velocity.x = 4.0;
-                }
+            self.obj.velocity.linear.x += acceleration;
+            if self.obj.velocity.linear.x > max_speed {
+                self.obj.velocity.linear.x = max_speed;
             }
-        } else if self.obj.velocity.x > 0.0 {
-            self.obj.velocity.x -= 0.15;
-            if self.obj.velocity.x < 0.0 {
-                self.obj.velocity.x = 0.0;
+        } else if self.obj.velocity.linear.x > ZERO {
+            self.obj.velocity.linear.x -= friction;
+            if self.obj.velocity.linear.x < ZERO {
+                self.obj.velocity.linear.x = ZERO;
             }
         }
 
         if self.moving_left {
-            self.obj.velocity.x -= 0.1;
-            if self.obj.is_running {
-                if self.obj.velocity.x < -4.0 * RUN_MODIFIER {
-                    self.obj.velocity.x = -4.0 * RUN_MODIFIER;
-                }
-            } else {
-                if self.obj.velocity.x < -4.0 {
-                    self.obj.velocity.x = -4.0;
-                }
+            self.obj.velocity.linear.x -= acceleration;
+            if self.obj.velocity.linear.x < -max_speed {
+                self.obj.velocity.linear.x = -max_speed;
             }
-        } else if self.obj.velocity.x < 0.0 {
-            self.obj.velocity.x += 0.15;
-            if self.obj.velocity.x > 0.0 {
-                self.obj.velocity.x = 0.0;
+        } else if self.obj.velocity.linear.x < ZERO {
+            self.obj.velocity.linear.x += friction;
+            if self.obj.velocity.linear.x > ZERO {
+                self.obj.velocity.linear.x = ZERO;
             }
         }
 
         // Updates position based on velocity and handle collisions
-        self.obj.update(map);
+        let airborne = self.obj.velocity.is_jumping || self.obj.velocity.is_falling;
+        self.obj.update(map, solids);
+        if airborne && self.obj.collider.grounded {
+            events.push(GameEvent::Landed { pos: self.feet() });
+        }
+        if let Some(tile) = self.obj.collider.ceiling_tile {
+            events.push(GameEvent::HeadBumped {
+                tile,
+                player: self.index,
+            });
+        }
 
-        let mut beneath_bbox = self.obj.get_bbox();
-        beneath_bbox.pos.y += 32.0;
+        if !self.obj.collider.grounded && !self.obj.velocity.is_jumping {
+            self.obj.velocity.is_falling = true;
+        }
 
-        if !Object::collided(&beneath_bbox, map).is_some() && !self.obj.is_jumping {
-            self.obj.is_falling = true;
+        if self.obj.transform.pos.y > scalar(map.dimensions.1 as f32 * TILE_SIZE) {
+            self.kill(events);
         }
 
-        self.set_animation();
+        if self.turn_cycle > 0 {
+            self.turn_cycle -= 1;
+            if self.obj.collider.grounded && self.turn_cycle.is_multiple_of(SKID_DUST_INTERVAL) {
+                events.push(GameEvent::Skidded { pos: self.feet() });
+            }
+        }
+        let state = self.animation_state();
+        self.animator
+            .update(animations, state, to_f32(self.obj.velocity.linear.x).abs());
         Ok(())
     }
 
-    fn set_animation(&mut self) {
-        self.sprite_id = PLAYER_SPRITE_STANDING; // Default case is standing animation
-        if self.obj.is_jumping || self.obj.is_falling {
-            self.sprite_id = 47;
-        } else if self.obj.velocity.x != 0.0 {
-            // If player recently turned, show turning animation
-            if self.turn_cycle > 0 {
-                self.sprite_id = 46;
-                self.turn_cycle -= 1;
-                return;
+    /// Moves the player into or out of a warp, ignoring physics
+    pub fn slide(&mut self, step: (f32, f32), animations: &AnimationSet) {
+        self.obj.transform.pos.x += scalar(step.0);
+        self.obj.transform.pos.y += scalar(step.1);
+        self.obj.velocity.linear = Vec2::default();
+        self.obj.velocity.is_jumping = false;
+        self.obj.velocity.is_falling = false;
+        let state = if step.0 != 0.0 {
+            AnimationState::Walk
+        } else {
+            AnimationState::Idle
+        };
+        self.animator.update(animations, state, step.0.abs());
+    }
+
+    // Bottom center of the bounding box
+    fn feet(&self) -> Vec2 {
+        let bbox = self.obj.get_bbox();
+        Vec2::new(bbox.pos.x + bbox.size.x / scalar(2.0), bbox.pos.y + bbox.size.y)
+    }
+
+    fn animation_state(&self) -> AnimationState {
+        if self.dead {
+            AnimationState::Die
+        } else if self.obj.velocity.is_jumping {
+            AnimationState::Jump
+        } else if self.obj.velocity.is_falling {
+            AnimationState::Fall
+        } else if self.crouching {
+            AnimationState::Crouch
+        } else if self.obj.velocity.linear.x == ZERO {
+            AnimationState::Idle
+        } else if self.turn_cycle > 0 {
+            // Player recently turned around
+            AnimationState::Skid
+        } else if self.obj.is_running {
+            AnimationState::Run
+        } else {
+            AnimationState::Walk
+        }
+    }
+
+    pub fn checksum(&self, sum: &mut Checksum) {
+        self.obj.checksum(sum);
+        sum.write_bool(self.moving_left);
+        sum.write_bool(self.moving_right);
+        sum.write_bool(self.crouching);
+        sum.write_bool(self.looking_up);
+        sum.write_bool(self.dead);
+        sum.write_u32(self.dead_ticks);
+        sum.write_u32(self.turn_cycle as u32);
+        sum.write_u32(self.coyote_ticks);
+        sum.write_u32(self.jump_buffer_ticks);
+        sum.write_u8(self.buttons.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use map::LayerKind;
+    use toml;
+
+    const FLOOR_Y: u32 = 10;
+    const LEDGE_WIDTH: u32 = 4;
+
+    // Floor along the left of the map, ending in a ledge with nothing below it
+    fn ledge_map() -> Map {
+        let mut map = Map::new(20, 12);
+        let width = map.dimensions.0;
+        let main = map.layer_mut(LayerKind::Main);
+        for x in 0..LEDGE_WIDTH {
+            main.tiles[(FLOOR_Y * width + x) as usize].id = 1;
+        }
+        map
+    }
+
+    fn profile() -> PhysicsProfile {
+        toml::from_str(include_str!("../resources/physics.toml")).unwrap()
+    }
+
+    fn animations() -> AnimationSet {
+        toml::from_str(include_str!("../resources/player_animations.toml")).unwrap()
+    }
+
+    // Player standing on the floor at a tile's x
+    fn player_at(x: u32) -> Player {
+        let mut player = Player::new(0);
+        player.obj.transform.pos =
+            Vec2::from_f32(x as f32 * TILE_SIZE, (FLOOR_Y - 1) as f32 * TILE_SIZE);
+        player
+    }
+
+    struct Run {
+        map: Map,
+        profile: PhysicsProfile,
+        animations: AnimationSet,
+        events: EventQueue,
+    }
+
+    impl Run {
+        fn new() -> Run {
+            Run {
+                map: ledge_map(),
+                profile: profile(),
+                animations: animations(),
+                events: EventQueue::new(),
             }
+        }
+
+        // Runs a tick holding some buttons, giving the events it pushed
+        fn tick(&mut self, player: &mut Player, buttons: u8) -> Vec<GameEvent> {
+            self.events.clear();
+            player.apply_buttons(Buttons(buttons), &self.profile);
+            player
+                .update(
+                    &self.map,
+                    &[],
+                    &self.profile,
+                    &self.animations,
+                    &mut self.events,
+                )
+                .unwrap();
+            self.events.events().to_vec()
+        }
+    }
+
+    // Walks off the ledge and presses jump on the given tick spent in the air, 1 being the
+    // first one. Gives whether the jump happened.
+    fn jump_after_walking_off(airborne_tick: u32) -> bool {
+        let mut run = Run::new();
+        let mut player = player_at(1);
+        let mut ticks = 0;
+        while !player.obj.velocity.is_falling {
+            run.tick(&mut player, Buttons::RIGHT);
+            ticks += 1;
+            assert!(ticks < 200, "the player never walked off the ledge");
+        }
+        for _ in 1..airborne_tick {
+            run.tick(&mut player, Buttons::RIGHT);
+        }
+        run.tick(&mut player, Buttons::RIGHT | Buttons::JUMP)
+            .contains(&GameEvent::Jumped)
+    }
+
+    #[test]
+    fn coyote_time_allows_late_jumps() {
+        let coyote_time = profile().coyote_time;
+        assert!(coyote_time > 0);
+        assert!(jump_after_walking_off(1));
+        assert!(jump_after_walking_off(coyote_time));
+        assert!(!jump_after_walking_off(coyote_time + 1));
+    }
+
+    // Drops the player onto the floor, pressing jump on the given tick, and gives the tick
+    // the jump happened on, if it did
+    fn jump_while_dropping(press: u32) -> Option<u32> {
+        let mut run = Run::new();
+        let mut player = player_at(1);
+        player.obj.transform.pos.y -= scalar(4.0 * TILE_SIZE);
+        (0..120).find(|&tick| {
+            let buttons = if tick >= press { Buttons::JUMP } else { 0 };
+            run.tick(&mut player, buttons).contains(&GameEvent::Jumped)
+        })
+    }
+
+    // First tick the dropped player is on the floor, after the one it lands in
+    fn landing_tick() -> u32 {
+        let mut run = Run::new();
+        let mut player = player_at(1);
+        player.obj.transform.pos.y -= scalar(4.0 * TILE_SIZE);
+        let landed = (0..120)
+            .find(|_| {
+                run.tick(&mut player, 0)
+                    .iter()
+                    .any(|event| matches!(event, GameEvent::Landed { .. }))
+            })
+            .expect("the player never landed");
+        landed + 1
+    }
 
-            self.sprite_id = match self.run_cycle {
-                0...5 => 43,
-                5...10 => 45,
-                _ => 44,
-            };
-            self.run_cycle += 1;
-            if self.run_cycle >= 15 {
-                self.run_cycle = 0;
+    #[test]
+    fn jump_buffer_remembers_early_presses() {
+        let jump_buffer = profile().jump_buffer;
+        let landing = landing_tick();
+        assert!(landing > jump_buffer + 1);
+        assert_eq!(jump_while_dropping(landing - jump_buffer), Some(landing));
+        assert_eq!(jump_while_dropping(landing - 1), Some(landing));
+        assert_eq!(jump_while_dropping(landing - jump_buffer - 1), None);
+    }
+
+    // Runs some ticks, pressing jump on the first one if asked to, and gives the ticks
+    // each event was pushed on
+    fn events_over(player: &mut Player, map: Map, ticks: u32, jump: bool) -> Vec<(u32, GameEvent)> {
+        let mut run = Run::new();
+        run.map = map;
+        let buttons = if jump { Buttons::JUMP } else { 0 };
+        let mut events = Vec::new();
+        for tick in 0..ticks {
+            for event in run.tick(player, buttons) {
+                events.push((tick, event));
             }
         }
+        events
+    }
+
+    #[test]
+    fn jumping_into_a_block_bumps_it() {
+        // Block three tiles above the one the player stands in, 68 pixels over its head
+        let mut map = ledge_map();
+        let block = ((FLOOR_Y - 4) * map.dimensions.0 + 1) as usize;
+        map.layer_mut(LayerKind::Main).tiles[block].id = 1;
+        let mut player = player_at(1);
+        let events = events_over(&mut player, map, 20, true);
+
+        // Rising at 6 pixels a tick, 0.2 less every tick, covers 68 pixels on the 16th tick
+        assert_eq!(events[0], (0, GameEvent::Jumped));
+        let bump = GameEvent::HeadBumped {
+            tile: block,
+            player: 0,
+        };
+        let bumps: Vec<_> = events.iter().filter(|&&(_, e)| e == bump).collect();
+        assert_eq!(bumps, [&(15, bump)]);
+    }
+
+    #[test]
+    fn dropping_onto_the_floor_lands() {
+        let mut player = player_at(1);
+        player.obj.transform.pos.y -= scalar(4.0 * TILE_SIZE);
+        let events = events_over(&mut player, ledge_map(), 60, false);
+
+        // The first tick finds nothing below. Falling speeds up by 0.2 a tick to 5 pixels a
+        // tick after 25 ticks and 65 pixels, and the other 63 of the 128 take 13 more.
+        let feet = Vec2::from_f32(TILE_SIZE + 4.0 + 12.0, FLOOR_Y as f32 * TILE_SIZE);
+        assert_eq!(events, [(38, GameEvent::Landed { pos: feet })]);
+    }
+
+    #[test]
+    fn falling_out_of_the_map_dies_once() {
+        let mut player = player_at(LEDGE_WIDTH + 2);
+        let events = events_over(&mut player, ledge_map(), 60, false);
+
+        // The map ends 96 pixels below, which takes 25 ticks speeding up to 65 pixels and
+        // 7 more at 5 pixels a tick, after the first tick finds nothing below
+        assert_eq!(events, [(32, GameEvent::PlayerDied { player: 0 })]);
+        assert!(player.dead);
     }
 }
@@ -0,0 +1,282 @@
+use checksum::Checksum;
+use game::Completion;
+use ggez::{Context, GameError, GameResult};
+use map::MAP_PATH;
+use player::Player;
+use state::MainState;
+use stats::Stats;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use toml;
+
+pub const PROFILE_SLOTS: usize = 3;
+const CHECKSUM_PREFIX: &str = "# checksum ";
+
+/// Star coin picked up somewhere in a level, by the map it is in and its tile on the main layer
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct StarCoin {
+    pub map: String,
+    pub tile: usize,
+}
+
+/// Progress through the game, kept across sessions
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Profile {
+    // Maps levels start in, in the order they were reached
+    pub unlocked: Vec<String>,
+    // Fewest ticks each level was finished in
+    pub best_times: BTreeMap<String, u32>,
+    pub star_coins: Vec<StarCoin>,
+    // Lives and score of each player when the last level was finished
+    pub stats: Vec<Stats>,
+}
+
+/// What a save slot holds
+pub enum Slot {
+    Empty,
+    // The file is there but can't be trusted, it has to be deleted before the slot is used again
+    Corrupted,
+    Used(Profile),
+}
+
+impl Profile {
+    pub fn new() -> Profile {
+        Profile {
+            unlocked: vec![MAP_PATH.to_string()],
+            best_times: BTreeMap::new(),
+            star_coins: Vec::new(),
+            stats: vec![Stats::new(0)],
+        }
+    }
+
+    /// Level to carry on from, the last one reached
+    pub fn level(&self) -> &str {
+        self.unlocked.last().map_or(MAP_PATH, |level| level.as_str())
+    }
+
+    /// Records a finished level and unlocks the one it leads to
+    pub fn complete_level(
+        &mut self,
+        level: &str,
+        ticks: u32,
+        next: &str,
+        star_coins: &[StarCoin],
+        stats: &[Stats],
+    ) {
+        let best = self.best_times.entry(level.to_string()).or_insert(ticks);
+        if ticks < *best {
+            *best = ticks;
+        }
+        if !self.unlocked.iter().any(|unlocked| unlocked == next) {
+            self.unlocked.push(next.to_string());
+        }
+        for coin in star_coins {
+            if !self.star_coins.contains(coin) {
+                self.star_coins.push(coin.clone());
+            }
+        }
+        self.stats = stats.to_vec();
+    }
+
+    /// Reads a save slot from the user data directory. A file that fails its checksum
+    /// is replaced by the copy kept from before the last save, if that one is intact.
+    pub fn load(ctx: &Context, slot: usize) -> Slot {
+        let path = slot_path(ctx, slot);
+        if !path.exists() {
+            return Slot::Empty;
+        }
+        let error = match read(&path) {
+            Ok(profile) => return Slot::Used(profile),
+            Err(e) => e,
+        };
+        println!("Save slot {} is corrupted: {}", slot + 1, error);
+
+        let backup = path.with_extension("toml.bak");
+        match read(&backup) {
+            Ok(profile) => {
+                println!("Using the backup of save slot {}", slot + 1);
+                Slot::Used(profile)
+            }
+            Err(_) => Slot::Corrupted,
+        }
+    }
+
+    /// Writes the profile to a save slot. It goes to a temporary file first which then
+    /// replaces the old one, so a crash halfway leaves either the old or the new save.
+    pub fn save(&self, ctx: &Context, slot: usize) -> GameResult<()> {
+        let path = slot_path(ctx, slot);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        // Going through a TOML value puts plain values before tables, as TOML requires
+        let body = toml::to_string(&toml::Value::try_from(self)?)?;
+        let contents = format!("{}{:016x}\n{}", CHECKSUM_PREFIX, checksum(&body), body);
+        let temporary = path.with_extension("toml.tmp");
+        {
+            let mut file = fs::File::create(&temporary)?;
+            file.write_all(contents.as_bytes())?;
+            file.sync_all()?;
+        }
+        // A corrupted save is not kept as the backup, it would replace a good one
+        if read(&path).is_ok() {
+            fs::copy(&path, path.with_extension("toml.bak"))?;
+        }
+        fs::rename(&temporary, &path)?;
+        Ok(())
+    }
+}
+
+/// Deletes a save slot along with its backup
+pub fn delete(ctx: &Context, slot: usize) -> GameResult<()> {
+    let path = slot_path(ctx, slot);
+    for file in &[path.clone(), path.with_extension("toml.bak")] {
+        if file.exists() {
+            fs::remove_file(file)?;
+        }
+    }
+    Ok(())
+}
+
+fn slot_path(ctx: &Context, slot: usize) -> PathBuf {
+    ctx.filesystem
+        .get_user_data_dir()
+        .join(format!("profile_{}.toml", slot + 1))
+}
+
+fn checksum(body: &str) -> u64 {
+    let mut sum = Checksum::new();
+    for byte in body.bytes() {
+        sum.write_u8(byte);
+    }
+    sum.finish()
+}
+
+// Reads a save file, checking it against the checksum on its first line
+fn read(path: &PathBuf) -> GameResult<Profile> {
+    let contents = fs::read_to_string(path)?;
+    let (header, body) = match contents.find('\n') {
+        Some(end) => (&contents[..end], &contents[end + 1..]),
+        None => (contents.as_str(), ""),
+    };
+    let expected = header
+        .strip_prefix(CHECKSUM_PREFIX)
+        .and_then(|hex| u64::from_str_radix(hex.trim(), 16).ok());
+    if expected != Some(checksum(body)) {
+        return Err(GameError::ResourceLoadError(format!(
+            "Checksum of {} does not match",
+            path.display()
+        )));
+    }
+    let profile: Profile = toml::from_str(body)?;
+    if profile.unlocked.is_empty() {
+        return Err(GameError::ResourceLoadError(format!(
+            "{} has no unlocked levels",
+            path.display()
+        )));
+    }
+    Ok(profile)
+}
+
+impl MainState {
+    /// Reads every save slot from disk again, for the slot menu
+    pub fn reload_profiles(&mut self, ctx: &Context) {
+        self.profiles = (0..PROFILE_SLOTS).map(|slot| Profile::load(ctx, slot)).collect();
+    }
+
+    /// Profile of the save slot being played, if any
+    pub fn current_profile(&self) -> Option<&Profile> {
+        match self.profile.map(|slot| &self.profiles[slot]) {
+            Some(Slot::Used(profile)) => Some(profile),
+            _ => None,
+        }
+    }
+
+    /// Plays on with a save slot from the last level reached, starting a new game if it is empty
+    pub fn start_profile(&mut self, ctx: &mut Context, slot: usize) -> GameResult<()> {
+        let profile = match self.profiles[slot] {
+            Slot::Empty => {
+                let profile = Profile::new();
+                profile.save(ctx, slot)?;
+                profile
+            }
+            Slot::Corrupted => {
+                return Err(GameError::ResourceLoadError(format!(
+                    "Save slot {} is corrupted, delete it to start over",
+                    slot + 1
+                )))
+            }
+            Slot::Used(ref profile) => profile.clone(),
+        };
+
+        let level = profile.level().to_string();
+        self.game.stats = profile.stats.clone();
+        self.game.players = self
+            .game
+            .stats
+            .iter()
+            .map(|stats| Player::new(stats.player))
+            .collect();
+        self.profiles[slot] = Slot::Used(profile);
+        self.profile = Some(slot);
+        self.start_level(ctx, &level)
+    }
+
+    /// Copies one save slot over another
+    pub fn copy_profile(&mut self, ctx: &Context, from: usize, to: usize) -> GameResult<()> {
+        let profile = match self.profiles[from] {
+            Slot::Used(ref profile) => profile.clone(),
+            _ => {
+                return Err(GameError::ResourceLoadError(format!(
+                    "Save slot {} has nothing to copy",
+                    from + 1
+                )))
+            }
+        };
+        profile.save(ctx, to)?;
+        self.profiles[to] = Slot::Used(profile);
+        Ok(())
+    }
+
+    pub fn delete_profile(&mut self, ctx: &Context, slot: usize) -> GameResult<()> {
+        delete(ctx, slot)?;
+        self.profiles[slot] = Slot::Empty;
+        if self.profile == Some(slot) {
+            self.profile = None;
+        }
+        Ok(())
+    }
+
+    /// Records a level just finished in the profile being played
+    pub fn complete_level(&mut self, completion: &Completion) {
+        let slot = match self.profile {
+            Some(slot) => slot,
+            None => return,
+        };
+        if let Slot::Used(ref mut profile) = self.profiles[slot] {
+            profile.complete_level(
+                &completion.level,
+                completion.ticks,
+                &completion.next,
+                &completion.star_coins,
+                &completion.stats,
+            );
+        }
+    }
+
+    /// Writes the profile being played to its save slot. Kept out of the simulation,
+    /// which may run a tick more than once.
+    pub fn save_profile(&self, ctx: &Context) {
+        let slot = match self.profile {
+            Some(slot) => slot,
+            None => return,
+        };
+        if let Slot::Used(ref profile) = self.profiles[slot] {
+            if let Err(e) = profile.save(ctx, slot) {
+                println!("Failed to save slot {}: {}", slot + 1, e);
+            }
+        }
+    }
+}
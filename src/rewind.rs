@@ -0,0 +1,156 @@
+use animation::TICKS_PER_SECOND;
+use ggez::graphics::{self, Color, DrawMode, Point2, Rect};
+use ggez::{Context, GameResult};
+use snapshot::Snapshot;
+use state::MainState;
+use std::collections::VecDeque;
+use {SCREEN_HEIGHT, SCREEN_WIDTH};
+
+const REWIND_SECONDS: f32 = 5.0;
+const TIMELINE_HEIGHT: f32 = 12.0;
+const TIMELINE_MARGIN: f32 = 8.0;
+
+/// The last few seconds of play, a snapshot per tick, to go back through
+pub struct Rewind {
+    frames: VecDeque<Snapshot>,
+    cursor: Option<usize>, // Frame scrubbed to, the later ones are dropped once play goes on
+    pub held: bool,        // Going back a tick per tick while the rewind key is held
+}
+
+impl Rewind {
+    pub fn new() -> Rewind {
+        Rewind {
+            frames: VecDeque::new(),
+            cursor: None,
+            held: false,
+        }
+    }
+
+    fn capacity() -> usize {
+        (REWIND_SECONDS * TICKS_PER_SECOND) as usize
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.cursor = None;
+    }
+
+    /// Adds the world as it is after a tick, forgetting the oldest when full
+    pub fn record(&mut self, snapshot: Snapshot) {
+        if let Some(cursor) = self.cursor.take() {
+            self.frames.truncate(cursor + 1);
+        }
+        if self.frames.len() == Rewind::capacity() {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(snapshot);
+    }
+
+    /// Forgets the current frame and gives the one before it, if any
+    fn back(&mut self) -> Option<&Snapshot> {
+        if let Some(cursor) = self.cursor.take() {
+            self.frames.truncate(cursor + 1);
+        }
+        if self.frames.len() < 2 {
+            return None;
+        }
+        self.frames.pop_back();
+        self.frames.back()
+    }
+
+    fn current(&self) -> usize {
+        self.cursor.unwrap_or(self.frames.len().saturating_sub(1))
+    }
+}
+
+impl MainState {
+    /// Goes back a tick, instead of simulating one, while the rewind key is held
+    pub fn update_rewind(&mut self, ctx: &mut Context) {
+        let snapshot = match self.rewind.back() {
+            Some(snapshot) => snapshot.clone(),
+            None => return,
+        };
+        self.game.restore(ctx, &snapshot);
+    }
+
+    /// Jumps to a recorded frame, pausing so it can be looked at
+    pub fn scrub_to(&mut self, ctx: &mut Context, frame: usize) {
+        let frame = match self.rewind.frames.len() {
+            0 => return,
+            len => frame.min(len - 1),
+        };
+        self.time.paused = true;
+        self.rewind.cursor = Some(frame);
+        let snapshot = self.rewind.frames[frame].clone();
+        self.game.restore(ctx, &snapshot);
+    }
+
+    /// Moves the scrubbed frame by some ticks, back for negative ones
+    pub fn scrub_by(&mut self, ctx: &mut Context, ticks: i32) {
+        let frame = (self.rewind.current() as i32 + ticks).max(0);
+        self.scrub_to(ctx, frame as usize);
+    }
+
+    /// Scrubs to the frame under a click on the timeline, if it is on it
+    pub fn click_timeline(&mut self, ctx: &mut Context, x: f32, y: f32) {
+        let rect = timeline_rect();
+        let len = self.rewind.frames.len();
+        if len == 0 || !rect.contains(Point2::new(x, y)) {
+            return;
+        }
+        let filled = len as f32 / Rewind::capacity() as f32;
+        let frame = ((x - rect.x) / (rect.w * filled) * len as f32) as usize;
+        self.scrub_to(ctx, frame);
+    }
+
+    /// Shows the frames kept to rewind through with the one the world is at, while
+    /// the debug overlay is on, and says so while rewinding
+    pub fn draw_timeline(&mut self, ctx: &mut Context) -> GameResult<()> {
+        if self.rewind.held {
+            let text = graphics::Text::new(ctx, "<< REWIND", &self.font)?;
+            let x = (SCREEN_WIDTH as f32 - text.width() as f32) / 2.0;
+            graphics::draw(ctx, &text, Point2::new(x, 60.0), 0.0)?;
+        }
+        let len = self.rewind.frames.len();
+        if !self.debug || len == 0 {
+            return Ok(());
+        }
+
+        // The bar fills up as frames are recorded, up to the seconds kept
+        let rect = timeline_rect();
+        let filled = len as f32 / Rewind::capacity() as f32;
+        graphics::set_color(ctx, Color::new(0.0, 0.0, 0.0, 0.6))?;
+        graphics::rectangle(ctx, DrawMode::Fill, rect)?;
+        graphics::set_color(ctx, Color::new(0.3, 0.6, 1.0, 0.6))?;
+        let recorded = Rect::new(rect.x, rect.y, rect.w * filled, rect.h);
+        graphics::rectangle(ctx, DrawMode::Fill, recorded)?;
+
+        let current = self.rewind.current();
+        let x = rect.x + rect.w * (current as f32 + 0.5) / len as f32 * filled;
+        graphics::set_color(ctx, Color::new(1.0, 1.0, 1.0, 1.0))?;
+        graphics::line(
+            ctx,
+            &[Point2::new(x, rect.y - 2.0), Point2::new(x, rect.y + rect.h + 2.0)],
+            2.0,
+        )?;
+        let label = format!(
+            "TICK {} ({} of {})",
+            self.rewind.frames[current].clock(),
+            current + 1,
+            len
+        );
+        let text = graphics::Text::new(ctx, &label, &self.font)?;
+        let pos = Point2::new(rect.x, rect.y - text.height() as f32 - 4.0);
+        graphics::draw(ctx, &text, pos, 0.0)
+    }
+}
+
+// Where the timeline is drawn, along the bottom of the screen
+fn timeline_rect() -> Rect {
+    Rect::new(
+        TIMELINE_MARGIN,
+        SCREEN_HEIGHT as f32 - TIMELINE_MARGIN - TIMELINE_HEIGHT,
+        SCREEN_WIDTH as f32 - 2.0 * TIMELINE_MARGIN,
+        TIMELINE_HEIGHT,
+    )
+}
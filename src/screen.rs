@@ -0,0 +1,577 @@
+use animation::TICKS_PER_SECOND;
+use audio::VOLUMES;
+use ggez::event::{Keycode, Mod};
+use ggez::graphics::{self, Color, DrawMode, Point2, Rect};
+use ggez::{Context, GameResult};
+use map::MAP_PATH;
+use profile::{Slot, PROFILE_SLOTS};
+use state::MainState;
+use {SCREEN_HEIGHT, SCREEN_WIDTH};
+
+const TITLE_ITEMS: [&str; 5] = ["START", "LEVEL SELECT", "EDITOR", "OPTIONS", "QUIT"];
+const PAUSE_ITEMS: [&str; 3] = ["RESUME", "RESTART", "QUIT"];
+const GAME_OVER_ITEMS: [&str; 2] = ["CONTINUE", "QUIT"];
+const OPTION_COUNT: usize = 6; // Three volumes, mute, players and back
+const MENU_TOP: f32 = 160.0;
+const LINE_HEIGHT: f32 = 24.0;
+
+/// Menu for picking, copying and deleting save slots
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ProfileMenu {
+    pub selected: usize,
+    pub copying: Option<usize>, // Slot being copied, to the one selected next
+    pub deleting: bool,         // Waiting for the deletion of the selected slot to be confirmed
+}
+
+/// A screen of the game. Screens are kept on a stack, the top one takes the input and
+/// is updated. Overlays such as the pause menu are drawn over the screens beneath them.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Screen {
+    Title { selected: usize },
+    Profiles(ProfileMenu),
+    LevelSelect { selected: usize },
+    Playing,
+    Paused { selected: usize },
+    GameOver { selected: usize },
+    LevelComplete { ticks: u32 }, // Ticks the level took
+    Editor,
+    Options { selected: usize },
+}
+
+impl Screen {
+    fn is_overlay(self) -> bool {
+        matches!(
+            self,
+            Screen::Paused { .. } | Screen::GameOver { .. } | Screen::LevelComplete { .. }
+        )
+    }
+}
+
+/// What a screen does with the stack after handling an update or a key
+pub enum ScreenChange {
+    Stay,
+    Push(Screen),
+    Pop,
+    Replace(Screen),
+    Reset(Vec<Screen>), // Replaces the whole stack
+}
+
+// Moves the selection of a menu with the up and down keys, wrapping around
+fn select(selected: &mut usize, count: usize, keycode: Keycode) {
+    match keycode {
+        Keycode::Up | Keycode::W => *selected = (*selected + count - 1) % count,
+        Keycode::Down | Keycode::S => *selected = (*selected + 1) % count,
+        _ => {}
+    }
+}
+
+fn playing() -> ScreenChange {
+    ScreenChange::Reset(vec![Screen::Title { selected: 0 }, Screen::Playing])
+}
+
+fn quit_to_title() -> ScreenChange {
+    ScreenChange::Reset(vec![Screen::Title { selected: 0 }])
+}
+
+fn seconds(ticks: u32) -> f32 {
+    ticks as f32 / TICKS_PER_SECOND
+}
+
+impl MainState {
+    pub fn screen(&self) -> Screen {
+        *self.screens.last().unwrap()
+    }
+
+    /// Whether the map is being edited rather than played
+    pub fn editing(&self) -> bool {
+        self.screens.contains(&Screen::Editor)
+    }
+
+    pub fn change_screen(&mut self, change: ScreenChange) {
+        let was_editing = self.editing();
+        match change {
+            ScreenChange::Stay => {}
+            ScreenChange::Push(screen) => self.screens.push(screen),
+            // The title screen is never popped
+            ScreenChange::Pop => {
+                if self.screens.len() > 1 {
+                    self.screens.pop();
+                }
+            }
+            ScreenChange::Replace(screen) => *self.screens.last_mut().unwrap() = screen,
+            ScreenChange::Reset(screens) => self.screens = screens,
+        }
+        // The map may have changed since the editor was last open
+        if self.editing() && !was_editing {
+            self.editor.deselect();
+        }
+    }
+
+    pub fn update_screen(&mut self, ctx: &mut Context) -> GameResult<()> {
+        let change = match self.screen() {
+            Screen::Playing => self.update_playing(ctx)?,
+            Screen::Editor => {
+                // Going back to before the map was edited would undo the edits
+                self.rewind.clear();
+                self.game.clock += 1;
+                self.update_editor(ctx)?;
+                ScreenChange::Stay
+            }
+            _ => ScreenChange::Stay,
+        };
+        self.change_screen(change);
+        Ok(())
+    }
+
+    /// Draws the top screen, over the ones beneath it if it is an overlay
+    pub fn draw_screens(&mut self, ctx: &mut Context) -> GameResult<()> {
+        let base = self
+            .screens
+            .iter()
+            .rposition(|screen| !screen.is_overlay())
+            .unwrap_or(0);
+        for i in base..self.screens.len() {
+            match self.screens[i] {
+                Screen::Title { selected } => self.draw_title(ctx, selected)?,
+                Screen::Profiles(menu) => self.draw_profiles(ctx, menu)?,
+                Screen::LevelSelect { selected } => self.draw_level_select(ctx, selected)?,
+                Screen::Playing | Screen::Editor => self.draw_world(ctx)?,
+                Screen::Paused { selected } => self.draw_paused(ctx, selected)?,
+                Screen::GameOver { selected } => self.draw_game_over(ctx, selected)?,
+                Screen::LevelComplete { ticks } => self.draw_level_complete(ctx, ticks)?,
+                Screen::Options { selected } => self.draw_options(ctx, selected)?,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn key_down_screen(
+        &mut self,
+        ctx: &mut Context,
+        keycode: Keycode,
+        keymod: Mod,
+        repeat: bool,
+    ) {
+        // The screen is taken off the stack while it handles the key, and put back changed
+        let mut screen = self.screens.pop().unwrap();
+        let change = match screen {
+            Screen::Title { ref mut selected } => self.key_down_title(ctx, keycode, selected),
+            Screen::Profiles(ref mut menu) => self.key_down_profiles(ctx, keycode, menu),
+            Screen::LevelSelect { ref mut selected } => {
+                self.key_down_level_select(ctx, keycode, selected)
+            }
+            Screen::Playing => self.key_down_playing(ctx, keycode, keymod, repeat),
+            Screen::Paused { ref mut selected } => self.key_down_paused(keycode, selected),
+            Screen::GameOver { ref mut selected } => self.key_down_game_over(keycode, selected),
+            Screen::LevelComplete { .. } => match keycode {
+                Keycode::Return | Keycode::Escape => ScreenChange::Pop,
+                _ => ScreenChange::Stay,
+            },
+            Screen::Editor => self.key_down_editing(ctx, keycode, keymod, repeat),
+            Screen::Options { ref mut selected } => self.key_down_options(keycode, selected),
+        };
+        self.screens.push(screen);
+        self.change_screen(change);
+    }
+
+    pub fn key_up_screen(
+        &mut self,
+        ctx: &mut Context,
+        keycode: Keycode,
+        keymod: Mod,
+        repeat: bool,
+    ) {
+        // Keys let go of on any screen are no longer held once back to playing
+        self.key_up_play(ctx, keycode, keymod, repeat);
+    }
+
+    // Runs the game, stopping for the game over and level complete screens.
+    // A networked game can't stop for one side, so it goes on without them.
+    fn update_playing(&mut self, ctx: &mut Context) -> GameResult<ScreenChange> {
+        if self.netplay.is_some() {
+            self.update_netplay(ctx)?;
+            self.game.completed = None;
+            return Ok(ScreenChange::Stay);
+        }
+        if self.rewind.held {
+            self.update_rewind(ctx);
+            return Ok(ScreenChange::Stay);
+        }
+        let buttons = self.held;
+        self.game.simulate(ctx, &buttons)?;
+        let completed = self.game.completed.take();
+        // Going back into the level just finished would finish it again
+        if completed.is_some() {
+            self.rewind.clear();
+        }
+        let snapshot = self.game.snapshot();
+        self.rewind.record(snapshot);
+
+        if let Some(completion) = completed {
+            self.complete_level(&completion);
+            self.save_profile(ctx);
+            return Ok(ScreenChange::Push(Screen::LevelComplete {
+                ticks: completion.ticks,
+            }));
+        }
+        // Restarting while lives are left happens in the simulation
+        let game_over =
+            self.game.all_deaths_shown() && self.game.stats.iter().all(|stats| stats.lives == 0);
+        if game_over {
+            return Ok(ScreenChange::Push(Screen::GameOver { selected: 0 }));
+        }
+        Ok(ScreenChange::Stay)
+    }
+
+    fn key_down_title(
+        &mut self,
+        ctx: &mut Context,
+        keycode: Keycode,
+        selected: &mut usize,
+    ) -> ScreenChange {
+        select(selected, TITLE_ITEMS.len(), keycode);
+        match keycode {
+            Keycode::Return => match *selected {
+                0 => {
+                    self.reload_profiles(ctx);
+                    ScreenChange::Push(Screen::Profiles(ProfileMenu {
+                        selected: self.profile.unwrap_or(0),
+                        copying: None,
+                        deleting: false,
+                    }))
+                }
+                1 => ScreenChange::Push(Screen::LevelSelect { selected: 0 }),
+                2 => ScreenChange::Push(Screen::Editor),
+                3 => ScreenChange::Push(Screen::Options { selected: 0 }),
+                _ => {
+                    let _ = ctx.quit();
+                    ScreenChange::Stay
+                }
+            },
+            Keycode::Escape => {
+                let _ = ctx.quit();
+                ScreenChange::Stay
+            }
+            _ => ScreenChange::Stay,
+        }
+    }
+
+    // C copies the selected slot to the one picked next, X deletes it once confirmed with Y
+    fn key_down_profiles(
+        &mut self,
+        ctx: &mut Context,
+        keycode: Keycode,
+        menu: &mut ProfileMenu,
+    ) -> ScreenChange {
+        if menu.deleting {
+            menu.deleting = false;
+            if keycode == Keycode::Y {
+                if let Err(e) = self.delete_profile(ctx, menu.selected) {
+                    println!("Failed to delete slot {}: {}", menu.selected + 1, e);
+                }
+            }
+            return ScreenChange::Stay;
+        }
+
+        select(&mut menu.selected, PROFILE_SLOTS, keycode);
+        let selected = menu.selected;
+        match keycode {
+            Keycode::Return => {
+                let result = match menu.copying.take() {
+                    Some(from) if from != selected => self.copy_profile(ctx, from, selected),
+                    Some(_) => Ok(()),
+                    None => match self.start_profile(ctx, selected) {
+                        Ok(()) => return playing(),
+                        Err(e) => Err(e),
+                    },
+                };
+                if let Err(e) = result {
+                    println!("{}", e);
+                }
+            }
+            Keycode::C => {
+                if let Slot::Used(_) = self.profiles[selected] {
+                    menu.copying = Some(selected);
+                }
+            }
+            Keycode::X | Keycode::Delete => {
+                if let Slot::Empty = self.profiles[selected] {
+                    return ScreenChange::Stay;
+                }
+                menu.copying = None;
+                menu.deleting = true;
+            }
+            Keycode::Escape if menu.copying.take().is_none() => return ScreenChange::Pop,
+            _ => {}
+        }
+        ScreenChange::Stay
+    }
+
+    /// Levels that can be picked, the ones reached in the save slot being played
+    fn unlocked_levels(&self) -> Vec<String> {
+        match self.current_profile() {
+            Some(profile) => profile.unlocked.clone(),
+            None => vec![MAP_PATH.to_string()],
+        }
+    }
+
+    fn key_down_level_select(
+        &mut self,
+        ctx: &mut Context,
+        keycode: Keycode,
+        selected: &mut usize,
+    ) -> ScreenChange {
+        let levels = self.unlocked_levels();
+        select(selected, levels.len(), keycode);
+        match keycode {
+            Keycode::Return => match self.start_level(ctx, &levels[*selected]) {
+                Ok(()) => playing(),
+                Err(e) => {
+                    println!("Failed to load {}: {}", levels[*selected], e);
+                    ScreenChange::Stay
+                }
+            },
+            Keycode::Escape => ScreenChange::Pop,
+            _ => ScreenChange::Stay,
+        }
+    }
+
+    fn key_down_playing(
+        &mut self,
+        ctx: &mut Context,
+        keycode: Keycode,
+        keymod: Mod,
+        repeat: bool,
+    ) -> ScreenChange {
+        // A networked game can't be paused or edited by one side
+        let local_only = self.netplay.is_none();
+        match keycode {
+            Keycode::Escape if local_only => ScreenChange::Push(Screen::Paused { selected: 0 }),
+            Keycode::F1 if local_only => ScreenChange::Replace(Screen::Editor),
+            _ => {
+                self.key_down_play(ctx, keycode, keymod, repeat);
+                ScreenChange::Stay
+            }
+        }
+    }
+
+    fn key_down_paused(&mut self, keycode: Keycode, selected: &mut usize) -> ScreenChange {
+        select(selected, PAUSE_ITEMS.len(), keycode);
+        match keycode {
+            Keycode::Return => match *selected {
+                0 => ScreenChange::Pop,
+                1 => {
+                    self.game.restart_level();
+                    ScreenChange::Pop
+                }
+                _ => quit_to_title(),
+            },
+            Keycode::Escape => ScreenChange::Pop,
+            _ => ScreenChange::Stay,
+        }
+    }
+
+    fn key_down_game_over(&mut self, keycode: Keycode, selected: &mut usize) -> ScreenChange {
+        select(selected, GAME_OVER_ITEMS.len(), keycode);
+        match keycode {
+            Keycode::Return if *selected == 0 => {
+                self.game.restart_level();
+                ScreenChange::Pop
+            }
+            Keycode::Return => quit_to_title(),
+            _ => ScreenChange::Stay,
+        }
+    }
+
+    fn key_down_editing(
+        &mut self,
+        ctx: &mut Context,
+        keycode: Keycode,
+        keymod: Mod,
+        repeat: bool,
+    ) -> ScreenChange {
+        match keycode {
+            Keycode::F1 => ScreenChange::Replace(Screen::Playing),
+            Keycode::Escape => ScreenChange::Pop,
+            _ => {
+                if keycode == Keycode::R {
+                    self.game.restart_level();
+                }
+                self.key_down_editor(ctx, keycode, keymod, repeat);
+                ScreenChange::Stay
+            }
+        }
+    }
+
+    // Left and right change the volumes, return toggles the others
+    fn key_down_options(&mut self, keycode: Keycode, selected: &mut usize) -> ScreenChange {
+        select(selected, OPTION_COUNT, keycode);
+        match (keycode, *selected) {
+            (Keycode::Left, i) | (Keycode::A, i) if i < VOLUMES.len() => {
+                self.audio.change_volume(VOLUMES[i], -1)
+            }
+            (Keycode::Right, i) | (Keycode::D, i) | (Keycode::Return, i) if i < VOLUMES.len() => {
+                self.audio.change_volume(VOLUMES[i], 1)
+            }
+            (Keycode::Return, 3) => self.audio.toggle_mute(),
+            (Keycode::Return, 4) if self.netplay.is_none() => self.game.toggle_second_player(),
+            (Keycode::Return, 5) | (Keycode::Escape, _) => return ScreenChange::Pop,
+            _ => {}
+        }
+        ScreenChange::Stay
+    }
+
+    // Lines of text down the middle of the screen, the highlighted ones in yellow
+    fn draw_lines(&mut self, ctx: &mut Context, lines: &[(String, bool)]) -> GameResult<()> {
+        for (i, &(ref line, highlighted)) in lines.iter().enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            let color = if highlighted {
+                Color::new(1.0, 0.8, 0.2, 1.0)
+            } else {
+                Color::new(1.0, 1.0, 1.0, 1.0)
+            };
+            graphics::set_color(ctx, color)?;
+            let text = graphics::Text::new(ctx, line, &self.font)?;
+            let x = (SCREEN_WIDTH as f32 - text.width() as f32) / 2.0;
+            let y = MENU_TOP + i as f32 * LINE_HEIGHT;
+            graphics::draw(ctx, &text, Point2::new(x, y), 0.0)?;
+        }
+        graphics::set_color(ctx, Color::new(1.0, 1.0, 1.0, 1.0))?;
+        Ok(())
+    }
+
+    // A title, the items to pick from and a hint about the keys
+    fn draw_menu(
+        &mut self,
+        ctx: &mut Context,
+        title: &str,
+        items: &[String],
+        selected: usize,
+        hint: &str,
+    ) -> GameResult<()> {
+        let mut lines = vec![(title.to_string(), false), (String::new(), false)];
+        lines.extend(
+            items
+                .iter()
+                .enumerate()
+                .map(|(i, item)| (item.clone(), i == selected)),
+        );
+        lines.push((String::new(), false));
+        lines.push((hint.to_string(), false));
+        self.draw_lines(ctx, &lines)
+    }
+
+    // Darkens the screens beneath an overlay
+    fn draw_shade(&mut self, ctx: &mut Context) -> GameResult<()> {
+        graphics::set_color(ctx, Color::new(0.0, 0.0, 0.0, 0.6))?;
+        let rect = Rect::new(0.0, 0.0, SCREEN_WIDTH as f32, SCREEN_HEIGHT as f32);
+        graphics::rectangle(ctx, DrawMode::Fill, rect)?;
+        graphics::set_color(ctx, Color::new(1.0, 1.0, 1.0, 1.0))
+    }
+
+    fn draw_title(&mut self, ctx: &mut Context, selected: usize) -> GameResult<()> {
+        let items: Vec<String> = TITLE_ITEMS.iter().map(|item| item.to_string()).collect();
+        self.draw_menu(ctx, "SUPER MARIO", &items, selected, "")
+    }
+
+    // Save slots, one line each with the progress made in it
+    fn draw_profiles(&mut self, ctx: &mut Context, menu: ProfileMenu) -> GameResult<()> {
+        let title = match menu.copying {
+            Some(from) => format!("COPY FILE {} TO", from + 1),
+            None => "SELECT A FILE".to_string(),
+        };
+        let items: Vec<String> = self
+            .profiles
+            .iter()
+            .enumerate()
+            .map(|(i, slot)| {
+                let summary = match *slot {
+                    Slot::Empty => "NEW GAME".to_string(),
+                    Slot::Corrupted => "CORRUPTED".to_string(),
+                    Slot::Used(ref profile) => {
+                        let lives = profile.stats.first().map_or(0, |stats| stats.lives);
+                        let score: u32 = profile.stats.iter().map(|stats| stats.score).sum();
+                        format!(
+                            "x{}  SCORE {:06}  LEVELS {}  STAR COINS {}",
+                            lives,
+                            score,
+                            profile.unlocked.len(),
+                            profile.star_coins.len()
+                        )
+                    }
+                };
+                format!("FILE {}  {}", i + 1, summary)
+            })
+            .collect();
+        let hint = if menu.deleting {
+            format!("DELETE FILE {}? Y / N", menu.selected + 1)
+        } else if menu.copying.is_some() {
+            "RETURN COPY  ESCAPE CANCEL".to_string()
+        } else {
+            "RETURN PLAY  C COPY  X DELETE".to_string()
+        };
+        self.draw_menu(ctx, &title, &items, menu.selected, &hint)
+    }
+
+    fn draw_level_select(&mut self, ctx: &mut Context, selected: usize) -> GameResult<()> {
+        let best_times = self
+            .current_profile()
+            .map(|profile| profile.best_times.clone())
+            .unwrap_or_default();
+        let items: Vec<String> = self
+            .unlocked_levels()
+            .iter()
+            .map(|level| match best_times.get(level) {
+                Some(&ticks) => format!("{}  BEST {:.2}", level, seconds(ticks)),
+                None => level.clone(),
+            })
+            .collect();
+        self.draw_menu(
+            ctx,
+            "LEVEL SELECT",
+            &items,
+            selected,
+            "RETURN PLAY  ESCAPE BACK",
+        )
+    }
+
+    fn draw_paused(&mut self, ctx: &mut Context, selected: usize) -> GameResult<()> {
+        self.draw_shade(ctx)?;
+        let items: Vec<String> = PAUSE_ITEMS.iter().map(|item| item.to_string()).collect();
+        self.draw_menu(ctx, "PAUSED", &items, selected, "")
+    }
+
+    fn draw_game_over(&mut self, ctx: &mut Context, selected: usize) -> GameResult<()> {
+        self.draw_shade(ctx)?;
+        let items: Vec<String> = GAME_OVER_ITEMS
+            .iter()
+            .map(|item| item.to_string())
+            .collect();
+        self.draw_menu(ctx, "GAME OVER", &items, selected, "")
+    }
+
+    fn draw_level_complete(&mut self, ctx: &mut Context, ticks: u32) -> GameResult<()> {
+        self.draw_shade(ctx)?;
+        let score: u32 = self.game.stats.iter().map(|stats| stats.score).sum();
+        let items = vec![
+            format!("TIME {:.2}", seconds(ticks)),
+            format!("SCORE {:06}", score),
+        ];
+        self.draw_menu(ctx, "COURSE CLEAR!", &items, items.len(), "RETURN CONTINUE")
+    }
+
+    fn draw_options(&mut self, ctx: &mut Context, selected: usize) -> GameResult<()> {
+        let settings = &self.audio.settings;
+        let items = vec![
+            format!("VOLUME < {:.0}% >", settings.master_volume * 100.0),
+            format!("MUSIC < {:.0}% >", settings.music_volume * 100.0),
+            format!("EFFECTS < {:.0}% >", settings.sfx_volume * 100.0),
+            format!("MUTE {}", if settings.muted { "ON" } else { "OFF" }),
+            format!("PLAYERS {}", self.game.players.len()),
+            "BACK".to_string(),
+        ];
+        self.draw_menu(ctx, "OPTIONS", &items, selected, "")
+    }
+}
@@ -0,0 +1,1129 @@
+use audio::Sfx;
+use bbox::BBox;
+use checksum::Checksum;
+use ecs;
+use events::GameEvent;
+use game::{Game, Loader};
+use ggez::graphics::{Color, DrawMode, Point2, Rect};
+use ggez::{graphics, Context, GameError, GameResult};
+use map::{LayerKind, Map, TILE_SIZE};
+use math::{scalar, to_f32, Vec2, ZERO};
+use occupancy::Occupancy;
+use sprite::EMPTY_SPRITE;
+use state::MainState;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Read;
+use std::mem;
+
+pub const SCRIPT_BUDGET: u32 = 1000; // Instructions a script may run per tick
+pub const MESSAGE_TICKS: u32 = 120; // How long a message shows when no time is given
+const MAX_ERRORS: usize = 4; // Errors kept on screen
+const MAX_ENTITIES: usize = 256; // Scripts can't spawn enemies past this many entities
+const BUILTINS: [&str; 4] = ["player", "clock", "x", "y"];
+
+/// Area of the map that runs a script when players go in and out of it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ScriptZone {
+    pub tile: (u32, u32), // Top left
+    pub size: (u32, u32), // In tiles
+    pub script: String,
+}
+
+/// Something placed in the map that a script moves around, drawn with a frame of the enemy sheet
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EntityDef {
+    pub pos: (f32, f32),
+    pub sprite: String,
+    pub script: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ScriptEvent {
+    Start, // When the level starts or restarts
+    Tick,
+    Enter,
+    Exit,
+    Touch, // A player touched an entity
+    Timer(String),
+}
+
+#[derive(Clone, Debug)]
+enum Value {
+    Number(i32),
+    Var(String),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Cmp {
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+}
+
+#[derive(Clone, Debug)]
+enum Call {
+    SpawnEnemy(Value, Value),
+    SetTile(Value, Value, Value),
+    PlaySound(Sfx),
+    Message(String, Value),
+    Teleport(Value, Value),
+    Move(Value, Value),
+    Remove,
+}
+
+#[derive(Clone, Debug)]
+enum Instr {
+    Set(String, Value, Option<(Op, Value)>),
+    JumpUnless(Value, Cmp, Value, usize),
+    Jump(usize),
+    Wait(Value),
+    StartTimer(String, Value),
+    Call(Call),
+    End,
+}
+
+// Block being parsed, waiting for its `end`
+enum Block {
+    Handler,
+    If(usize, Option<usize>), // The conditional jump, and the jump over the else branch
+    While(usize),             // The conditional jump, which comes right after the loop start
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Text(String),
+}
+
+/// What a script asks the game to do, applied by `Game` once the script yields
+pub enum Command {
+    SpawnEnemy {
+        x: i32,
+        y: i32,
+    },
+    SetTile {
+        x: i32,
+        y: i32,
+        id: i32,
+    },
+    PlaySound(Sfx),
+    Message {
+        text: String,
+        ticks: u32,
+    },
+    Teleport {
+        player: Option<usize>,
+        x: i32,
+        y: i32,
+    },
+    Move {
+        x: i32,
+        y: i32,
+    },
+    Remove,
+}
+
+/// A compiled script. Scripts are made of handlers, one per event:
+///
+/// ```text
+/// # Comments start with a hash
+/// on enter
+///   set count count + 1
+///   if count == 3
+///     message "Third time lucky" 120
+///     spawn_enemy 20 8
+///   end
+/// end
+/// on timer door
+///   set_tile 30 10 -1
+/// end
+/// ```
+///
+/// Handlers are `start`, `tick`, `enter`, `exit`, `touch` and `timer <name>`.
+/// Statements are `set <var> <a> [op b]`, `if <a> <cmp> <b> ... [else ...] end`,
+/// `while <a> <cmp> <b> ... end`, `wait <ticks>`, `start_timer <name> <ticks>`
+/// and the calls `spawn_enemy x y`, `set_tile x y id` (-1 empties it),
+/// `play_sound name`, `message "text" [ticks]`, `teleport x y`, `move dx dy` and `remove`.
+/// Positions are in tiles, except for `move` which is in pixels. `x` and `y` are the
+/// tile the top left of the zone or entity is in.
+/// Values are integers. `player`, `clock`, `x` and `y` are read only.
+pub struct Script {
+    handlers: Vec<(ScriptEvent, usize)>, // First instruction of each handler
+    code: Vec<Instr>,
+    lines: Vec<usize>, // Source line of each instruction, for errors
+}
+
+impl Script {
+    /// Loads and compiles a script. Errors start with the path, to be shown as they are.
+    pub fn load(ctx: &mut Context, path: &str) -> Result<Script, String> {
+        let mut contents = String::new();
+        ctx.filesystem
+            .open(path)
+            .and_then(|mut file| file.read_to_string(&mut contents).map_err(GameError::from))
+            .map_err(|e| format!("{}: {}", path, e))?;
+        Script::parse(&contents).map_err(|e| format!("{} {}", path, e))
+    }
+
+    pub fn parse(source: &str) -> Result<Script, String> {
+        let mut script = Script {
+            handlers: Vec::new(),
+            code: Vec::new(),
+            lines: Vec::new(),
+        };
+        let mut blocks = Vec::new();
+        for (i, line) in source.lines().enumerate() {
+            let tokens = tokenize(line).map_err(|e| format!("line {}: {}", i + 1, e))?;
+            if !tokens.is_empty() {
+                script
+                    .parse_line(&tokens, &mut blocks, i + 1)
+                    .map_err(|e| format!("line {}: {}", i + 1, e))?;
+            }
+        }
+        if !blocks.is_empty() {
+            return Err("is missing an end".to_string());
+        }
+        Ok(script)
+    }
+
+    fn emit(&mut self, instr: Instr, line: usize) -> usize {
+        self.code.push(instr);
+        self.lines.push(line);
+        self.code.len() - 1
+    }
+
+    // Points a jump at the next instruction to be emitted
+    fn patch(&mut self, at: usize) {
+        let next = self.code.len();
+        match self.code[at] {
+            Instr::JumpUnless(_, _, _, ref mut target) | Instr::Jump(ref mut target) => {
+                *target = next
+            }
+            _ => {}
+        }
+    }
+
+    fn parse_line(
+        &mut self,
+        tokens: &[Token],
+        blocks: &mut Vec<Block>,
+        line: usize,
+    ) -> Result<(), String> {
+        let keyword = match tokens[0] {
+            Token::Word(ref word) => word.as_str(),
+            Token::Text(_) => return Err("expected a command, not text".to_string()),
+        };
+        let rest = &tokens[1..];
+        match keyword {
+            "on" => {
+                if !blocks.is_empty() {
+                    return Err("handlers can't be inside other blocks".to_string());
+                }
+                let event = match (rest.first().map(word), rest.len()) {
+                    (Some(Ok("start")), 1) => ScriptEvent::Start,
+                    (Some(Ok("tick")), 1) => ScriptEvent::Tick,
+                    (Some(Ok("enter")), 1) => ScriptEvent::Enter,
+                    (Some(Ok("exit")), 1) => ScriptEvent::Exit,
+                    (Some(Ok("touch")), 1) => ScriptEvent::Touch,
+                    (Some(Ok("timer")), 2) => ScriptEvent::Timer(name(&rest[1])?.to_string()),
+                    _ => {
+                        return Err(
+                            "expected start, tick, enter, exit, touch or timer <name>".to_string()
+                        )
+                    }
+                };
+                if self.handlers.iter().any(|(e, _)| *e == event) {
+                    return Err(format!("{:?} is handled twice", event));
+                }
+                self.handlers.push((event, self.code.len()));
+                blocks.push(Block::Handler);
+                return Ok(());
+            }
+            "end" => {
+                args(rest, 0)?;
+                match blocks.pop() {
+                    Some(Block::Handler) => {
+                        self.emit(Instr::End, line);
+                    }
+                    Some(Block::If(jump, None)) => self.patch(jump),
+                    Some(Block::If(_, Some(skip))) => self.patch(skip),
+                    Some(Block::While(jump)) => {
+                        self.emit(Instr::Jump(jump), line);
+                        self.patch(jump);
+                    }
+                    None => return Err("end without a block".to_string()),
+                }
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        if blocks.is_empty() {
+            return Err("statements must be inside an on ... end block".to_string());
+        }
+        match keyword {
+            "if" | "while" => {
+                let args = args(rest, 3)?;
+                let cmp = match word(&args[1])? {
+                    "==" => Cmp::Eq,
+                    "!=" => Cmp::Ne,
+                    "<" => Cmp::Lt,
+                    ">" => Cmp::Gt,
+                    "<=" => Cmp::Le,
+                    ">=" => Cmp::Ge,
+                    other => return Err(format!("unknown comparison {}", other)),
+                };
+                let instr = Instr::JumpUnless(value(&args[0])?, cmp, value(&args[2])?, 0);
+                let jump = self.emit(instr, line);
+                blocks.push(if keyword == "if" {
+                    Block::If(jump, None)
+                } else {
+                    Block::While(jump)
+                });
+            }
+            "else" => {
+                args(rest, 0)?;
+                match blocks.pop() {
+                    Some(Block::If(jump, None)) => {
+                        let skip = self.emit(Instr::Jump(0), line);
+                        self.patch(jump);
+                        blocks.push(Block::If(jump, Some(skip)));
+                    }
+                    _ => return Err("else without an if".to_string()),
+                }
+            }
+            "set" => {
+                let var = match rest.first() {
+                    Some(token) => name(token)?,
+                    None => return Err("set needs a variable".to_string()),
+                };
+                if BUILTINS.contains(&var) {
+                    return Err(format!("{} can't be set", var));
+                }
+                let expr = &rest[1..];
+                let op = match expr.len() {
+                    1 => None,
+                    3 => {
+                        let op = match word(&expr[1])? {
+                            "+" => Op::Add,
+                            "-" => Op::Sub,
+                            "*" => Op::Mul,
+                            "/" => Op::Div,
+                            "%" => Op::Rem,
+                            other => return Err(format!("unknown operator {}", other)),
+                        };
+                        Some((op, value(&expr[2])?))
+                    }
+                    _ => {
+                        return Err(
+                            "expected a value or a value, an operator and a value".to_string()
+                        )
+                    }
+                };
+                self.emit(Instr::Set(var.to_string(), value(&expr[0])?, op), line);
+            }
+            "wait" => {
+                let args = args(rest, 1)?;
+                self.emit(Instr::Wait(value(&args[0])?), line);
+            }
+            "start_timer" => {
+                let args = args(rest, 2)?;
+                let instr = Instr::StartTimer(name(&args[0])?.to_string(), value(&args[1])?);
+                self.emit(instr, line);
+            }
+            _ => {
+                let call = parse_call(keyword, rest)?;
+                self.emit(Instr::Call(call), line);
+            }
+        }
+        Ok(())
+    }
+
+    fn handler(&self, event: &ScriptEvent) -> Option<usize> {
+        self.handlers.iter().position(|(e, _)| e == event)
+    }
+}
+
+fn parse_call(keyword: &str, rest: &[Token]) -> Result<Call, String> {
+    let call = match keyword {
+        "spawn_enemy" => {
+            let args = args(rest, 2)?;
+            Call::SpawnEnemy(value(&args[0])?, value(&args[1])?)
+        }
+        "set_tile" => {
+            let args = args(rest, 3)?;
+            Call::SetTile(value(&args[0])?, value(&args[1])?, value(&args[2])?)
+        }
+        "play_sound" => {
+            let sound = name(&args(rest, 1)?[0])?;
+            match Sfx::from_name(sound) {
+                Some(sfx) => Call::PlaySound(sfx),
+                None => return Err(format!("unknown sound {}", sound)),
+            }
+        }
+        "message" => {
+            let text = match rest.first() {
+                Some(Token::Text(text)) => text.clone(),
+                _ => return Err("message needs text in quotes".to_string()),
+            };
+            let ticks = match rest.len() {
+                1 => Value::Number(MESSAGE_TICKS as i32),
+                2 => value(&rest[1])?,
+                _ => return Err("expected text and how many ticks to show it".to_string()),
+            };
+            Call::Message(text, ticks)
+        }
+        "teleport" => {
+            let args = args(rest, 2)?;
+            Call::Teleport(value(&args[0])?, value(&args[1])?)
+        }
+        "move" => {
+            let args = args(rest, 2)?;
+            Call::Move(value(&args[0])?, value(&args[1])?)
+        }
+        "remove" => {
+            args(rest, 0)?;
+            Call::Remove
+        }
+        other => return Err(format!("unknown command {}", other)),
+    };
+    Ok(call)
+}
+
+// Splits a line into words and quoted text, dropping comments
+fn tokenize(line: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '#' {
+            break;
+        } else if c == '"' {
+            chars.next();
+            let mut text = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some(c) => text.push(c),
+                    None => return Err("text is missing its closing quote".to_string()),
+                }
+            }
+            tokens.push(Token::Text(text));
+        } else {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == '"' || c == '#' {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Word(word));
+        }
+    }
+    Ok(tokens)
+}
+
+fn args(tokens: &[Token], count: usize) -> Result<&[Token], String> {
+    if tokens.len() == count {
+        Ok(tokens)
+    } else {
+        Err(format!(
+            "expected {} arguments, got {}",
+            count,
+            tokens.len()
+        ))
+    }
+}
+
+fn word(token: &Token) -> Result<&str, String> {
+    match *token {
+        Token::Word(ref word) => Ok(word),
+        Token::Text(ref text) => Err(format!("unexpected text \"{}\"", text)),
+    }
+}
+
+fn name(token: &Token) -> Result<&str, String> {
+    let name = word(token)?;
+    let valid = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_alphanumeric() || c == '_');
+    if valid {
+        Ok(name)
+    } else {
+        Err(format!("{} is not a valid name", name))
+    }
+}
+
+fn value(token: &Token) -> Result<Value, String> {
+    let text = word(token)?;
+    match text.parse::<i32>() {
+        Ok(number) => Ok(Value::Number(number)),
+        Err(_) => name(token).map(|name| Value::Var(name.to_string())),
+    }
+}
+
+// A handler running, possibly waiting to carry on in a later tick
+#[derive(Serialize, Deserialize, Clone)]
+struct Thread {
+    handler: usize,
+    pc: usize,
+    wait: u32,
+    player: Option<usize>, // Player that set the handler off, if any
+}
+
+/// Variables and running handlers of one zone or entity, kept in snapshots
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ScriptState {
+    pub path: String,
+    vars: BTreeMap<String, i32>,
+    threads: Vec<Thread>,
+    timers: BTreeMap<String, u32>,
+    failed: bool, // Stopped by an error until the level restarts
+}
+
+impl ScriptState {
+    pub fn new(path: &str) -> ScriptState {
+        ScriptState {
+            path: path.to_string(),
+            vars: BTreeMap::new(),
+            threads: Vec::new(),
+            timers: BTreeMap::new(),
+            failed: false,
+        }
+    }
+
+    /// Starts the handler for an event, unless it is still running from an earlier one
+    pub fn fire(&mut self, script: &Script, event: &ScriptEvent, player: Option<usize>) {
+        let handler = match script.handler(event) {
+            Some(handler) => handler,
+            None => return,
+        };
+        if self.failed || self.threads.iter().any(|thread| thread.handler == handler) {
+            return;
+        }
+        self.threads.push(Thread {
+            handler,
+            pc: script.handlers[handler].1,
+            wait: 0,
+            player,
+        });
+    }
+
+    /// Runs the handlers until they finish or wait, at most `SCRIPT_BUDGET` instructions
+    /// between them. An error stops the script and is returned to be shown.
+    pub fn update(&mut self, script: &Script, env: &[(&str, i32)]) -> Result<Vec<Command>, String> {
+        let mut commands = Vec::new();
+        if self.failed {
+            return Ok(commands);
+        }
+
+        let mut expired = Vec::new();
+        for (name, ticks) in &mut self.timers {
+            *ticks -= 1;
+            if *ticks == 0 {
+                expired.push(name.clone());
+            }
+        }
+        for name in expired {
+            self.timers.remove(&name);
+            self.fire(script, &ScriptEvent::Timer(name), None);
+        }
+
+        let mut budget = SCRIPT_BUDGET;
+        let threads = mem::take(&mut self.threads);
+        let mut result = Ok(());
+        let mut running = Vec::new();
+        for mut thread in threads {
+            if thread.wait > 0 {
+                thread.wait -= 1;
+                if thread.wait > 0 {
+                    running.push(thread);
+                    continue;
+                }
+            }
+            match self.run(script, &mut thread, env, &mut budget, &mut commands) {
+                Ok(true) => {}
+                Ok(false) => running.push(thread),
+                Err(e) => {
+                    result = Err(format!(
+                        "{} line {}: {}",
+                        self.path, script.lines[thread.pc], e
+                    ));
+                    break;
+                }
+            }
+        }
+        match result {
+            Ok(()) => {
+                self.threads = running;
+                Ok(commands)
+            }
+            Err(e) => {
+                self.failed = true;
+                self.timers.clear();
+                Err(e)
+            }
+        }
+    }
+
+    // Runs a thread until it ends, giving true, or waits, giving false
+    fn run(
+        &mut self,
+        script: &Script,
+        thread: &mut Thread,
+        env: &[(&str, i32)],
+        budget: &mut u32,
+        commands: &mut Vec<Command>,
+    ) -> Result<bool, String> {
+        loop {
+            if *budget == 0 {
+                return Err("ran out of instructions, is a loop missing a wait?".to_string());
+            }
+            *budget -= 1;
+            match script.code[thread.pc] {
+                Instr::Set(ref var, ref a, ref op) => {
+                    let mut result = self.value(a, thread, env)?;
+                    if let Some((op, ref b)) = *op {
+                        result = apply(op, result, self.value(b, thread, env)?)?;
+                    }
+                    self.vars.insert(var.clone(), result);
+                }
+                Instr::JumpUnless(ref a, cmp, ref b, target) => {
+                    let (a, b) = (self.value(a, thread, env)?, self.value(b, thread, env)?);
+                    let holds = match cmp {
+                        Cmp::Eq => a == b,
+                        Cmp::Ne => a != b,
+                        Cmp::Lt => a < b,
+                        Cmp::Gt => a > b,
+                        Cmp::Le => a <= b,
+                        Cmp::Ge => a >= b,
+                    };
+                    if !holds {
+                        thread.pc = target;
+                        continue;
+                    }
+                }
+                Instr::Jump(target) => {
+                    thread.pc = target;
+                    continue;
+                }
+                Instr::Wait(ref ticks) => {
+                    let ticks = self.value(ticks, thread, env)?;
+                    thread.pc += 1;
+                    if ticks > 0 {
+                        thread.wait = ticks as u32;
+                        return Ok(false);
+                    }
+                    continue;
+                }
+                Instr::StartTimer(ref name, ref ticks) => {
+                    let ticks = self.value(ticks, thread, env)?.max(1) as u32;
+                    self.timers.insert(name.clone(), ticks);
+                }
+                Instr::Call(ref call) => commands.push(self.call(call, thread, env)?),
+                Instr::End => return Ok(true),
+            }
+            thread.pc += 1;
+        }
+    }
+
+    fn call(&self, call: &Call, thread: &Thread, env: &[(&str, i32)]) -> Result<Command, String> {
+        let value = |v: &Value| self.value(v, thread, env);
+        let command = match *call {
+            Call::SpawnEnemy(ref x, ref y) => Command::SpawnEnemy {
+                x: value(x)?,
+                y: value(y)?,
+            },
+            Call::SetTile(ref x, ref y, ref id) => Command::SetTile {
+                x: value(x)?,
+                y: value(y)?,
+                id: value(id)?,
+            },
+            Call::PlaySound(sfx) => Command::PlaySound(sfx),
+            Call::Message(ref text, ref ticks) => Command::Message {
+                text: text.clone(),
+                ticks: value(ticks)?.max(1) as u32,
+            },
+            Call::Teleport(ref x, ref y) => Command::Teleport {
+                player: thread.player,
+                x: value(x)?,
+                y: value(y)?,
+            },
+            Call::Move(ref x, ref y) => Command::Move {
+                x: value(x)?,
+                y: value(y)?,
+            },
+            Call::Remove => Command::Remove,
+        };
+        Ok(command)
+    }
+
+    fn value(&self, value: &Value, thread: &Thread, env: &[(&str, i32)]) -> Result<i32, String> {
+        let name = match *value {
+            Value::Number(number) => return Ok(number),
+            Value::Var(ref name) => name,
+        };
+        if name == "player" {
+            return Ok(thread.player.map_or(-1, |player| player as i32));
+        }
+        env.iter()
+            .find(|&&(var, _)| var == name)
+            .map(|&(_, value)| value)
+            .or_else(|| self.vars.get(name).cloned())
+            .ok_or_else(|| format!("{} is not set", name))
+    }
+
+    pub fn checksum(&self, sum: &mut Checksum) {
+        for (name, value) in &self.vars {
+            sum.write_str(name);
+            sum.write_u32(*value as u32);
+        }
+        for thread in &self.threads {
+            sum.write_u32(thread.pc as u32);
+            sum.write_u32(thread.wait);
+        }
+        for (name, ticks) in &self.timers {
+            sum.write_str(name);
+            sum.write_u32(*ticks);
+        }
+        sum.write_bool(self.failed);
+    }
+}
+
+fn apply(op: Op, a: i32, b: i32) -> Result<i32, String> {
+    if b == 0 && matches!(op, Op::Div | Op::Rem) {
+        return Err("division by zero".to_string());
+    }
+    Ok(match op {
+        Op::Add => a.wrapping_add(b),
+        Op::Sub => a.wrapping_sub(b),
+        Op::Mul => a.wrapping_mul(b),
+        Op::Div => a.wrapping_div(b),
+        Op::Rem => a.wrapping_rem(b),
+    })
+}
+
+/// A script zone in play
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Zone {
+    pub bbox: BBox,
+    pub players: Occupancy,
+    pub script: ScriptState,
+}
+
+/// Component of the entities a script runs
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Scripted {
+    pub state: ScriptState,
+    pub touching: bool, // A player touched it last tick
+}
+
+impl Scripted {
+    pub fn new(path: &str) -> Scripted {
+        Scripted {
+            state: ScriptState::new(path),
+            touching: false,
+        }
+    }
+}
+
+// Tile a position in the world is in, for the `x` and `y` scripts read
+fn tile_at(pos: Vec2) -> (i32, i32) {
+    let tile = |v| (to_f32(v) / TILE_SIZE).floor() as i32;
+    (tile(pos.x), tile(pos.y))
+}
+
+// What a command acts on
+#[derive(Clone, Copy)]
+enum Owner {
+    Zone,
+    Entity(usize),
+}
+
+/// Compiles every script a map uses. Scripts that fail to load are reported and left out,
+/// so whatever uses them does nothing.
+pub fn load_scripts<L: Loader>(
+    loader: &mut L,
+    map: &Map,
+) -> (HashMap<String, Script>, Vec<String>) {
+    let mut scripts = HashMap::new();
+    let mut errors = Vec::new();
+    let mut failed = HashSet::new();
+    let paths = map.scripts.iter().map(|zone| &zone.script);
+    for path in paths.chain(map.entities.iter().map(|entity| &entity.script)) {
+        if scripts.contains_key(path) || failed.contains(path) {
+            continue;
+        }
+        match loader.load_script(path) {
+            Ok(script) => {
+                scripts.insert(path.clone(), script);
+            }
+            Err(e) => {
+                println!("Failed to load script {}", e);
+                failed.insert(path.clone());
+                errors.push(e);
+            }
+        }
+    }
+    (scripts, errors)
+}
+
+impl Game {
+    /// Compiles the scripts of the current map, replacing the errors shown
+    pub fn load_scripts<L: Loader>(&mut self, loader: &mut L) {
+        let (scripts, errors) = load_scripts(loader, self.map.as_ref().unwrap());
+        self.scripts = scripts;
+        self.script_errors = errors;
+    }
+
+    /// Puts the zones back the way the map places them and starts the scripts of the
+    /// zones and entities
+    pub fn reset_scripts(&mut self) {
+        let map = self.map.as_ref().unwrap();
+        self.zones = map
+            .scripts
+            .iter()
+            .map(|zone| Zone {
+                bbox: BBox::new(
+                    scalar(zone.tile.0 as f32 * TILE_SIZE),
+                    scalar(zone.tile.1 as f32 * TILE_SIZE),
+                    scalar(zone.size.0 as f32 * TILE_SIZE),
+                    scalar(zone.size.1 as f32 * TILE_SIZE),
+                ),
+                players: Occupancy::default(),
+                script: ScriptState::new(&zone.script),
+            })
+            .collect();
+        self.message = None;
+
+        let scripts = &self.scripts;
+        let states = self.zones.iter_mut().map(|zone| &mut zone.script);
+        let entities = self.world.entities.iter_mut().filter_map(|e| e.script.as_mut());
+        for state in states.chain(entities.map(|scripted| &mut scripted.state)) {
+            if let Some(script) = scripts.get(&state.path) {
+                state.fire(script, &ScriptEvent::Start, None);
+            }
+        }
+    }
+
+    /// Sets off the handlers of zones and entities for what the players did this tick, runs
+    /// the scripts and carries out what they ask for
+    pub fn update_scripts(&mut self) {
+        if let Some((_, ref mut ticks)) = self.message {
+            *ticks -= 1;
+        }
+        if self.message.as_ref().is_some_and(|&(_, ticks)| ticks == 0) {
+            self.message = None;
+        }
+
+        let players: Vec<(usize, BBox)> = self
+            .players
+            .iter()
+            .filter(|player| !player.dead)
+            .map(|player| (player.index, player.obj.get_bbox()))
+            .collect();
+        let clock = self.clock as i32;
+        let mut commands = Vec::new();
+        let mut errors = Vec::new();
+
+        for zone in &mut self.zones {
+            let script = match self.scripts.get(&zone.script.path) {
+                Some(script) => script,
+                None => continue,
+            };
+            // Dying counts as leaving
+            let changes = zone.players.update(&zone.bbox, &players);
+            for player in changes.entered {
+                zone.script.fire(script, &ScriptEvent::Enter, Some(player));
+            }
+            for player in changes.left {
+                zone.script.fire(script, &ScriptEvent::Exit, Some(player));
+            }
+            zone.script.fire(script, &ScriptEvent::Tick, None);
+
+            let (x, y) = tile_at(zone.bbox.pos);
+            match zone
+                .script
+                .update(script, &[("clock", clock), ("x", x), ("y", y)])
+            {
+                Ok(zone_commands) => {
+                    commands.extend(zone_commands.into_iter().map(|c| (Owner::Zone, c)))
+                }
+                Err(e) => errors.push(e),
+            }
+        }
+
+        for (i, entity) in self.world.entities.iter_mut().enumerate() {
+            let bbox = match entity.bbox() {
+                Some(bbox) => bbox,
+                None => continue,
+            };
+            let scripted = match entity.script.as_mut() {
+                Some(scripted) => scripted,
+                None => continue,
+            };
+            let script = match self.scripts.get(&scripted.state.path) {
+                Some(script) => script,
+                None => continue,
+            };
+            let touching = players.iter().find(|(_, other)| bbox.intersects(other));
+            if let (Some(&(player, _)), false) = (touching, scripted.touching) {
+                scripted
+                    .state
+                    .fire(script, &ScriptEvent::Touch, Some(player));
+            }
+            scripted.touching = touching.is_some();
+            scripted.state.fire(script, &ScriptEvent::Tick, None);
+
+            let (x, y) = tile_at(bbox.pos);
+            match scripted
+                .state
+                .update(script, &[("clock", clock), ("x", x), ("y", y)])
+            {
+                Ok(entity_commands) => {
+                    commands.extend(entity_commands.into_iter().map(|c| (Owner::Entity(i), c)))
+                }
+                Err(e) => errors.push(e),
+            }
+        }
+
+        for (owner, command) in commands {
+            if let Err(e) = self.apply_command(owner, command) {
+                errors.push(e);
+            }
+        }
+        self.world.maintain();
+        for error in errors {
+            self.script_error(error);
+        }
+    }
+
+    fn apply_command(&mut self, owner: Owner, command: Command) -> Result<(), String> {
+        match command {
+            Command::SpawnEnemy { x, y } => {
+                if self.world.entities.len() >= MAX_ENTITIES {
+                    return Err(format!("too many entities to spawn another at {} {}", x, y));
+                }
+                let pos = Vec2::from_f32(x as f32 * TILE_SIZE, y as f32 * TILE_SIZE);
+                self.world.spawn(ecs::goomba(pos));
+            }
+            Command::SetTile { x, y, id } => {
+                let dimensions = self.map.as_ref().unwrap().dimensions;
+                let (width, height) = (dimensions.0 as i32, dimensions.1 as i32);
+                if x < 0 || y < 0 || x >= width || y >= height {
+                    return Err(format!("tile {} {} is outside the map", x, y));
+                }
+                let id = if id < 0 {
+                    EMPTY_SPRITE
+                } else if (id as usize) < self.tile_count {
+                    id as usize
+                } else {
+                    return Err(format!("there is no tile {}", id));
+                };
+                self.map_mut().layer_mut(LayerKind::Main).tiles[(y * width + x) as usize].id = id;
+            }
+            Command::PlaySound(sfx) => self.events.push(GameEvent::SoundPlayed { sfx }),
+            Command::Message { text, ticks } => self.message = Some((text, ticks)),
+            Command::Teleport { player, x, y } => {
+                let pos = Vec2::from_f32(x as f32 * TILE_SIZE, y as f32 * TILE_SIZE);
+                let players = self
+                    .players
+                    .iter_mut()
+                    .filter(|p| player.is_none_or(|i| p.index == i));
+                for player in players.filter(|player| !player.dead) {
+                    player.obj.transform.pos = pos;
+                    player.obj.velocity.linear = Vec2::new(ZERO, ZERO);
+                }
+            }
+            Command::Move { x, y } => match owner {
+                Owner::Entity(i) => {
+                    if let Some(ref mut transform) = self.world.entities[i].transform {
+                        transform.pos.x += scalar(x as f32);
+                        transform.pos.y += scalar(y as f32);
+                    }
+                }
+                Owner::Zone => return Err("only entities can move".to_string()),
+            },
+            Command::Remove => match owner {
+                Owner::Entity(i) => self.world.despawn(i),
+                Owner::Zone => return Err("only entities can be removed".to_string()),
+            },
+        }
+        Ok(())
+    }
+
+    // Keeps the last few errors to show in the game
+    fn script_error(&mut self, error: String) {
+        println!("Script error: {}", error);
+        self.script_errors.push(error);
+        if self.script_errors.len() > MAX_ERRORS {
+            self.script_errors.remove(0);
+        }
+    }
+}
+
+impl MainState {
+    /// Outlines the script zones while editing
+    pub fn draw_zones(&mut self, ctx: &mut Context) -> GameResult<()> {
+        graphics::set_color(ctx, Color::new(0.4, 0.6, 1.0, 1.0))?;
+        for zone in &self.game.map.as_ref().unwrap().scripts {
+            let pos = Point2::new(
+                zone.tile.0 as f32 * TILE_SIZE,
+                zone.tile.1 as f32 * TILE_SIZE,
+            );
+            let dest = self.game.camera.to_screen(pos);
+            let rect = Rect::new(
+                dest.x,
+                dest.y,
+                zone.size.0 as f32 * TILE_SIZE,
+                zone.size.1 as f32 * TILE_SIZE,
+            );
+            graphics::rectangle(ctx, DrawMode::Line(2.0), rect)?;
+            let text = graphics::Text::new(ctx, &zone.script, &self.font)?;
+            graphics::draw(ctx, &text, Point2::new(dest.x + 4.0, dest.y + 4.0), 0.0)?;
+        }
+        graphics::set_color(ctx, Color::new(1.0, 1.0, 1.0, 1.0))
+    }
+
+    /// Draws the message a script is showing and the latest script errors
+    pub fn draw_script_text(&mut self, ctx: &mut Context) -> GameResult<()> {
+        if let Some((ref message, _)) = self.game.message {
+            let text = graphics::Text::new(ctx, message, &self.font)?;
+            let x = (::SCREEN_WIDTH as f32 - text.width() as f32) / 2.0;
+            graphics::draw(ctx, &text, Point2::new(x, 120.0), 0.0)?;
+        }
+
+        graphics::set_color(ctx, Color::new(1.0, 0.3, 0.3, 1.0))?;
+        for (i, error) in self.game.script_errors.iter().enumerate() {
+            let text = graphics::Text::new(ctx, error, &self.font)?;
+            let y = ::SCREEN_HEIGHT as f32
+                - 24.0
+                - (self.game.script_errors.len() - 1 - i) as f32 * 20.0;
+            graphics::draw(ctx, &text, Point2::new(8.0, y), 0.0)?;
+        }
+        graphics::set_color(ctx, Color::new(1.0, 1.0, 1.0, 1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use game::tests::course;
+
+    fn parse_error(source: &str) -> String {
+        match Script::parse(source) {
+            Ok(_) => panic!("parsed a broken script"),
+            Err(e) => e,
+        }
+    }
+
+    // Starts a script, as the level starting does
+    fn start(source: &str) -> (Script, ScriptState) {
+        let script = Script::parse(source).unwrap();
+        let mut state = ScriptState::new("test");
+        state.fire(&script, &ScriptEvent::Start, None);
+        (script, state)
+    }
+
+    fn update(script: &Script, state: &mut ScriptState) -> Result<Vec<Command>, String> {
+        state.update(script, &[("clock", 0), ("x", 0), ("y", 0)])
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(parse_error("on start\n  set a 1\n"), "is missing an end");
+        assert_eq!(
+            parse_error("on start\n  else\nend\n"),
+            "line 2: else without an if"
+        );
+        assert_eq!(
+            parse_error("on start\n  set x 1\nend\n"),
+            "line 2: x can't be set"
+        );
+    }
+
+    #[test]
+    fn loops_without_a_wait_run_out_of_instructions() {
+        let (script, mut state) = start("on start\n  while 1 == 1\n    set a 1\n  end\nend\n");
+        let error = update(&script, &mut state).err().unwrap();
+        assert!(error.contains("ran out of instructions"), "{}", error);
+        assert!(state.failed);
+        // A failed script stays stopped
+        assert!(update(&script, &mut state).unwrap().is_empty());
+    }
+
+    #[test]
+    fn division_by_zero_stops_the_script() {
+        let (script, mut state) = start("on start\n  set a 1 / 0\nend\n");
+        assert_eq!(
+            update(&script, &mut state).err().unwrap(),
+            "test line 2: division by zero"
+        );
+        assert!(state.failed);
+    }
+
+    #[test]
+    fn wait_carries_on_after_the_ticks() {
+        let (script, mut state) = start("on start\n  set a 1\n  wait 3\n  set a 2\nend\n");
+        for _ in 0..3 {
+            update(&script, &mut state).unwrap();
+            assert_eq!(state.vars["a"], 1);
+        }
+        update(&script, &mut state).unwrap();
+        assert_eq!(state.vars["a"], 2);
+        assert!(state.threads.is_empty());
+    }
+
+    #[test]
+    fn timers_fire_their_handler() {
+        let source =
+            "on start\n  start_timer door 2\nend\non timer door\n  message \"open\"\nend\n";
+        let (script, mut state) = start(source);
+        for _ in 0..2 {
+            assert!(update(&script, &mut state).unwrap().is_empty());
+        }
+        let commands = update(&script, &mut state).unwrap();
+        assert!(matches!(commands[..], [Command::Message { ref text, .. }] if text == "open"));
+        assert!(state.timers.is_empty());
+    }
+
+    #[test]
+    fn spawning_stops_at_the_entity_cap() {
+        let mut game = course();
+        let spawn = || Command::SpawnEnemy { x: 2, y: 2 };
+        while game.world.entities.len() < MAX_ENTITIES {
+            game.apply_command(Owner::Zone, spawn()).unwrap();
+        }
+        assert!(game.apply_command(Owner::Zone, spawn()).is_err());
+        assert_eq!(game.world.entities.len(), MAX_ENTITIES);
+    }
+
+    #[test]
+    fn timers_are_in_the_checksum() {
+        let checksum = |ticks: u32| {
+            let source = format!("on start\n  start_timer door {}\nend\n", ticks);
+            let (script, mut state) = start(&source);
+            update(&script, &mut state).unwrap();
+            let mut sum = Checksum::new();
+            state.checksum(&mut sum);
+            sum.finish()
+        };
+        assert_eq!(checksum(10), checksum(10));
+        assert_ne!(checksum(10), checksum(20));
+    }
+}
@@ -0,0 +1,227 @@
+use ecs::World;
+use game::{Game, Loader};
+use ggez::{Context, GameError, GameResult};
+use map::{Map, LAYERS};
+use math::{Vec2, SCALAR_KIND};
+use platform::Platform;
+use player::Player;
+use profile::StarCoin;
+use script::Zone;
+use state::MainState;
+use stats::Stats;
+use std::io::{Read, Write};
+use std::rc::Rc;
+use toml;
+use trigger::TriggerState;
+use warp::Transition;
+
+pub const SAVE_SLOTS: usize = 4;
+const SCALAR_KEY: &str = "scalar"; // Kind of scalar the file was saved with
+
+/// Copy of everything the simulation depends on, to go back to later
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Snapshot {
+    clock: u64,
+    time_left: u32,
+    // Shared with the game and the other snapshots until the map changes, which is
+    // rare, so a snapshot per tick doesn't copy every tile
+    map: Rc<Map>,
+    map_path: String,
+    level: String,
+    level_ticks: u32,
+    players: Vec<Player>,
+    world: World,
+    platforms: Vec<Platform>,
+    stats: Vec<Stats>,
+    warp: Option<Transition>,
+    star_coins: Vec<StarCoin>,
+    zones: Vec<Zone>,
+    message: Option<(String, u32)>,
+    triggers: Vec<TriggerState>,
+    camera_locked: bool,
+    // A locked camera holds the players in, so where it is matters to the simulation
+    #[serde(default)]
+    camera: Vec2,
+    music: Option<String>,
+}
+
+impl Snapshot {
+    /// Tick the snapshot was taken at
+    pub fn clock(&self) -> u64 {
+        self.clock
+    }
+
+    /// File in the user directory a save slot is kept in
+    pub fn slot_path(slot: usize) -> String {
+        format!("/savestate_{}.toml", slot + 1)
+    }
+
+    pub fn load(ctx: &mut Context, path: &str) -> GameResult<Snapshot> {
+        let mut file = ctx.filesystem.open(path)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        // Positions and velocities are stored as the bits of whatever Scalar is, which
+        // mean something else in a build with the other kind
+        let mut value: toml::Value = toml::from_str(&contents)?;
+        let kind = value
+            .as_table_mut()
+            .and_then(|table| table.remove(SCALAR_KEY));
+        match kind.as_ref().and_then(|kind| kind.as_str()) {
+            Some(kind) if kind == SCALAR_KIND => {}
+            kind => {
+                return Err(GameError::ResourceLoadError(format!(
+                    "{} was saved with {} scalars, this build uses {}",
+                    path,
+                    kind.unwrap_or("unknown"),
+                    SCALAR_KIND
+                )))
+            }
+        }
+        let snapshot: Snapshot = value.try_into()?;
+
+        let map = &snapshot.map;
+        let size = (map.dimensions.0 * map.dimensions.1) as usize;
+        if map.layers.len() != LAYERS.len() || map.layers.iter().any(|l| l.tiles.len() != size) {
+            return Err(GameError::ResourceLoadError(format!(
+                "Map in {} does not match its dimensions",
+                path
+            )));
+        }
+        Ok(snapshot)
+    }
+
+    /// Saves the snapshot as TOML to the user directory
+    pub fn save(&self, ctx: &mut Context, path: &str) -> GameResult<()> {
+        // Going through a TOML value puts plain values before tables, as TOML requires
+        let mut value = toml::Value::try_from(self)?;
+        if let Some(table) = value.as_table_mut() {
+            table.insert(SCALAR_KEY.to_string(), toml::Value::from(SCALAR_KIND));
+        }
+        let contents = toml::to_string(&value)?;
+        let mut file = ctx.filesystem.create(path)?;
+        file.write_all(contents.as_bytes())?;
+        Ok(())
+    }
+}
+
+impl Game {
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            clock: self.clock,
+            time_left: self.time_left,
+            map: Rc::clone(self.map.as_ref().unwrap()),
+            map_path: self.map_path.clone(),
+            level: self.level.clone(),
+            level_ticks: self.level_ticks,
+            players: self.players.clone(),
+            world: self.world.clone(),
+            platforms: self.platforms.clone(),
+            stats: self.stats.clone(),
+            warp: self.warp.clone(),
+            star_coins: self.star_coins.clone(),
+            zones: self.zones.clone(),
+            message: self.message.clone(),
+            triggers: self.triggers.clone(),
+            camera_locked: self.camera_locked,
+            camera: self.camera.pos,
+            music: self.music.clone(),
+        }
+    }
+
+    /// Puts the world back the way it was when the snapshot was taken
+    pub fn restore<L: Loader>(&mut self, loader: &mut L, snapshot: &Snapshot) {
+        // The snapshot may be from before going through a warp to another map
+        if snapshot.map_path != self.map_path {
+            self.map_path = snapshot.map_path.clone();
+            self.map = Some(Rc::clone(&snapshot.map));
+            self.load_scripts(loader);
+        }
+        self.clock = snapshot.clock;
+        self.time_left = snapshot.time_left;
+        self.level = snapshot.level.clone();
+        self.level_ticks = snapshot.level_ticks;
+        self.map = Some(Rc::clone(&snapshot.map));
+        self.players = snapshot.players.clone();
+        self.world = snapshot.world.clone();
+        self.platforms = snapshot.platforms.clone();
+        self.stats = snapshot.stats.clone();
+        self.warp = snapshot.warp.clone();
+        self.star_coins = snapshot.star_coins.clone();
+        self.zones = snapshot.zones.clone();
+        self.message = snapshot.message.clone();
+        self.triggers = snapshot.triggers.clone();
+        self.camera_locked = snapshot.camera_locked;
+        self.camera.pos = snapshot.camera;
+        self.music = snapshot.music.clone();
+    }
+}
+
+impl MainState {
+    /// Saves the world to the selected slot, in memory and on disk
+    pub fn quicksave(&mut self, ctx: &mut Context) {
+        let snapshot = self.game.snapshot();
+        let path = Snapshot::slot_path(self.save_slot);
+        match snapshot.save(ctx, &path) {
+            Ok(()) => println!("Saved to slot {}", self.save_slot + 1),
+            Err(e) => println!(
+                "Saved to slot {} in memory only, writing {} failed: {}",
+                self.save_slot + 1,
+                path,
+                e
+            ),
+        }
+        self.save_slots[self.save_slot] = Some(snapshot);
+    }
+
+    /// Loads the world from the selected slot, from disk if it isn't in memory
+    pub fn quickload(&mut self, ctx: &mut Context) {
+        if self.save_slots[self.save_slot].is_none() {
+            let path = Snapshot::slot_path(self.save_slot);
+            if !ctx.filesystem.exists(&path) {
+                println!("Slot {} is empty", self.save_slot + 1);
+                return;
+            }
+            match Snapshot::load(ctx, &path) {
+                Ok(snapshot) => self.save_slots[self.save_slot] = Some(snapshot),
+                Err(e) => {
+                    println!("Failed to load {}: {}", path, e);
+                    return;
+                }
+            }
+        }
+
+        let snapshot = self.save_slots[self.save_slot].take().unwrap();
+        self.game.restore(ctx, &snapshot);
+        println!("Loaded slot {}", self.save_slot + 1);
+        self.save_slots[self.save_slot] = Some(snapshot);
+    }
+
+    pub fn next_save_slot(&mut self) {
+        self.save_slot = (self.save_slot + 1) % SAVE_SLOTS;
+        println!("Save slot {} selected", self.save_slot + 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use game::tests::{course, Scripts};
+    use map::{LayerKind, BRICK_BLOCK};
+    use player::Buttons;
+
+    #[test]
+    fn snapshots_share_the_map_until_it_changes() {
+        let mut game = course();
+        let snapshot = game.snapshot();
+        let held = [Buttons::default(); 2];
+        game.simulate(&mut Scripts, &held).unwrap();
+        assert!(Rc::ptr_eq(&snapshot.map, game.map.as_ref().unwrap()));
+
+        game.map_mut().layer_mut(LayerKind::Main).tiles[0].id = BRICK_BLOCK;
+        assert!(!Rc::ptr_eq(&snapshot.map, game.map.as_ref().unwrap()));
+        assert_ne!(snapshot.map.layer(LayerKind::Main).tiles[0].id, BRICK_BLOCK);
+
+        game.restore(&mut Scripts, &snapshot);
+        assert!(Rc::ptr_eq(&snapshot.map, game.map.as_ref().unwrap()));
+    }
+}
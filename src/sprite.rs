@@ -1,44 +1,180 @@
+use ggez::{Context, GameError, GameResult};
 use ggez::graphics;
 use graphics::Point2;
+use std::collections::HashMap;
+use std::io::Read;
+use toml;
 
-pub const PLAYER_SPRITE_OFFSET: (f32, f32) = (80.0, 2.0);
-pub const SPRITES_PER_ROW: usize = 33;
-pub const SPRITE_SIZE: f32 = 16.0;
 pub const SCALE_FACTOR: f32 = 2.0;
 pub const EMPTY_SPRITE: usize = 100000;
 
-/// Gets the location of the sprite based on its ID
-pub fn id_to_player_sprite(index: usize) -> Point2 {
-    let x = PLAYER_SPRITE_OFFSET.0 + ((index % 21 as usize) as f32 * 17.0);
-    let y = PLAYER_SPRITE_OFFSET.1 + ((index / 21 as usize) as f32 * 16.0);
-    Point2::new(x, y)
+/// Location of a sprite on its sheet, in pixels.
+/// The pivot is the point of the frame placed at the draw position.
+#[derive(Deserialize, Clone)]
+pub struct Frame {
+    pub x: f32,
+    pub y: f32,
+    pub w: f32,
+    pub h: f32,
+    #[serde(default)]
+    pub pivot: (f32, f32),
 }
 
-/// Creates the parameters needed to draw the sprite
+/// A regular grid of frames named `<prefix><n>`, numbered row by row
+#[derive(Deserialize)]
+struct Grid {
+    prefix: String,
+    x: f32,
+    y: f32,
+    w: f32,
+    h: f32,
+    stride: (f32, f32),
+    columns: usize,
+    count: usize,
+}
+
+#[derive(Deserialize)]
+struct AtlasFile {
+    image: String,
+    #[serde(default)]
+    grid: Vec<Grid>,
+    #[serde(default)]
+    frames: HashMap<String, Frame>,
+}
+
+/// Colors to replace when recoloring a sprite sheet, as RGB values
+#[derive(Deserialize)]
+pub struct Palette {
+    swaps: Vec<ColorSwap>,
+}
+
+#[derive(Deserialize)]
+struct ColorSwap {
+    from: (u8, u8, u8),
+    to: (u8, u8, u8),
+}
+
+impl Palette {
+    pub fn load(ctx: &mut Context, path: &str) -> GameResult<Palette> {
+        let mut file = ctx.filesystem.open(path)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        Ok(toml::from_str(&contents)?)
+    }
+}
+
+/// Sprite sheet together with the named frames on it, loaded from an atlas descriptor
+pub struct Atlas {
+    pub image: graphics::Image,
+    frames: Vec<Frame>,
+    names: HashMap<String, usize>,
+}
+
+impl Atlas {
+    pub fn load(ctx: &mut Context, path: &str) -> GameResult<Atlas> {
+        let mut file = ctx.filesystem.open(path)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let file: AtlasFile = toml::from_str(&contents)?;
+
+        let mut image = graphics::Image::new(ctx, &file.image)?;
+        image.set_filter(graphics::FilterMode::Nearest);
+
+        // Grid frames come first so their numbering matches the tile ids used by maps
+        let mut frames = Vec::new();
+        let mut names = HashMap::new();
+        for grid in &file.grid {
+            if grid.columns == 0 {
+                return Err(GameError::ResourceLoadError(format!(
+                    "Grid {} in {} needs at least one column",
+                    grid.prefix, path
+                )));
+            }
+            for n in 0..grid.count {
+                names.insert(format!("{}{}", grid.prefix, n), frames.len());
+                frames.push(Frame {
+                    x: grid.x + (n % grid.columns) as f32 * grid.stride.0,
+                    y: grid.y + (n / grid.columns) as f32 * grid.stride.1,
+                    w: grid.w,
+                    h: grid.h,
+                    pivot: (0.0, 0.0),
+                });
+            }
+        }
+        for (name, frame) in file.frames {
+            if names.contains_key(&name) {
+                return Err(GameError::ResourceLoadError(format!(
+                    "Frame {} is defined twice in {}",
+                    name, path
+                )));
+            }
+            names.insert(name, frames.len());
+            frames.push(frame);
+        }
+
+        Ok(Atlas {
+            image,
+            frames,
+            names,
+        })
+    }
+
+    /// Copy of the atlas with the colors of its image swapped, e.g. to draw another player
+    pub fn recolor(&self, ctx: &mut Context, palette: &Palette) -> GameResult<Atlas> {
+        let mut pixels = self.image.to_rgba8(ctx)?;
+        for pixel in pixels.chunks_mut(4) {
+            let color = (pixel[0], pixel[1], pixel[2]);
+            if let Some(swap) = palette.swaps.iter().find(|swap| swap.from == color) {
+                pixel[0] = swap.to.0;
+                pixel[1] = swap.to.1;
+                pixel[2] = swap.to.2;
+            }
+        }
+        let (width, height) = (self.image.width() as u16, self.image.height() as u16);
+        let mut image = graphics::Image::from_rgba8(ctx, width, height, &pixels)?;
+        image.set_filter(graphics::FilterMode::Nearest);
+        Ok(Atlas {
+            image,
+            frames: self.frames.clone(),
+            names: self.names.clone(),
+        })
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn frame(&self, index: usize) -> Option<&Frame> {
+        self.frames.get(index)
+    }
+
+    pub fn index(&self, name: &str) -> Option<usize> {
+        self.names.get(name).cloned()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Frame> {
+        self.index(name).and_then(|index| self.frame(index))
+    }
+}
+
+/// Creates the parameters needed to draw the frame from the image it belongs to
 pub fn create_sprite_param(
-    index: usize,
+    frame: &Frame,
     dest: graphics::Point2,
     image: &graphics::Image,
-    player_sprite: bool,
 ) -> graphics::DrawParam {
-    let (x, y);
-    if player_sprite {
-        let offset = id_to_player_sprite(index);
-        x = offset.x as u32;
-        y = offset.y as u32;
-    } else {
-        x = (SPRITE_SIZE * (index % SPRITES_PER_ROW) as f32) as u32;
-        y = (SPRITE_SIZE * (index / SPRITES_PER_ROW) as f32) as u32;
-    }
     graphics::DrawParam {
         src: graphics::Rect::fraction(
-            x as f32,
-            y as f32,
-            SPRITE_SIZE,
-            SPRITE_SIZE,
+            frame.x,
+            frame.y,
+            frame.w,
+            frame.h,
             &image.get_dimensions(),
         ),
-        dest,
+        dest: Point2::new(
+            dest.x - frame.pivot.0 * SCALE_FACTOR,
+            dest.y - frame.pivot.1 * SCALE_FACTOR,
+        ),
         rotation: 0.0,
         scale: graphics::Point2::new(SCALE_FACTOR, SCALE_FACTOR),
         offset: graphics::Point2::new(0.0, 0.0),
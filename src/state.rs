@@ -1,114 +1,507 @@
-use ggez::graphics::{Color, Point2};
-use ggez::{event, graphics, Context, GameResult};
+use animation::{AnimationSet, TileAnimations, PLAYER_ANIMATIONS_PATH, TILE_ANIMATIONS_PATH,
+                TICKS_PER_SECOND};
+use audio::{Audio, Volume};
+use game::{Game, RESPAWN_TICKS};
+use ggez::graphics::{Color, DrawMode, Point2, Rect};
+use ggez::{event, graphics, timer, Context, GameResult};
 use object::Direction;
-use player::Player;
-use enemy::Enemy;
+use player::Buttons;
+use debug::TimeControl;
+use map::{LayerKind, Map, MAP_PATH, TILE_SIZE};
+use math::Vec2;
+use net::{NetConfig, Session};
+use particle::Particles;
+use platform::Motion;
+use physics::PhysicsConfig;
+use profile::{Profile, Slot, PROFILE_SLOTS};
+use rewind::Rewind;
+use screen::Screen;
+use snapshot::{Snapshot, SAVE_SLOTS};
 use sprite;
-use sprite::{EMPTY_SPRITE, SCALE_FACTOR, SPRITE_SIZE};
+use sprite::{Atlas, Palette, EMPTY_SPRITE, SCALE_FACTOR};
+use std::collections::HashMap;
+use warp;
+use SCREEN_WIDTH;
 
-#[derive(PartialEq)]
-pub enum GameMode {
-    Play,
-    Editor,
-}
+const HURRY_TIME: u32 = 100; // Seconds left when the hurry up music starts
+const PLAYER_NAMES: [&str; 2] = ["MARIO", "LUIGI"];
 
 pub struct Editor {
     pub index: usize,
+    pub layer: LayerKind,
+    pub visible: [bool; 3],
+    pub platform: Option<usize>, // Platform in the map being edited
+    pub trigger: Option<usize>,  // Trigger in the map being edited
+    pub prompt: Option<Prompt>,
 }
 
-pub struct Tile {
-    pub active: bool,
-    pub id: usize,
+impl Editor {
+    /// Forgets the platform and trigger being edited, which are indices into a map
+    /// that may have been replaced since
+    pub fn deselect(&mut self) {
+        self.platform = None;
+        self.trigger = None;
+        if let Some(Prompt::Actions(..)) = self.prompt {
+            self.prompt = None;
+        }
+    }
 }
 
-pub struct Map {
-    pub dimensions: (u32, u32),
-    pub tiles: Vec<Tile>,
+/// Text being typed in the editor, and what it is for
+pub enum Prompt {
+    Warp(String),           // Destination of the warp at the cursor
+    Actions(usize, String), // Actions of a trigger
+}
+
+impl Prompt {
+    pub fn text_mut(&mut self) -> &mut String {
+        match *self {
+            Prompt::Warp(ref mut text) | Prompt::Actions(_, ref mut text) => text,
+        }
+    }
 }
 
 pub struct MainState {
-    pub tile_image: graphics::Image,
-    pub player_image: graphics::Image,
-    pub map: Option<Map>,
-    pub mode: GameMode,
+    pub game: Game,
+    pub tiles: Atlas,
+    pub tile_animations: TileAnimations,
+    pub player_sprites: Vec<Atlas>, // One recolored sheet per player
+    pub enemy_sprites: Atlas,
+    // Images of the parallax layers, by path. Those that failed to load are left out.
+    pub parallax_images: HashMap<String, Option<graphics::Image>>,
+    pub audio: Audio,
+    pub particles: Particles,
+    pub font: graphics::Font,
+    pub screens: Vec<Screen>, // The top one is shown and takes the input
     pub editor: Editor,
-    pub player: Player,
-    pub enemies: Vec<Enemy>,
+    pub held: [Buttons; 2], // Held on the keyboard, per set of key bindings
+    pub netplay: Option<Session>,
+    pub save_slots: Vec<Option<Snapshot>>, // Quicksaves kept in memory, also written to disk
+    pub save_slot: usize,
+    pub profiles: Vec<Slot>,
+    pub profile: Option<usize>, // Save slot being played
+    pub debug: bool,            // Draw hitboxes, velocities and the tile grid over the world
+    pub time: TimeControl,
+    pub rewind: Rewind,
 }
 
 impl MainState {
     pub fn new(ctx: &mut Context) -> GameResult<MainState> {
-        let mut tile_image = graphics::Image::new(ctx, "/tiles.png")?;
-        let mut player_image = graphics::Image::new(ctx, "/player.png")?;
-        tile_image.set_filter(graphics::FilterMode::Nearest);
-        player_image.set_filter(graphics::FilterMode::Nearest);
-        let mut map = Map {
-            dimensions: (16, 16),
-            tiles: Vec::new(),
+        let tiles = Atlas::load(ctx, "/tiles.atlas.toml")?;
+        let player_sprites = Atlas::load(ctx, "/player.atlas.toml")?;
+        let player_animations = AnimationSet::load(ctx, PLAYER_ANIMATIONS_PATH, &player_sprites)?;
+        let luigi = Palette::load(ctx, "/luigi.palette.toml")?;
+        let luigi_sprites = player_sprites.recolor(ctx, &luigi)?;
+        let player_sprites = vec![player_sprites, luigi_sprites];
+        let enemy_sprites = Atlas::load(ctx, "/enemies.atlas.toml")?;
+        // Only a missing map starts empty. A broken one is an error, rather than an empty
+        // map the editor would save over it.
+        let map = if ctx.filesystem.exists(MAP_PATH) {
+            let map = Map::load(ctx, MAP_PATH)?;
+            warp::validate(ctx, &map, MAP_PATH)?;
+            map
+        } else {
+            println!("No {}, starting with an empty map", MAP_PATH);
+            Map::new(16, 16)
         };
-        let mode = GameMode::Editor;
-        let editor = Editor { index: 0 };
-        let player = Player::new();
-        let enemies = Vec::new();
-        for _ in 0..16 * 16 {
-            map.tiles.push(Tile {
-                active: true,
-                id: EMPTY_SPRITE,
-            });
-        }
-        let main_state = MainState {
-            tile_image,
-            map: Some(map),
-            mode,
+        let editor = Editor {
+            index: 0,
+            layer: LayerKind::Main,
+            visible: [true; 3],
+            platform: None,
+            trigger: None,
+            prompt: None,
+        };
+        let tile_animations = TileAnimations::load(ctx, TILE_ANIMATIONS_PATH)?;
+        let physics = PhysicsConfig::load(ctx)?;
+        let audio = Audio::load(ctx)?;
+        let font = graphics::Font::default_font()?;
+        let particles = Particles::load(ctx)?;
+        let tile_count = tiles.frame_count();
+        let mut game = Game::new(map, MAP_PATH, physics, player_animations, tile_count);
+        game.load_scripts(ctx);
+        let mut main_state = MainState {
+            game,
+            tiles,
+            tile_animations,
+            parallax_images: HashMap::new(),
+            audio,
+            particles,
+            font,
+            screens: vec![Screen::Title { selected: 0 }],
             editor,
-            player_image,
-            player,
-            enemies,
+            player_sprites,
+            enemy_sprites,
+            held: [Buttons::default(); 2],
+            netplay: None,
+            save_slots: vec![None; SAVE_SLOTS],
+            save_slot: 0,
+            profiles: (0..PROFILE_SLOTS).map(|slot| Profile::load(ctx, slot)).collect(),
+            profile: None,
+            debug: false,
+            time: TimeControl::new(),
+            rewind: Rewind::new(),
         };
+        main_state.load_parallax_images(ctx);
         Ok(main_state)
     }
 
-    fn update_editor(&mut self, _ctx: &mut Context) -> GameResult<()> {
+    /// Starts a level from the beginning, with nothing to rewind to from before it
+    pub fn start_level(&mut self, ctx: &mut Context, path: &str) -> GameResult<()> {
+        self.game.start_level(ctx, path)?;
+        self.rewind.clear();
         Ok(())
     }
 
-    fn draw_player(&mut self, ctx: &mut Context) -> GameResult<()> {
-        let id = self.player.sprite_id;
-        let dest = self.player.obj.pos;
-        let mut param = sprite::create_sprite_param(id, dest, &self.player_image, true);
-        if self.player.obj.direction == Direction::Left {
-            param.scale.x *= -1.0;
-            param.dest.x += 32.0;
+    // Loads the images of the current map's parallax layers that aren't loaded yet. The
+    // map may change in the simulation, which doesn't deal with images, so this is done
+    // every frame.
+    fn load_parallax_images(&mut self, ctx: &mut Context) {
+        for layer in &self.game.map.as_ref().unwrap().parallax {
+            if self.parallax_images.contains_key(&layer.image) {
+                continue;
+            }
+            let image = graphics::Image::new(ctx, &layer.image).map(|mut image| {
+                image.set_filter(graphics::FilterMode::Nearest);
+                image
+            });
+            if let Err(ref e) = image {
+                println!("Failed to load parallax image {}: {}", layer.image, e);
+            }
+            self.parallax_images.insert(layer.image.clone(), image.ok());
         }
-        graphics::draw_ex(ctx, &self.player_image, param)?;
+    }
+
+    /// Starts a networked game against a peer, waiting for it to answer first
+    pub fn start_netplay(&mut self, config: &NetConfig) -> GameResult<()> {
+        let session = Session::new(config)?;
+        println!(
+            "Playing as player {} on {}, waiting for {}",
+            config.player + 1,
+            config.local,
+            config.peer
+        );
+        if self.game.players.len() < 2 {
+            self.game.toggle_second_player();
+        }
+        self.screens = vec![Screen::Title { selected: 0 }, Screen::Playing];
+        self.game.restart_level();
+        self.netplay = Some(session);
         Ok(())
     }
 
-    fn draw_enemies(&mut self, ctx: &mut Context) -> GameResult<()> {
+    // Advances a networked game by a tick, first going back to fix any ticks
+    // simulated with wrongly predicted buttons of the peer
+    pub fn update_netplay(&mut self, ctx: &mut Context) -> GameResult<()> {
+        let session = self.netplay.as_mut().unwrap();
+        // Whichever player is controlled here, it uses player one's keys
+        self.game.step_netplay(ctx, session, self.held[0])
+    }
+
+    // Passes the events of the last tick on to the systems presenting them, which
+    // are left out of the simulation
+    fn handle_events(&mut self, ctx: &mut Context) {
+        let map = self.game.map.as_ref().unwrap();
+        for event in self.game.events.events() {
+            self.audio.handle_event(ctx, *event);
+            self.particles.handle_event(*event, map);
+        }
+    }
+
+    // Plays the music of the level, hurried when time runs low and silent outside of play
+    fn update_music(&mut self, ctx: &mut Context) {
+        let map = self.game.map.as_ref().unwrap();
+        let all_dead = self.game.players.iter().all(|player| player.dead);
+        let track = if self.screen() != Screen::Playing || all_dead {
+            ""
+        } else if let Some(ref track) = self.game.music {
+            track
+        } else if self.game.time_left <= HURRY_TIME * TICKS_PER_SECOND as u32 {
+            &map.hurry_music
+        } else {
+            &map.music
+        };
+        let track = if track.is_empty() { None } else { Some(track) };
+        self.audio.play_music(ctx, track);
+    }
+
+    pub fn update_editor(&mut self, _ctx: &mut Context) -> GameResult<()> {
+        let map = self.game.map.as_ref().unwrap();
+        let x = (self.editor.index as u32 % map.dimensions.0) as f32 * TILE_SIZE;
+        let y = (self.editor.index as u32 / map.dimensions.0) as f32 * TILE_SIZE;
+        self.game.camera.follow(Vec2::from_f32(x, y), map);
         Ok(())
     }
 
-    fn draw_map(&mut self, ctx: &mut Context) -> GameResult<()> {
-        let map = &self.map.as_ref().unwrap().tiles;
+    /// Draws the map and everything in it, with the editor markers while editing
+    /// and the HUD while playing
+    pub fn draw_world(&mut self, ctx: &mut Context) -> GameResult<()> {
+        self.draw_parallax(ctx)?;
+        self.draw_map(ctx, LayerKind::Background)?;
+        // Behind the pipe while going through it
+        if self.game.warp.is_some() {
+            self.draw_players(ctx)?;
+        }
+        self.draw_map(ctx, LayerKind::Main)?;
+        self.draw_platforms(ctx)?;
+        self.draw_entities(ctx)?;
+        if self.game.warp.is_none() {
+            self.draw_players(ctx)?;
+        }
+        self.particles.draw(ctx, &self.game.camera)?;
+        self.draw_map(ctx, LayerKind::Foreground)?;
+        self.draw_warps(ctx)?;
+        if self.editing() {
+            self.draw_zones(ctx)?;
+            self.draw_triggers(ctx)?;
+        }
+        self.draw_script_text(ctx)?;
+        if self.debug {
+            self.draw_debug(ctx)?;
+        }
+        if !self.editing() {
+            self.draw_hud(ctx)?;
+        }
+        self.draw_frame_counter(ctx)?;
+        self.draw_timeline(ctx)?;
+        Ok(())
+    }
+
+    fn draw_parallax(&mut self, ctx: &mut Context) -> GameResult<()> {
+        for layer in &self.game.map.as_ref().unwrap().parallax {
+            let image = match self.parallax_images.get(&layer.image) {
+                Some(Some(image)) => image,
+                _ => continue,
+            };
+            let width = image.width() as f32 * SCALE_FACTOR;
+            let camera = self.game.camera.pos.to_point();
+            let mut x = -camera.x * layer.scroll_factor;
+            let y = layer.offset_y - camera.y * layer.scroll_factor;
+
+            // Repeating layers are tiled horizontally to cover the whole screen
+            if layer.repeat {
+                x %= width;
+                if x > 0.0 {
+                    x -= width;
+                }
+            }
+            loop {
+                let param = graphics::DrawParam {
+                    dest: Point2::new(x, y),
+                    scale: Point2::new(SCALE_FACTOR, SCALE_FACTOR),
+                    ..Default::default()
+                };
+                graphics::draw_ex(ctx, image, param)?;
+                x += width;
+                if !layer.repeat || x >= SCREEN_WIDTH as f32 {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn draw_players(&mut self, ctx: &mut Context) -> GameResult<()> {
+        // Dead players are shown until they come back or the level restarts
+        let shown = self
+            .game
+            .players
+            .iter()
+            .filter(|player| !player.dead || player.dead_ticks < RESPAWN_TICKS);
+        for player in shown {
+            let sprites = &self.player_sprites[player.index % self.player_sprites.len()];
+            let name = player.animator.frame(&self.game.player_animations);
+            // Atlases of the other players may lack frames the animations were checked against
+            let frame = match sprites.get(name) {
+                Some(frame) => frame,
+                None => continue,
+            };
+            let dest = self
+                .game
+                .camera
+                .to_screen(player.obj.transform.pos.to_point());
+            let mut param = sprite::create_sprite_param(frame, dest, &sprites.image);
+            if player.obj.transform.direction == Direction::Left {
+                param.scale.x *= -1.0;
+                param.dest.x += frame.w * SCALE_FACTOR;
+            }
+            graphics::draw_ex(ctx, &sprites.image, param)?;
+        }
+        Ok(())
+    }
+
+    fn draw_platforms(&mut self, ctx: &mut Context) -> GameResult<()> {
+        let image = &self.tiles.image;
+        for platform in &self.game.platforms {
+            let frame = match self.tiles.frame(platform.tile) {
+                Some(frame) => frame,
+                None => continue,
+            };
+            let pos = platform.bbox.pos.to_point();
+            for i in 0..platform.width {
+                let dest = Point2::new(pos.x + i as f32 * TILE_SIZE, pos.y);
+                let param =
+                    sprite::create_sprite_param(frame, self.game.camera.to_screen(dest), image);
+                graphics::draw_ex(ctx, image, param)?;
+            }
+        }
+
+        // Paths of the platforms, shown while editing
+        if !self.editing() {
+            return Ok(());
+        }
+        let half_tile = Point2::new(TILE_SIZE / 2.0, TILE_SIZE / 2.0);
+        for (i, def) in self.game.map.as_ref().unwrap().platforms.iter().enumerate() {
+            let start = Point2::new(def.pos.0, def.pos.1);
+            let mut points = vec![start];
+            match def.motion {
+                Motion::Path { ref waypoints, .. } => {
+                    points.extend(waypoints.iter().map(|&(x, y)| Point2::new(x, y)));
+                    points.push(start);
+                }
+                Motion::Oscillate { offset, .. } => {
+                    points.push(Point2::new(start.x + offset.0, start.y + offset.1));
+                }
+                Motion::Falling { .. } => {}
+            }
+            if points.len() < 2 {
+                continue;
+            }
+            let points: Vec<Point2> = points
+                .iter()
+                .map(|p| {
+                    self.game
+                        .camera
+                        .to_screen(Point2::new(p.x + half_tile.x, p.y + half_tile.y))
+                })
+                .collect();
+            let alpha = if self.editor.platform == Some(i) { 1.0 } else { 0.4 };
+            graphics::set_color(ctx, Color::new(1.0, 1.0, 1.0, alpha))?;
+            graphics::line(ctx, &points, 2.0)?;
+        }
+        graphics::set_color(ctx, Color::new(1.0, 1.0, 1.0, 1.0))?;
+        Ok(())
+    }
+
+    // Warps and exits, shown while editing
+    fn draw_warps(&mut self, ctx: &mut Context) -> GameResult<()> {
+        if !self.editing() {
+            return Ok(());
+        }
+        let map = self.game.map.as_ref().unwrap();
+        let markers = map
+            .warps
+            .iter()
+            .map(|warp| (warp.tile, warp.direction, warp.destination.to_string(), false))
+            .chain(
+                map.exits
+                    .iter()
+                    .map(|exit| (exit.tile, exit.direction, exit.name.clone(), true)),
+            );
+        for (tile, direction, label, is_exit) in markers {
+            let pos = self.game.camera.to_screen(Point2::new(
+                tile.0 as f32 * TILE_SIZE,
+                tile.1 as f32 * TILE_SIZE,
+            ));
+            let color = if is_exit {
+                Color::new(0.3, 1.0, 0.3, 1.0)
+            } else {
+                Color::new(1.0, 0.6, 0.2, 1.0)
+            };
+            graphics::set_color(ctx, color)?;
+            let rect = Rect::new(pos.x, pos.y, TILE_SIZE, TILE_SIZE);
+            graphics::rectangle(ctx, DrawMode::Line(2.0), rect)?;
+            let label = format!("{:?} {}", direction, label);
+            let text = graphics::Text::new(ctx, &label, &self.font)?;
+            graphics::draw(ctx, &text, Point2::new(pos.x, pos.y - 16.0), 0.0)?;
+        }
+        graphics::set_color(ctx, Color::new(1.0, 1.0, 1.0, 1.0))?;
+
+        let label = match self.editor.prompt {
+            Some(Prompt::Warp(ref text)) => {
+                format!("Warp to (exit, area <map> <exit>, level <map>): {}_", text)
+            }
+            Some(Prompt::Actions(_, ref text)) => format!(
+                "Actions (spawn x y; coin x y; toggle x y w h; lock; unlock; music <track>; \
+                 text <seconds> <text>): {}_",
+                text
+            ),
+            None => return Ok(()),
+        };
+        let text = graphics::Text::new(ctx, &label, &self.font)?;
+        graphics::draw(ctx, &text, Point2::new(8.0, 8.0), 0.0)?;
+        Ok(())
+    }
+
+    fn draw_hud(&mut self, ctx: &mut Context) -> GameResult<()> {
+        let seconds = self.game.time_left.div_ceil(TICKS_PER_SECOND as u32);
+        let time = format!("TIME {:03}", seconds);
+        let text = graphics::Text::new(ctx, &time, &self.font)?;
+        let x = SCREEN_WIDTH as f32 - text.width() as f32 - 8.0;
+        graphics::draw(ctx, &text, Point2::new(x, 8.0), 0.0)?;
+
+        // One line per player
+        for stats in &self.game.stats {
+            let hud = format!(
+                "{} x{}   SCORE {:06}   COINS x{:02}",
+                PLAYER_NAMES[stats.player % PLAYER_NAMES.len()],
+                stats.lives,
+                stats.score,
+                stats.coins
+            );
+            let text = graphics::Text::new(ctx, &hud, &self.font)?;
+            let y = 8.0 + stats.player as f32 * 20.0;
+            graphics::draw(ctx, &text, Point2::new(8.0, y), 0.0)?;
+        }
+
+        if self.netplay.as_ref().is_some_and(|session| !session.started) {
+            let text = graphics::Text::new(ctx, "WAITING FOR THE OTHER PLAYER", &self.font)?;
+            let x = (SCREEN_WIDTH as f32 - text.width() as f32) / 2.0;
+            graphics::draw(ctx, &text, Point2::new(x, 200.0), 0.0)?;
+        }
+        Ok(())
+    }
+
+    fn draw_map(&mut self, ctx: &mut Context, kind: LayerKind) -> GameResult<()> {
+        // Hidden layers are only hidden while editing
+        if self.editing() && !self.editor.visible[kind.index()] {
+            return Ok(());
+        }
+
+        let editing = self.editing();
+        let map = &self.game.map.as_ref().unwrap().layer(kind).tiles;
         for (i, tile) in map.iter().enumerate() {
-            let tile_size = SPRITE_SIZE * SCALE_FACTOR;
-            let mut id = tile.id;
+            // Tiles turned off by a trigger are only shown, faded, while editing
+            if !tile.active && !editing {
+                continue;
+            }
+            let mut alpha = if tile.active { 1.0 } else { 0.4 };
+            let mut id = self.tile_animations.frame(tile.id, self.game.clock);
             if id == EMPTY_SPRITE {
-                if i == self.editor.index {
-                    graphics::set_color(ctx, Color::new(1.0, 1.0, 1.0, 0.2))?;
+                if i == self.editor.index && kind == self.editor.layer {
+                    alpha = 0.2;
                     id = 0;
                 } else {
                     continue;
                 }
             }
 
-            let dimensions = &self.map.as_ref().unwrap().dimensions;
-            let x = (i as u32 % dimensions.0) as f32 * tile_size;
-            let y = (i as u32 / dimensions.0) as f32 * tile_size;
-            let dest = Point2::new(x, y);
-            let param = sprite::create_sprite_param(id, dest, &self.tile_image, false);
-            graphics::draw_ex(ctx, &self.tile_image, param)?;
-            graphics::set_color(ctx, Color::new(1.0, 1.0, 1.0, 1.0))?;
+            let dimensions = &self.game.map.as_ref().unwrap().dimensions;
+            let x = (i as u32 % dimensions.0) as f32 * TILE_SIZE;
+            let y = (i as u32 / dimensions.0) as f32 * TILE_SIZE;
+            let dest = self.game.camera.to_screen(Point2::new(x, y));
+            let frame = match self.tiles.frame(id) {
+                Some(frame) => frame,
+                None => continue,
+            };
+            let param = sprite::create_sprite_param(frame, dest, &self.tiles.image);
+            if alpha < 1.0 {
+                graphics::set_color(ctx, Color::new(1.0, 1.0, 1.0, alpha))?;
+            }
+            graphics::draw_ex(ctx, &self.tiles.image, param)?;
+            if alpha < 1.0 {
+                graphics::set_color(ctx, Color::new(1.0, 1.0, 1.0, 1.0))?;
+            }
         }
 
         Ok(())
@@ -117,22 +510,32 @@ impl MainState {
 
 impl event::EventHandler for MainState {
     fn update(&mut self, ctx: &mut Context) -> GameResult<()> {
-        match self.mode {
-            GameMode::Editor => {
-                self.update_editor(ctx)?;
+        // Ticks at a fixed rate however fast frames are drawn
+        while timer::check_update_time(ctx, TICKS_PER_SECOND as u32) {
+            // Both sides of a networked game must simulate with the same profile
+            if self.netplay.is_none() {
+                self.game.physics.update();
             }
-            GameMode::Play => {
-                self.player.update(self.map.as_ref().unwrap())?;
+            // Menus and networked games keep running while the world is paused or slowed down
+            let in_world = matches!(self.screen(), Screen::Playing | Screen::Editor);
+            if !in_world || self.netplay.is_some() || self.time.tick() {
+                self.game.events.clear();
+                self.update_screen(ctx)?;
+                self.handle_events(ctx);
+                self.particles.update();
             }
-        };
+            self.update_music(ctx);
+            self.audio.update();
+        }
+        self.load_parallax_images(ctx);
         Ok(())
     }
 
     fn draw(&mut self, ctx: &mut Context) -> GameResult<()> {
+        let (r, g, b) = self.game.map.as_ref().unwrap().background_color;
+        graphics::set_background_color(ctx, Color::new(r, g, b, 1.0));
         graphics::clear(ctx);
-        graphics::set_background_color(ctx, Color::new(0.43, 0.56, 0.97, 1.0));
-        self.draw_map(ctx)?;
-        self.draw_player(ctx)?;
+        self.draw_screens(ctx)?;
         graphics::present(ctx);
         Ok(())
     }
@@ -144,26 +547,77 @@ impl event::EventHandler for MainState {
         keymod: event::Mod,
         repeat: bool,
     ) {
+        // Typed text goes to the prompt, see text_input_event
+        if self.editor.prompt.is_some() {
+            self.key_down_prompt(ctx, keycode);
+            return;
+        }
+        // Anything changing the world outside of the players' buttons would make
+        // the two sides of a networked game drift apart
+        let local_only = self.netplay.is_none();
+        let in_world = matches!(self.screen(), Screen::Playing | Screen::Editor);
+        let playing = self.screen() == Screen::Playing;
         match keycode {
-            event::Keycode::F1 => {
-                if self.mode == GameMode::Editor {
-                    self.mode = GameMode::Play;
+            event::Keycode::F2 => {
+                println!(
+                    "World checksum at tick {}: {:016x}",
+                    self.game.clock,
+                    self.game.checksum()
+                );
+            }
+            event::Keycode::F3 if local_only && in_world => {
+                self.game.toggle_second_player();
+            }
+            event::Keycode::F5 if local_only && playing => {
+                self.quicksave(ctx);
+            }
+            event::Keycode::F6 => {
+                self.next_save_slot();
+            }
+            event::Keycode::F9 if local_only && playing => {
+                self.quickload(ctx);
+            }
+            event::Keycode::F4 => {
+                self.debug = !self.debug;
+            }
+            event::Keycode::F7 if local_only && in_world => {
+                self.time.toggle_pause();
+            }
+            event::Keycode::F8 if local_only && in_world => {
+                self.time.step();
+            }
+            event::Keycode::F10 if local_only && in_world => {
+                self.time.cycle_speed();
+            }
+            // Scrubbing through the rewind timeline, ten ticks at a time with shift
+            event::Keycode::LeftBracket | event::Keycode::RightBracket
+                if local_only && self.debug && self.screen() == Screen::Playing =>
+            {
+                let ticks = if keymod.intersects(event::LSHIFTMOD | event::RSHIFTMOD) {
+                    10
                 } else {
-                    self.mode = GameMode::Editor;
-                }
-            },
-            event::Keycode::R => {
-                self.player.obj.pos = Point2::new(0.0, 320.0);
+                    1
+                };
+                let back = keycode == event::Keycode::LeftBracket;
+                self.scrub_by(ctx, if back { -ticks } else { ticks });
             }
-            _ => {}
-        }
-        match self.mode {
-            GameMode::Editor => {
-                self.key_down_editor(ctx, keycode, keymod, repeat);
+            event::Keycode::M => {
+                self.audio.toggle_mute();
             }
-            GameMode::Play => {
-                self.key_down_play(ctx, keycode, keymod, repeat);
+            event::Keycode::Minus => {
+                self.audio.change_volume(Volume::Master, -1);
             }
+            event::Keycode::Equals => {
+                self.audio.change_volume(Volume::Master, 1);
+            }
+            _ => {}
+        }
+        self.key_down_screen(ctx, keycode, keymod, repeat);
+    }
+
+    fn text_input_event(&mut self, _ctx: &mut Context, text: String) {
+        if let Some(ref mut prompt) = self.editor.prompt {
+            prompt.text_mut().push_str(&text);
         }
     }
 
@@ -177,11 +631,19 @@ impl event::EventHandler for MainState {
         if repeat {
             return;
         }
-        match self.mode {
-            GameMode::Editor => {}
-            GameMode::Play => {
-                self.key_up_play(ctx, keycode, keymod, repeat);
-            }
+        self.key_up_screen(ctx, keycode, keymod, repeat);
+    }
+
+    fn mouse_button_down_event(
+        &mut self,
+        ctx: &mut Context,
+        button: event::MouseButton,
+        x: i32,
+        y: i32,
+    ) {
+        let scrubbing = self.netplay.is_none() && self.debug && self.screen() == Screen::Playing;
+        if button == event::MouseButton::Left && scrubbing {
+            self.click_timeline(ctx, x as f32, y as f32);
         }
     }
 }
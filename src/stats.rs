@@ -0,0 +1,50 @@
+use ecs::Item;
+use events::GameEvent;
+
+const STOMP_SCORE: u32 = 100;
+const COIN_SCORE: u32 = 200;
+const STAR_COIN_SCORE: u32 = 2000;
+pub const START_LIVES: u32 = 3;
+
+/// Counters of one player shown on the HUD, kept up to date from game events
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Stats {
+    pub player: usize,
+    pub score: u32,
+    pub coins: u32,
+    pub lives: u32,
+}
+
+impl Stats {
+    pub fn new(player: usize) -> Stats {
+        Stats {
+            player,
+            score: 0,
+            coins: 0,
+            lives: START_LIVES,
+        }
+    }
+
+    pub fn handle_event(&mut self, event: GameEvent) {
+        match event {
+            GameEvent::EnemyStomped { player } if player == self.player => {
+                self.score += STOMP_SCORE
+            }
+            GameEvent::CoinCollected { player, .. }
+            | GameEvent::ItemCollected {
+                item: Item::Coin,
+                player,
+            } if player == self.player => {
+                self.coins += 1;
+                self.score += COIN_SCORE;
+            }
+            GameEvent::StarCoinCollected { player, .. } if player == self.player => {
+                self.score += STAR_COIN_SCORE;
+            }
+            GameEvent::PlayerDied { player } if player == self.player => {
+                self.lives = self.lives.saturating_sub(1);
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,397 @@
+use animation::TICKS_PER_SECOND;
+use bbox::BBox;
+use ecs;
+use game::Game;
+use ggez::graphics::{self, Color, DrawMode, Point2, Rect};
+use ggez::{Context, GameResult};
+use map::{LayerKind, TILE_SIZE};
+use math::{scalar, Vec2};
+use occupancy::Occupancy;
+use state::MainState;
+use std::fmt;
+
+/// When a trigger's actions run
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum TriggerEvent {
+    Enter, // Something came in while it was empty
+    Stay,  // Every tick something is in it
+    Exit,  // The last thing in it left
+}
+
+impl TriggerEvent {
+    /// The next event, used when cycling through them in the editor
+    pub fn next(self) -> TriggerEvent {
+        match self {
+            TriggerEvent::Enter => TriggerEvent::Stay,
+            TriggerEvent::Stay => TriggerEvent::Exit,
+            TriggerEvent::Exit => TriggerEvent::Enter,
+        }
+    }
+}
+
+/// What sets a trigger off
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Activator {
+    Player,
+    Enemy,
+    Any,
+}
+
+impl Activator {
+    pub fn next(self) -> Activator {
+        match self {
+            Activator::Player => Activator::Enemy,
+            Activator::Enemy => Activator::Any,
+            Activator::Any => Activator::Player,
+        }
+    }
+}
+
+fn default_activator() -> Activator {
+    Activator::Player
+}
+
+/// Something a trigger does. Positions and sizes are in tiles.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Action {
+    SpawnEnemy { tile: (u32, u32) },
+    SpawnCoin { tile: (u32, u32) },
+    // Turns the tiles of the main layer in an area on or off. Tiles that are off
+    // neither collide nor draw.
+    ToggleTiles { tile: (u32, u32), size: (u32, u32) },
+    // Stops the camera where it is and keeps the players on screen
+    LockCamera,
+    UnlockCamera,
+    // Empty for silence, until the level restarts
+    PlayMusic { track: String },
+    ShowText { text: String, seconds: u32 },
+}
+
+impl Action {
+    /// Parses actions typed in the editor, separated by semicolons: `spawn <x> <y>`,
+    /// `coin <x> <y>`, `toggle <x> <y> [<w> <h>]`, `lock`, `unlock`, `music <track>|none` and
+    /// `text <seconds> <text>`
+    pub fn parse_list(text: &str) -> Result<Vec<Action>, String> {
+        text.split(';')
+            .filter(|action| !action.trim().is_empty())
+            .map(Action::parse)
+            .collect()
+    }
+
+    fn parse(text: &str) -> Result<Action, String> {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let number = |word: &str| {
+            word.parse::<u32>()
+                .map_err(|_| format!("Expected a number, got \"{}\"", word))
+        };
+        match words.as_slice() {
+            ["spawn", x, y] => Ok(Action::SpawnEnemy {
+                tile: (number(x)?, number(y)?),
+            }),
+            ["coin", x, y] => Ok(Action::SpawnCoin {
+                tile: (number(x)?, number(y)?),
+            }),
+            ["toggle", x, y] => Ok(Action::ToggleTiles {
+                tile: (number(x)?, number(y)?),
+                size: (1, 1),
+            }),
+            ["toggle", x, y, w, h] => Ok(Action::ToggleTiles {
+                tile: (number(x)?, number(y)?),
+                size: (number(w)?, number(h)?),
+            }),
+            ["lock"] => Ok(Action::LockCamera),
+            ["unlock"] => Ok(Action::UnlockCamera),
+            ["music", "none"] => Ok(Action::PlayMusic {
+                track: String::new(),
+            }),
+            ["music", track] => Ok(Action::PlayMusic {
+                track: track.to_string(),
+            }),
+            ["text", seconds, ..] if words.len() > 2 => Ok(Action::ShowText {
+                text: words[2..].join(" "),
+                seconds: number(seconds)?,
+            }),
+            _ => Err(format!(
+                "Expected spawn, coin, toggle, lock, unlock, music or text, got \"{}\"",
+                text.trim()
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    /// Formats the action the way it is typed in the editor
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Action::SpawnEnemy { tile } => write!(f, "spawn {} {}", tile.0, tile.1),
+            Action::SpawnCoin { tile } => write!(f, "coin {} {}", tile.0, tile.1),
+            Action::ToggleTiles { tile, size } => {
+                write!(f, "toggle {} {} {} {}", tile.0, tile.1, size.0, size.1)
+            }
+            Action::LockCamera => write!(f, "lock"),
+            Action::UnlockCamera => write!(f, "unlock"),
+            Action::PlayMusic { ref track } if track.is_empty() => write!(f, "music none"),
+            Action::PlayMusic { ref track } => write!(f, "music {}", track),
+            Action::ShowText { ref text, seconds } => write!(f, "text {} {}", seconds, text),
+        }
+    }
+}
+
+/// Invisible area of the map that runs actions when players or enemies go through it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Trigger {
+    pub tile: (u32, u32), // Top left
+    pub size: (u32, u32), // In tiles
+    pub on: TriggerEvent,
+    #[serde(default = "default_activator")]
+    pub by: Activator,
+    // Only fires the first time, until the level restarts
+    #[serde(default)]
+    pub once: bool,
+    #[serde(default)]
+    pub actions: Vec<Action>,
+}
+
+impl Trigger {
+    pub fn new(tile: (u32, u32)) -> Trigger {
+        Trigger {
+            tile,
+            size: (1, 1),
+            on: TriggerEvent::Enter,
+            by: Activator::Player,
+            once: false,
+            actions: Vec::new(),
+        }
+    }
+
+    pub fn bbox(&self) -> BBox {
+        BBox::new(
+            scalar(self.tile.0 as f32 * TILE_SIZE),
+            scalar(self.tile.1 as f32 * TILE_SIZE),
+            scalar(self.size.0 as f32 * TILE_SIZE),
+            scalar(self.size.1 as f32 * TILE_SIZE),
+        )
+    }
+
+    /// The actions the way they are typed in the editor
+    pub fn actions_text(&self) -> String {
+        let actions: Vec<String> = self.actions.iter().map(|a| a.to_string()).collect();
+        actions.join("; ")
+    }
+}
+
+/// A trigger in play
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct TriggerState {
+    pub occupancy: Occupancy,
+    pub fired: bool,
+}
+
+impl TriggerState {
+    /// Whether the trigger fires, given what is in it this tick
+    pub fn update(&mut self, trigger: &Trigger, candidates: &[(usize, BBox)]) -> bool {
+        let was_occupied = !self.occupancy.is_empty();
+        self.occupancy.update(&trigger.bbox(), candidates);
+        let occupied = !self.occupancy.is_empty();
+        let fires = match trigger.on {
+            TriggerEvent::Enter => occupied && !was_occupied,
+            TriggerEvent::Stay => occupied,
+            TriggerEvent::Exit => !occupied && was_occupied,
+        };
+        if !fires || (trigger.once && self.fired) {
+            return false;
+        }
+        self.fired = true;
+        true
+    }
+}
+
+impl Game {
+    /// Empties the triggers of the current map and lets them fire again
+    pub fn reset_triggers(&mut self) {
+        let count = self.map.as_ref().unwrap().triggers.len();
+        self.triggers = vec![TriggerState::default(); count];
+        self.camera_locked = false;
+        self.music = None;
+    }
+
+    /// Fires the triggers players and enemies went in or out of during this tick
+    pub fn update_triggers(&mut self) {
+        let players = self
+            .players
+            .iter()
+            .filter(|player| !player.dead)
+            .map(|player| (Activator::Player, player.obj.get_bbox()));
+        let enemies = self
+            .world
+            .hostile_bboxes()
+            .into_iter()
+            .map(|bbox| (Activator::Enemy, bbox));
+        let activators: Vec<(Activator, BBox)> = players.chain(enemies).collect();
+
+        // Triggers added in the editor since the last reset start out empty, so none
+        // are left out of the zip below
+        let map = self.map.as_ref().unwrap();
+        self.triggers
+            .resize(map.triggers.len(), TriggerState::default());
+
+        let mut actions = Vec::new();
+        for (trigger, state) in map.triggers.iter().zip(self.triggers.iter_mut()) {
+            // Only whether anything is in it matters, so the keys are just positions
+            let candidates: Vec<(usize, BBox)> = activators
+                .iter()
+                .filter(|&&(activator, _)| trigger.by == Activator::Any || trigger.by == activator)
+                .map(|&(_, bbox)| bbox)
+                .enumerate()
+                .collect();
+            if state.update(trigger, &candidates) {
+                actions.extend(trigger.actions.iter().cloned());
+            }
+        }
+        for action in actions {
+            self.run_action(action);
+        }
+    }
+
+    fn run_action(&mut self, action: Action) {
+        match action {
+            Action::SpawnEnemy { tile } => {
+                let pos = Vec2::from_f32(tile.0 as f32 * TILE_SIZE, tile.1 as f32 * TILE_SIZE);
+                self.world.spawn(ecs::goomba(pos));
+            }
+            Action::SpawnCoin { tile } => {
+                let pos = Vec2::from_f32(tile.0 as f32 * TILE_SIZE, tile.1 as f32 * TILE_SIZE);
+                self.world.spawn(ecs::coin(pos));
+            }
+            Action::ToggleTiles { tile, size } => {
+                let map = self.map_mut();
+                let (width, height) = map.dimensions;
+                let tiles = &mut map.layer_mut(LayerKind::Main).tiles;
+                // Saturating, as actions in a map file are not checked like typed ones
+                for y in tile.1..tile.1.saturating_add(size.1).min(height) {
+                    for x in tile.0..tile.0.saturating_add(size.0).min(width) {
+                        let tile = &mut tiles[(y * width + x) as usize];
+                        tile.active = !tile.active;
+                    }
+                }
+            }
+            Action::LockCamera => self.camera_locked = true,
+            Action::UnlockCamera => self.camera_locked = false,
+            Action::PlayMusic { track } => self.music = Some(track),
+            Action::ShowText { text, seconds } => {
+                self.message = Some((text, seconds.saturating_mul(TICKS_PER_SECOND as u32).max(1)));
+            }
+        }
+    }
+}
+
+impl MainState {
+    /// Outlines the triggers while editing, with when they fire and what they do
+    pub fn draw_triggers(&mut self, ctx: &mut Context) -> GameResult<()> {
+        let map = self.game.map.as_ref().unwrap();
+        for (i, trigger) in map.triggers.iter().enumerate() {
+            let color = if self.editor.trigger == Some(i) {
+                Color::new(1.0, 0.4, 1.0, 1.0)
+            } else {
+                Color::new(0.7, 0.3, 0.9, 1.0)
+            };
+            graphics::set_color(ctx, color)?;
+            let pos = Point2::new(
+                trigger.tile.0 as f32 * TILE_SIZE,
+                trigger.tile.1 as f32 * TILE_SIZE,
+            );
+            let dest = self.game.camera.to_screen(pos);
+            let rect = Rect::new(
+                dest.x,
+                dest.y,
+                trigger.size.0 as f32 * TILE_SIZE,
+                trigger.size.1 as f32 * TILE_SIZE,
+            );
+            graphics::rectangle(ctx, DrawMode::Line(2.0), rect)?;
+            let once = if trigger.once { " once" } else { "" };
+            let label = format!(
+                "{:?} {:?}{}: {}",
+                trigger.on,
+                trigger.by,
+                once,
+                trigger.actions_text()
+            );
+            let text = graphics::Text::new(ctx, &label, &self.font)?;
+            graphics::draw(ctx, &text, Point2::new(dest.x, dest.y - 16.0), 0.0)?;
+        }
+        graphics::set_color(ctx, Color::new(1.0, 1.0, 1.0, 1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Something one tile big at a tile of the map
+    fn at(x: u32) -> (usize, BBox) {
+        (0, Trigger::new((x, 0)).bbox())
+    }
+
+    // Whether the trigger fires on each tick, given what is in it then
+    fn fires(trigger: &Trigger, ticks: &[&[(usize, BBox)]]) -> Vec<bool> {
+        let mut state = TriggerState::default();
+        ticks
+            .iter()
+            .map(|candidates| state.update(trigger, candidates))
+            .collect()
+    }
+
+    #[test]
+    fn fires_on_enter_stay_and_exit() {
+        let ticks: [&[(usize, BBox)]; 5] = [&[at(5)], &[at(0)], &[at(0)], &[], &[at(0)]];
+        let mut trigger = Trigger::new((0, 0));
+        trigger.on = TriggerEvent::Enter;
+        assert_eq!(fires(&trigger, &ticks), [false, true, false, false, true]);
+        trigger.on = TriggerEvent::Stay;
+        assert_eq!(fires(&trigger, &ticks), [false, true, true, false, true]);
+        trigger.on = TriggerEvent::Exit;
+        assert_eq!(fires(&trigger, &ticks), [false, false, false, true, false]);
+    }
+
+    #[test]
+    fn enter_waits_for_it_to_be_empty() {
+        let second = (1, Trigger::new((0, 0)).bbox());
+        let ticks: [&[(usize, BBox)]; 3] = [&[at(0)], &[at(0), second], &[second]];
+        let mut trigger = Trigger::new((0, 0));
+        assert_eq!(fires(&trigger, &ticks), [true, false, false]);
+        trigger.on = TriggerEvent::Exit;
+        assert_eq!(fires(&trigger, &ticks), [false, false, false]);
+    }
+
+    #[test]
+    fn once_fires_only_the_first_time() {
+        let ticks: [&[(usize, BBox)]; 4] = [&[at(0)], &[], &[at(0)], &[]];
+        let mut trigger = Trigger::new((0, 0));
+        trigger.once = true;
+        assert_eq!(fires(&trigger, &ticks), [true, false, false, false]);
+        trigger.once = false;
+        assert_eq!(fires(&trigger, &ticks), [true, false, true, false]);
+    }
+
+    #[test]
+    fn actions_read_back_the_way_they_are_shown() {
+        let text = "spawn 1 2; coin 3 4; toggle 5 6 7 8; lock; unlock; music /music/boss.wav; \
+                    music none; text 3 Welcome to the castle";
+        let actions = Action::parse_list(text).unwrap();
+        assert_eq!(actions.len(), 8);
+        let mut trigger = Trigger::new((0, 0));
+        trigger.actions = actions.clone();
+        let shown = trigger.actions_text();
+        assert_eq!(Action::parse_list(&shown).unwrap(), actions);
+
+        // A single tile toggle is shown with its size
+        let toggle = Action::parse_list("toggle 5 6").unwrap();
+        assert_eq!(toggle[0].to_string(), "toggle 5 6 1 1");
+        assert!(Action::parse_list("spawn 1").is_err());
+        assert!(Action::parse_list("coin x 2").is_err());
+        assert!(Action::parse_list("text 3").is_err());
+    }
+}
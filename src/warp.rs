@@ -0,0 +1,219 @@
+use bbox::BBox;
+use checksum::Checksum;
+use ggez::{Context, GameError, GameResult};
+use map::{Map, TILE_SIZE};
+use math::scalar;
+use player::Player;
+use std::fmt;
+
+pub const WARP_TICKS: u32 = 32; // Ticks spent sliding into or out of a pipe
+
+/// The way the player moves when going into a warp, or coming out of an exit.
+/// Doors are entered by holding up and have no movement.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum WarpDirection {
+    Up,
+    Down,
+    Left,
+    Right,
+    Door,
+}
+
+impl WarpDirection {
+    /// Pixels moved per tick while entering or exiting
+    pub fn step(self) -> (f32, f32) {
+        match self {
+            WarpDirection::Up => (0.0, -1.0),
+            WarpDirection::Down => (0.0, 1.0),
+            WarpDirection::Left => (-1.0, 0.0),
+            WarpDirection::Right => (1.0, 0.0),
+            WarpDirection::Door => (0.0, 0.0),
+        }
+    }
+
+    /// The next direction, used when cycling through them in the editor
+    pub fn next(self) -> WarpDirection {
+        match self {
+            WarpDirection::Down => WarpDirection::Right,
+            WarpDirection::Right => WarpDirection::Left,
+            WarpDirection::Left => WarpDirection::Up,
+            WarpDirection::Up => WarpDirection::Door,
+            WarpDirection::Door => WarpDirection::Down,
+        }
+    }
+}
+
+/// Where a warp leads
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Destination {
+    // An exit in the same map
+    Exit { exit: String },
+    // An exit in another map that is part of the same level, e.g. a bonus room.
+    // The level timer keeps running.
+    Area { map: String, exit: String },
+    // The start of another level
+    Level { map: String },
+}
+
+impl Destination {
+    /// Parses a destination typed in the editor: `<exit>`, `area <map> <exit>` or `level <map>`
+    pub fn parse(text: &str) -> Result<Destination, String> {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        match words.as_slice() {
+            [exit] => Ok(Destination::Exit {
+                exit: exit.to_string(),
+            }),
+            ["area", map, exit] => Ok(Destination::Area {
+                map: map.to_string(),
+                exit: exit.to_string(),
+            }),
+            ["level", map] => Ok(Destination::Level {
+                map: map.to_string(),
+            }),
+            _ => Err(format!(
+                "Expected <exit>, area <map> <exit> or level <map>, got \"{}\"",
+                text
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Destination::Exit { ref exit } => write!(f, "{}", exit),
+            Destination::Area { ref map, ref exit } => write!(f, "area {} {}", map, exit),
+            Destination::Level { ref map } => write!(f, "level {}", map),
+        }
+    }
+}
+
+/// Named place in a map the player can arrive at
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Exit {
+    pub name: String,
+    pub tile: (u32, u32),
+    pub direction: WarpDirection,
+}
+
+/// Entrance of a pipe or door
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Warp {
+    pub tile: (u32, u32),
+    pub direction: WarpDirection,
+    pub destination: Destination,
+}
+
+impl Warp {
+    /// Whether the player is pushing into the warp the way it is entered:
+    /// crouching on top of a pipe, walking into the side of one, holding up
+    /// under one or holding up in front of a door
+    pub fn entered_by(&self, player: &Player) -> bool {
+        let size = scalar(TILE_SIZE);
+        let tile = BBox::new(
+            scalar(self.tile.0 as f32 * TILE_SIZE),
+            scalar(self.tile.1 as f32 * TILE_SIZE),
+            size,
+            size,
+        );
+        // Touching counts, so look one pixel ahead
+        let mut bbox = player.obj.get_bbox();
+        let (dx, dy) = self.direction.step();
+        bbox.pos.x += scalar(dx);
+        bbox.pos.y += scalar(dy);
+        if !bbox.intersects(&tile) {
+            return false;
+        }
+
+        let grounded = player.obj.collider.grounded;
+        match self.direction {
+            WarpDirection::Down => {
+                let center = bbox.pos.x + bbox.size.x / scalar(2.0);
+                grounded && player.crouching && center >= tile.pos.x && center < tile.pos.x + size
+            }
+            WarpDirection::Right => grounded && player.moving_right,
+            WarpDirection::Left => grounded && player.moving_left,
+            WarpDirection::Up => player.looking_up,
+            WarpDirection::Door => grounded && player.looking_up,
+        }
+    }
+}
+
+/// The player going into a warp, or coming out of an exit
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Transition {
+    pub player: usize, // Who went in, everyone comes out
+    pub direction: WarpDirection,
+    pub ticks: u32,
+    pub destination: Option<Destination>, // Where to go once in, None when coming out
+}
+
+impl Transition {
+    pub fn checksum(&self, sum: &mut Checksum) {
+        sum.write_u32(self.player as u32);
+        sum.write_u8(self.direction as u8);
+        sum.write_u32(self.ticks);
+        match self.destination {
+            None => sum.write_u8(0),
+            Some(Destination::Exit { ref exit }) => {
+                sum.write_u8(1);
+                sum.write_str(exit);
+            }
+            Some(Destination::Area { ref map, ref exit }) => {
+                sum.write_u8(2);
+                sum.write_str(map);
+                sum.write_str(exit);
+            }
+            Some(Destination::Level { ref map }) => {
+                sum.write_u8(3);
+                sum.write_str(map);
+            }
+        }
+    }
+}
+
+/// Checks that a destination exists, loading the map it leads to if it is in another file
+pub fn check_destination(ctx: &mut Context, map: &Map, destination: &Destination) -> GameResult<()> {
+    let (target, exit) = match *destination {
+        Destination::Exit { ref exit } => (None, Some(exit)),
+        Destination::Area { ref map, ref exit } => (Some(map), Some(exit)),
+        Destination::Level { ref map } => (Some(map), None),
+    };
+    let loaded = match target {
+        Some(path) => Some(Map::load(ctx, path).map_err(|e| {
+            GameError::ResourceLoadError(format!("Cannot load {}: {}", path, e))
+        })?),
+        None => None,
+    };
+    let target = loaded.as_ref().unwrap_or(map);
+    match exit {
+        Some(exit) if target.exit(exit).is_none() => Err(GameError::ResourceLoadError(format!(
+            "No exit named {}",
+            exit
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Checks that every warp of a map leads somewhere and that exit names are unique
+pub fn validate(ctx: &mut Context, map: &Map, path: &str) -> GameResult<()> {
+    for (i, exit) in map.exits.iter().enumerate() {
+        if map.exits[..i].iter().any(|other| other.name == exit.name) {
+            return Err(GameError::ResourceLoadError(format!(
+                "Exit {} is defined twice in {}",
+                exit.name, path
+            )));
+        }
+    }
+    for warp in &map.warps {
+        if let Err(e) = check_destination(ctx, map, &warp.destination) {
+            return Err(GameError::ResourceLoadError(format!(
+                "Warp at {:?} in {} leads to {}: {}",
+                warp.tile, path, warp.destination, e
+            )));
+        }
+    }
+    Ok(())
+}